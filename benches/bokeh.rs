@@ -0,0 +1,66 @@
+use bokeh::params::{KernelParamSet, KERNEL4_PARAM_SET, KERNEL9_PARAM_SET};
+use bokeh::{bokeh_blur, KernelPlan};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const SIZES: [usize; 3] = [256, 1024, 4096];
+const RADII: [f64; 2] = [5.0, 20.0];
+const PARAM_SETS: [(&str, KernelParamSet); 2] = [
+    ("kernel4", KERNEL4_PARAM_SET),
+    ("kernel9", KERNEL9_PARAM_SET),
+];
+
+/// A square image of a smooth gradient scattered with bright highlights, like
+/// the lights the blur is usually used on
+fn test_image(size: usize) -> Vec<[f64; 4]> {
+    (0..size * size)
+        .map(|n| {
+            let (x, y) = (n % size, n / size);
+            if (x * 7 + y * 13) % 97 == 0 {
+                [255.0; 4]
+            } else {
+                let v = (x + y) as f64 / (2 * size) as f64 * 128.0;
+                [v, 64.0, 128.0 - v, 255.0]
+            }
+        })
+        .collect()
+}
+
+fn blur(c: &mut Criterion) {
+    for size in SIZES {
+        let img = test_image(size);
+        let mut group = c.benchmark_group(format!("bokeh_blur/{size}x{size}"));
+        group.throughput(Throughput::Elements((size * size) as u64));
+        if size >= 4096 {
+            // Each iteration takes seconds and around a gigabyte of memory
+            group.sample_size(10);
+        }
+
+        for (name, param_set) in &PARAM_SETS {
+            for radius in RADII {
+                group.bench_with_input(BenchmarkId::new(*name, radius), &radius, |b, &radius| {
+                    b.iter_batched_ref(
+                        || img.clone(),
+                        |img| bokeh_blur(img, size, size, radius, param_set, 3.0),
+                        criterion::BatchSize::LargeInput,
+                    )
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+fn kernel_generation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("kernel_generation");
+    for (name, param_set) in &PARAM_SETS {
+        for radius in RADII {
+            group.bench_with_input(BenchmarkId::new(*name, radius), &radius, |b, &radius| {
+                b.iter(|| KernelPlan::for_radius(param_set, black_box(radius)))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, blur, kernel_generation);
+criterion_main!(benches);
@@ -20,12 +20,13 @@ def read_to_df(dir_path: str) -> pd.DataFrame:
         with open(os.path.join(dir_path, filepath), "r") as f:
             data = json.load(f)
 
+        step = 2 * radius / max(len(data) - 1, 1)
         for n, elem in enumerate(data):
             records.append(
                 {
                     "components": params,
                     "radius": radius,
-                    "pixels": n - radius,
+                    "pixels": n * step - radius,
                     "y": elem,
                 }
             )
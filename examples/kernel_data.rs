@@ -1,4 +1,4 @@
-use bokeh::{kernel_gaussian_components, params::*};
+use bokeh::{params::*, sample_combined_kernel};
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
@@ -23,17 +23,10 @@ fn main() -> std::io::Result<()> {
     .enumerate()
     {
         for kernel_size in [1, 5, 10, 50, 100] {
-            let kernels = kernel_gaussian_components(params, kernel_size as f64);
+            let output = sample_combined_kernel(params, kernel_size as f64, 20 * kernel_size + 1);
 
-            let mut output = vec![0.0; kernels[0].len()];
-            for (n, kernel) in kernels.iter().enumerate() {
-                for (m, k) in kernel.iter().enumerate() {
-                    output[m] += params.real_component(n) * k.re + params.imag_component(n) * k.im;
-                }
-
-                let mut file = File::create(format!("{}/{}_{}.json", out_dir, p + 1, kernel_size))?;
-                write!(file, "{:?}", output)?;
-            }
+            let mut file = File::create(format!("{}/{}_{}.json", out_dir, p + 1, kernel_size))?;
+            write!(file, "{:?}", output)?;
         }
     }
 
@@ -0,0 +1,91 @@
+//! Blurring of [`ndarray`] arrays, without flattening them into slices of
+//! pixels.
+use crate::complex::try_bokeh_blur;
+use crate::error::BlurError;
+use crate::params::KernelParamSet;
+use alloc::vec::Vec;
+use ndarray::{ArrayViewMut3, Axis};
+
+/// Blurs an image held in an array using an approximation of a disc-shaped
+/// kernel to produce a Bokeh lens effect.
+///
+/// Takes an exclusive reference to a view of shape `(height, width, 4)`, where
+/// the last axis is R, G, B, A. The array can have any layout, e.g. C- or
+/// F-contiguous, or a slice of a larger array, as the pixels are copied out
+/// and written back in place. Requires the `ndarray` feature.
+///
+/// The image is blurred by a disc-shaped kernel with radius `radius`, built
+/// from components corresponding to `param_set`. The exposure can be modified
+/// using `gamma`, set to `1.0` for no change.
+///
+/// Returns [`BlurError::ChannelMismatch`] if the last axis isn't of length 4,
+/// and [`BlurError::InvalidRadius`] and [`BlurError::NonFiniteInput`] as
+/// [`try_bokeh_blur`], in each case without modifying the array.
+pub fn bokeh_blur_ndarray(
+    arr: &mut ArrayViewMut3<f64>,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) -> Result<(), BlurError> {
+    let (height, width, channels) = arr.dim();
+    if channels != 4 {
+        return Err(BlurError::ChannelMismatch {
+            expected: 4,
+            got: channels,
+        });
+    }
+
+    let mut pixels = arr
+        .lanes(Axis(2))
+        .into_iter()
+        .map(|rgba| [rgba[0], rgba[1], rgba[2], rgba[3]])
+        .collect::<Vec<_>>();
+    try_bokeh_blur(&mut pixels, width, height, radius, param_set, gamma)?;
+
+    for (mut lane, rgba) in arr.lanes_mut(Axis(2)).into_iter().zip(pixels) {
+        for (c, v) in lane.iter_mut().zip(rgba) {
+            *c = v;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::KERNEL9_PARAM_SET;
+    use crate::testing::gradient_image;
+    use ndarray::{Array3, ShapeBuilder};
+
+    #[test]
+    fn blurs_any_layout() {
+        let (w, h) = (9, 7);
+        let original = gradient_image(w, h);
+        let mut expected = original.clone();
+        crate::bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+
+        for fortran_order in [false, true] {
+            let shape = (h, w, 4).set_f(fortran_order);
+            let mut arr = Array3::from_shape_fn(shape, |(y, x, c)| original[y * w + x][c]);
+            bokeh_blur_ndarray(&mut arr.view_mut(), 2.0, &KERNEL9_PARAM_SET, 2.2).unwrap();
+
+            for ((y, x, c), v) in arr.indexed_iter() {
+                assert!((v - expected[y * w + x][c]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_channel_count() {
+        let mut arr = Array3::zeros((3, 3, 3));
+        assert_eq!(
+            bokeh_blur_ndarray(&mut arr.view_mut(), 1.0, &KERNEL9_PARAM_SET, 1.0),
+            Err(BlurError::ChannelMismatch {
+                expected: 4,
+                got: 3
+            })
+        );
+        assert_eq!(arr, Array3::zeros((3, 3, 3)));
+    }
+}
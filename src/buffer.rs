@@ -0,0 +1,215 @@
+//! Blurring of [`image::ImageBuffer`]s, without going through a
+//! [`image::DynamicImage`].
+use crate::builder::BlurOptions;
+use crate::params::KernelParamSet;
+use crate::Blur;
+use image::{ImageBuffer, Pixel};
+use std::ops::{Deref, DerefMut};
+
+/// A channel value of an [`ImageBuffer`] the blurs can read and write,
+/// converted to and from the range `0.0..=255.0` they work in
+pub(crate) trait Channel: Copy {
+    /// The value scaled to `0.0..=255.0`
+    fn to_f64(self) -> f64;
+
+    /// The channel value of `value` in `0.0..=255.0`
+    fn from_f64(value: f64) -> Self;
+}
+
+impl Channel for u8 {
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+
+    fn from_f64(value: f64) -> Self {
+        value.round() as u8
+    }
+}
+
+/// Floating point channels hold `0.0..=1.0`, as in [`image::Rgba32FImage`]
+impl Channel for f32 {
+    fn to_f64(self) -> f64 {
+        f64::from(self) * 255.0
+    }
+
+    fn from_f64(value: f64) -> Self {
+        (value / 255.0) as f32
+    }
+}
+
+/// Reads the pixels of an image, blurs them with `blur` and writes them back.
+///
+/// Each pixel's channels are read into the first channels of an RGBA pixel in
+/// order, e.g. the luma and alpha of [`image::LumaA`] into the red and green,
+/// and the rest left at zero. Only the pixel's own channels are written back.
+/// `blur` is passed the pixels and the width and height of the image, and
+/// must leave the pixels in the range `0.0..=255.0`.
+fn blur_pixels<P, C>(img: &mut ImageBuffer<P, C>, blur: impl FnOnce(&mut [[f64; 4]], usize, usize))
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+    C: Deref<Target = [P::Subpixel]> + DerefMut,
+{
+    assert!(
+        P::CHANNEL_COUNT <= 4,
+        "pixels with {} channels can't be blurred",
+        P::CHANNEL_COUNT
+    );
+    let (w, h) = img.dimensions();
+    let mut pixels = img
+        .pixels()
+        .map(|p| {
+            let mut rgba = [0.0; 4];
+            for (c, &v) in rgba.iter_mut().zip(p.channels()) {
+                *c = v.to_f64();
+            }
+            rgba
+        })
+        .collect::<Vec<_>>();
+
+    blur(&mut pixels, w as usize, h as usize);
+
+    for (p, rgba) in img.pixels_mut().zip(pixels) {
+        for (v, c) in p.channels_mut().iter_mut().zip(rgba) {
+            *v = Channel::from_f64(c);
+        }
+    }
+}
+
+/// Blurs buffers of any pixel type with up to 4 channels of `u8`, or of `f32`
+/// between `0.0` and `1.0`, such as [`image::RgbaImage`], [`image::RgbImage`]
+/// and [`image::Rgba32FImage`]. Floating point values above `1.0` are clamped,
+/// see [`crate::bokeh_blur_linear`] to keep them.
+impl<P, C> Blur for ImageBuffer<P, C>
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+    C: Deref<Target = [P::Subpixel]> + DerefMut,
+{
+    fn bokeh_blur(&mut self, radius: f64, param_set: &KernelParamSet, gamma: f64) {
+        blur_pixels(self, |pixels, w, h| {
+            crate::bokeh_blur(pixels, w, h, radius, param_set, gamma)
+        })
+    }
+
+    fn bokeh_blur_with_mask<'a>(
+        &mut self,
+        mask: impl IntoIterator<Item = &'a bool>,
+        radius: f64,
+        param_set: &KernelParamSet,
+        gamma: f64,
+    ) {
+        blur_pixels(self, |pixels, w, h| {
+            crate::bokeh_blur_with_mask(pixels, mask, w, h, radius, param_set, gamma)
+        })
+    }
+
+    fn gaussian_blur(&mut self, r: f64, kernel_radius: usize) {
+        blur_pixels(self, |pixels, w, h| {
+            crate::gaussian_blur(pixels, w, h, r, kernel_radius)
+        })
+    }
+
+    fn bokeh_blur_with(&mut self, options: &BlurOptions) {
+        blur_pixels(self, |pixels, w, h| options.apply_slice(pixels, w, h))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::KERNEL9_PARAM_SET;
+    use image::{
+        DynamicImage, GrayAlphaImage, LumaA, Rgb, Rgb32FImage, RgbImage, Rgba, Rgba32FImage,
+        RgbaImage,
+    };
+
+    /// The blurs only differ by the order of floating point sums, which can
+    /// tip a value either side of a whole number
+    fn assert_bytes_close(a: &[u8], b: &[u8]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert!(a.abs_diff(*b) <= 1);
+        }
+    }
+
+    #[test]
+    fn buffer_matches_dynamic_image() {
+        let buf = RgbaImage::from_fn(9, 7, |x, y| {
+            Rgba([(x * 29) as u8, (y * 31) as u8, (x * y) as u8, 255])
+        });
+        let mask = (0..9 * 7).map(|i| i % 4 != 0).collect::<Vec<_>>();
+
+        let mut img = buf.clone();
+        img.bokeh_blur(2.0, &KERNEL9_PARAM_SET, 2.2);
+        let mut expected = DynamicImage::ImageRgba8(buf.clone());
+        expected.bokeh_blur(2.0, &KERNEL9_PARAM_SET, 2.2);
+        assert_bytes_close(img.as_raw(), expected.as_bytes());
+
+        let mut img = buf.clone();
+        img.bokeh_blur_with_mask(&mask, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        let mut expected = DynamicImage::ImageRgba8(buf);
+        expected.bokeh_blur_with_mask(&mask, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        assert_bytes_close(img.as_raw(), expected.as_bytes());
+    }
+
+    #[test]
+    fn blurs_each_channel_of_other_pixel_types() {
+        let rgba = RgbaImage::from_fn(9, 7, |x, y| {
+            Rgba([(x * 29) as u8, (y * 31) as u8, (x * y) as u8, 255])
+        });
+        let mut expected = rgba.clone();
+        expected.bokeh_blur(2.0, &KERNEL9_PARAM_SET, 2.2);
+
+        let mut rgb = RgbImage::from_fn(9, 7, |x, y| Rgb(rgba.get_pixel(x, y).to_rgb().0));
+        rgb.bokeh_blur(2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (p, e) in rgb.pixels().zip(expected.pixels()) {
+            assert_eq!(p.0, e.to_rgb().0);
+        }
+
+        // The luma and alpha are blurred in the place of the red and green
+        let mut luma_alpha = GrayAlphaImage::from_fn(9, 7, |x, y| {
+            let [r, g, _, _] = rgba.get_pixel(x, y).0;
+            LumaA([r, g])
+        });
+        luma_alpha.bokeh_blur(2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (p, e) in luma_alpha.pixels().zip(expected.pixels()) {
+            assert_eq!(p.0, [e.0[0], e.0[1]]);
+        }
+    }
+
+    #[test]
+    fn float_buffers_keep_their_precision() {
+        let buf = Rgba32FImage::from_fn(9, 7, |x, y| {
+            Rgba([x as f32 / 8.0, y as f32 / 6.0, 0.3, 1.0])
+        });
+        let mut img = buf.clone();
+        img.bokeh_blur(2.0, &KERNEL9_PARAM_SET, 2.2);
+
+        let mut expected = buf
+            .pixels()
+            .map(|p| p.0.map(|c| f64::from(c) * 255.0))
+            .collect::<Vec<_>>();
+        crate::bokeh_blur(&mut expected, 9, 7, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (a, b) in img
+            .pixels()
+            .flat_map(|p| p.0)
+            .zip(expected.iter().flatten())
+        {
+            assert!((f64::from(a) - b / 255.0).abs() < 1e-6);
+        }
+        // Not rounded to 8 bits
+        assert!(img
+            .pixels()
+            .flat_map(|p| p.0)
+            .any(|c| (c * 255.0).fract().abs() > 1e-3));
+
+        let mut rgb = Rgb32FImage::from_fn(9, 7, |x, y| Rgb(buf.get_pixel(x, y).to_rgb().0));
+        rgb.gaussian_blur(1.0, 3);
+        let mut expected = buf.clone();
+        expected.gaussian_blur(1.0, 3);
+        for (p, e) in rgb.pixels().zip(expected.pixels()) {
+            assert_eq!(p.0, e.to_rgb().0);
+        }
+    }
+}
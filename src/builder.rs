@@ -0,0 +1,498 @@
+//! A reusable blur configuration, built up one setting at a time.
+use crate::color::ColorSpace;
+use crate::complex::{
+    fill_edges, premultiply, recommended_kernel_radius, unpremultiply, ComplexImage,
+};
+use crate::edge::EdgeMode;
+use crate::mask::Rect;
+use crate::params::{KernelParamSet, KERNEL9_PARAM_SET};
+use crate::plan::KernelPlan;
+use crate::radius::BlurRadius;
+use alloc::vec::Vec;
+
+#[cfg(feature = "image")]
+use image::DynamicImage;
+
+/// The settings of a Bokeh blur, reusable across many images.
+///
+/// Built with [`BokehBlur::builder`], which names each setting rather than
+/// relying on the order of positional arguments:
+/// ```
+/// use bokeh::{params::KERNEL9_PARAM_SET, BokehBlur, EdgeMode};
+///
+/// let blur = BokehBlur::builder()
+///     .radius(2.0)
+///     .param_set(&KERNEL9_PARAM_SET)
+///     .gamma(3.0)
+///     .edge_mode(EdgeMode::Clamp)
+///     .build();
+///
+/// let mut pixels = vec![[255.0; 4]; 16];
+/// blur.apply_slice(&mut pixels, 4, 4);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BokehBlur<'a> {
+    radius: f64,
+    kernel_radius: Option<usize>,
+    param_set: KernelParamSet<'a>,
+    color_space: ColorSpace,
+    edge_mode: EdgeMode,
+    premultiply: bool,
+    linear_alpha: bool,
+    region: Option<Rect>,
+}
+
+/// The settings of a blur, as taken by [`crate::Blur::bokeh_blur_with`]. The
+/// same as [`BokehBlur`], built with [`BokehBlur::builder`].
+pub type BlurOptions<'a> = BokehBlur<'a>;
+
+impl<'a> BokehBlur<'a> {
+    /// Starts building a blur with the default settings: a radius of `1.0`,
+    /// the [`crate::recommended_kernel_radius`], 9 components, a gamma of
+    /// `1.0`, [`EdgeMode::Zero`], straight, not premultiplied, alpha with the
+    /// gamma applied and the whole image blurred.
+    pub fn builder() -> BokehBlurBuilder<'a> {
+        BokehBlurBuilder {
+            blur: BokehBlur {
+                radius: 1.0,
+                kernel_radius: None,
+                param_set: KERNEL9_PARAM_SET,
+                color_space: ColorSpace::Gamma(1.0),
+                edge_mode: EdgeMode::Zero,
+                premultiply: false,
+                linear_alpha: false,
+                region: None,
+            },
+        }
+    }
+
+    /// The prepared kernels for these settings
+    fn plan(&self) -> KernelPlan {
+        KernelPlan::new(
+            &self.param_set,
+            self.radius,
+            self.kernel_radius
+                .unwrap_or_else(|| recommended_kernel_radius(self.radius)),
+        )
+    }
+
+    /// Blurs an image, or just its region if one is set, clamping the result
+    /// to `0.0..=max_value`
+    fn blur(&self, img: &mut [[f64; 4]], width: usize, height: usize, max_value: f64) {
+        let plan = self.plan();
+        if plan.is_identity() {
+            return;
+        }
+
+        let Some(region) = self.region else {
+            return self.blur_with_plan(&plan, img, width, height, max_value);
+        };
+
+        // Blur the region along with the pixels the kernel reaches around it,
+        // so the result matches blurring the whole image, then keep just the
+        // region
+        let region = region.expand(0, width, height);
+        let crop = region.expand(plan.kernel_radius(), width, height);
+        let mut pixels = (crop.y..crop.y + crop.height)
+            .flat_map(|y| &img[y * width + crop.x..y * width + crop.x + crop.width])
+            .copied()
+            .collect::<Vec<_>>();
+        self.blur_with_plan(&plan, &mut pixels, crop.width, crop.height, max_value);
+
+        let inner = Rect::new(
+            region.x - crop.x,
+            region.y - crop.y,
+            region.width,
+            region.height,
+        );
+        for n in 0..region.width * region.height {
+            img[region.pixel_index(n, width)] = pixels[inner.pixel_index(n, crop.width)];
+        }
+    }
+
+    /// Blurs a whole image with the kernels of `plan`, clamping the result to
+    /// `0.0..=max_value`
+    fn blur_with_plan(
+        &self,
+        plan: &KernelPlan,
+        img: &mut [[f64; 4]],
+        width: usize,
+        height: usize,
+        max_value: f64,
+    ) {
+        if self.premultiply {
+            for pixel in img.iter_mut() {
+                *pixel = premultiply(*pixel, max_value);
+            }
+        }
+
+        let alpha_space = if self.linear_alpha {
+            ColorSpace::Linear
+        } else {
+            self.color_space
+        };
+        let spaces = [
+            self.color_space,
+            self.color_space,
+            self.color_space,
+            alpha_space,
+        ];
+        for pixel in img.iter_mut() {
+            *pixel = core::array::from_fn(|c| spaces[c].decode(pixel[c], max_value));
+        }
+
+        let mut blurred = ComplexImage::from_slice(img, width, height, 1.0)
+            .with_edge_mode(self.edge_mode)
+            .convolve(plan.kernels());
+        // The colour beyond the edges is blended like the pixels
+        let edge_mode = match self.edge_mode {
+            EdgeMode::ConstantColor(color) if self.premultiply => {
+                EdgeMode::ConstantColor(premultiply(color, max_value))
+            }
+            edge_mode => edge_mode,
+        };
+        fill_edges(
+            &mut blurred,
+            width,
+            height,
+            plan.kernels(),
+            edge_mode,
+            |c, value| spaces[c].decode(value, max_value),
+        );
+
+        for (n, rgba) in blurred.into_iter().enumerate() {
+            // Clamp any values from floating point ops
+            img[n] = core::array::from_fn(|c| {
+                spaces[c].encode(rgba[c].max(0.0), max_value).min(max_value)
+            });
+        }
+
+        if self.premultiply {
+            for pixel in img.iter_mut() {
+                *pixel = unpremultiply(*pixel, max_value);
+            }
+        }
+    }
+
+    /// Blurs an image with these settings.
+    ///
+    /// Takes an exclusive reference to a slice of size 4 arrays, where each
+    /// array element corresponds to a pixel. Also requires the `width` and
+    /// `height` of the image.
+    pub fn apply_slice(&self, img: &mut [[f64; 4]], width: usize, height: usize) {
+        self.blur(img, width, height, 255.0)
+    }
+
+    /// Blurs an image with these settings.
+    ///
+    /// Takes an exclusive reference to a [`image::DynamicImage`](image).
+    /// Images with 16 bits per channel are blurred at their full precision, as
+    /// by [`crate::dynamic_image::bokeh_blur`].
+    #[cfg(feature = "image")]
+    pub fn apply(&self, img: &mut DynamicImage) {
+        crate::complex::dynamic_image::blur_pixels(img, |pixels, w, h, max_value| {
+            self.blur(pixels, w, h, max_value)
+        })
+    }
+}
+
+/// Builds a [`BokehBlur`], see [`BokehBlur::builder`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BokehBlurBuilder<'a> {
+    blur: BokehBlur<'a>,
+}
+
+impl<'a> BokehBlurBuilder<'a> {
+    /// Sets the radius of the disc-shaped kernel
+    pub fn radius(mut self, radius: f64) -> Self {
+        self.blur.radius = radius;
+        self
+    }
+
+    /// Sets the radius of the disc-shaped kernel from a [`BlurRadius`], with
+    /// the kernel sampled to its [`BlurRadius::kernel_radius`]
+    pub fn blur_radius(mut self, radius: BlurRadius) -> Self {
+        self.blur.radius = radius.to_pixels();
+        self.blur.kernel_radius = Some(radius.kernel_radius());
+        self
+    }
+
+    /// Sets the number of pixels either side of the centre the kernel is
+    /// sampled to, rather than the [`crate::recommended_kernel_radius`]
+    pub fn kernel_radius(mut self, kernel_radius: usize) -> Self {
+        self.blur.kernel_radius = Some(kernel_radius);
+        self
+    }
+
+    /// Sets the components the kernel is built from
+    pub fn param_set(mut self, param_set: &KernelParamSet<'a>) -> Self {
+        self.blur.param_set = *param_set;
+        self
+    }
+
+    /// Sets the gamma modifying the exposure, `1.0` for no change. The same
+    /// as a [`ColorSpace::Gamma`].
+    pub fn gamma(mut self, gamma: f64) -> Self {
+        self.blur.color_space = ColorSpace::Gamma(gamma);
+        self
+    }
+
+    /// Sets the colour space the pixels are convolved in, see [`ColorSpace`]
+    pub fn color_space(mut self, color_space: ColorSpace) -> Self {
+        self.blur.color_space = color_space;
+        self
+    }
+
+    /// Sets how pixels beyond the edges of the image are sampled
+    pub fn edge_mode(mut self, edge_mode: EdgeMode) -> Self {
+        self.blur.edge_mode = edge_mode;
+        self
+    }
+
+    /// Sets whether the colours are premultiplied by their alpha while
+    /// convolving, see [`crate::bokeh_blur_premultiplied`]
+    pub fn premultiply(mut self, premultiply: bool) -> Self {
+        self.blur.premultiply = premultiply;
+        self
+    }
+
+    /// Sets whether the alpha channel is convolved as it is, without the gamma
+    /// or colour space applied to the colours.
+    ///
+    /// The gamma models how light is perceived, so applying it to the
+    /// coverage held in the alpha distorts the transparency.
+    pub fn linear_alpha(mut self, linear_alpha: bool) -> Self {
+        self.blur.linear_alpha = linear_alpha;
+        self
+    }
+
+    /// Sets a region of the image to blur, leaving the pixels outside it
+    /// unchanged.
+    ///
+    /// Only the region and the pixels the kernel reaches around it are
+    /// convolved, so blurring a small region of a large image is much faster
+    /// than blurring all of it, and the pixels of the region are the same as if
+    /// the whole image was blurred. The part of the region beyond the edges of
+    /// the image is ignored.
+    pub fn region(mut self, region: Rect) -> Self {
+        self.blur.region = Some(region);
+        self
+    }
+
+    /// Finishes building the blur
+    pub fn build(self) -> BokehBlur<'a> {
+        self.blur
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::KERNEL5_PARAM_SET;
+    use crate::testing::gradient_image;
+
+    #[test]
+    fn builder_matches_positional_functions() {
+        let (w, h) = (9, 7);
+        let original = gradient_image(w, h);
+        let blur = BokehBlur::builder()
+            .radius(2.5)
+            .param_set(&KERNEL5_PARAM_SET)
+            .gamma(3.0)
+            .edge_mode(EdgeMode::Reflect)
+            .build();
+
+        let mut img = original.clone();
+        blur.apply_slice(&mut img, w, h);
+        let mut expected = original;
+        crate::bokeh_blur_with_edge_mode(
+            &mut expected,
+            w,
+            h,
+            2.5,
+            &KERNEL5_PARAM_SET,
+            3.0,
+            EdgeMode::Reflect,
+        );
+
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn defaults_and_options() {
+        let (w, h) = (8, 6);
+        let original = gradient_image(w, h);
+
+        let mut img = original.clone();
+        BokehBlur::builder().build().apply_slice(&mut img, w, h);
+        let mut expected = original.clone();
+        crate::bokeh_blur(&mut expected, w, h, 1.0, &KERNEL9_PARAM_SET, 1.0);
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+
+        let mut img = original.clone();
+        BokehBlur::builder()
+            .radius(2.0)
+            .kernel_radius(4)
+            .build()
+            .apply_slice(&mut img, w, h);
+        let mut expected = original;
+        crate::bokeh_blur_with_kernel_radius(&mut expected, w, h, 2.0, 4, &KERNEL9_PARAM_SET, 1.0);
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+
+        let original = crate::testing::checkerboard(w, h, 2);
+        let mut img = original.clone();
+        BokehBlur::builder()
+            .radius(2.0)
+            .premultiply(true)
+            .build()
+            .apply_slice(&mut img, w, h);
+        let mut expected = original;
+        crate::bokeh_blur_premultiplied(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn blur_radius_sets_radius_and_kernel_radius() {
+        let (w, h) = (9, 7);
+        let original = gradient_image(w, h);
+
+        let mut img = original.clone();
+        BokehBlur::builder()
+            .blur_radius(BlurRadius::sigma(1.25))
+            .build()
+            .apply_slice(&mut img, w, h);
+        let mut expected = original;
+        crate::bokeh_blur(&mut expected, w, h, 2.5, &KERNEL9_PARAM_SET, 1.0);
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+
+        let radius = BlurRadius::pixels(3.5);
+        assert_eq!(radius.to_sigma(), 1.75);
+        assert_eq!(radius.kernel_radius(), 4);
+    }
+
+    #[test]
+    fn srgb_decodes_before_convolving() {
+        let (w, h) = (9, 7);
+        let original = crate::testing::checkerboard(w, h, 2);
+
+        let mut img = original.clone();
+        BokehBlur::builder()
+            .radius(2.0)
+            .color_space(ColorSpace::Srgb)
+            .build()
+            .apply_slice(&mut img, w, h);
+
+        let space = ColorSpace::Srgb;
+        let mut expected = original
+            .iter()
+            .map(|p| p.map(|c| space.decode(c, 255.0)))
+            .collect::<Vec<_>>();
+        crate::bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - space.encode(*b, 255.0)).abs() < 1e-9);
+        }
+
+        // A gamma is the same as its colour space
+        let blur = |builder: BokehBlurBuilder| {
+            let mut img = original.clone();
+            builder.radius(2.0).build().apply_slice(&mut img, w, h);
+            img
+        };
+        assert_eq!(
+            blur(BokehBlur::builder().gamma(2.2)),
+            blur(BokehBlur::builder().color_space(ColorSpace::Gamma(2.2)))
+        );
+        assert_eq!(
+            blur(BokehBlur::builder().gamma(1.0)),
+            blur(BokehBlur::builder().color_space(ColorSpace::Linear))
+        );
+    }
+
+    #[test]
+    fn linear_alpha_skips_the_gamma() {
+        let (w, h) = (8, 6);
+        let original = crate::testing::checkerboard(w, h, 2);
+        let blur = |linear_alpha| {
+            let mut img = original.clone();
+            BokehBlur::builder()
+                .radius(2.0)
+                .gamma(3.0)
+                .linear_alpha(linear_alpha)
+                .build()
+                .apply_slice(&mut img, w, h);
+            img
+        };
+        let (linear, gamma) = (blur(true), blur(false));
+
+        let mut expected = original.clone();
+        crate::bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        for ((l, g), e) in linear.iter().zip(&gamma).zip(&expected) {
+            for c in 0..3 {
+                assert!((l[c] - g[c]).abs() < 1e-9);
+            }
+            assert!((l[3] - e[3]).abs() < 1e-9);
+        }
+        assert_ne!(linear, gamma);
+    }
+
+    #[test]
+    fn blurs_only_the_region() {
+        use crate::{Blur, Image};
+
+        let (w, h) = (17, 13);
+        let original = gradient_image(w, h);
+        let mut expected = original.clone();
+        crate::bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+
+        // The region overhangs the right edge of the image
+        let region = Rect::new(9, 3, 20, 4);
+        let options = BlurOptions::builder()
+            .radius(2.0)
+            .gamma(2.2)
+            .region(region)
+            .build();
+        let mut pixels = original.clone();
+        Image::new(&mut pixels, w, h).bokeh_blur_with(&options);
+
+        for (n, p) in pixels.iter().enumerate() {
+            let (x, y) = (n % w, n / w);
+            let inside = x >= 9 && (3..7).contains(&y);
+            let e = if inside { expected[n] } else { original[n] };
+            for c in 0..4 {
+                assert!((p[c] - e[c]).abs() < 1e-9, "({x}, {y})");
+            }
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn applies_to_dynamic_images() {
+        use image::{DynamicImage, RgbaImage};
+
+        let original = DynamicImage::ImageRgba8(RgbaImage::from_fn(9, 7, |x, y| {
+            image::Rgba([(x * 29) as u8, (y * 31) as u8, 200, 255])
+        }));
+        let mut img = original.clone();
+        BokehBlur::builder()
+            .radius(2.0)
+            .gamma(2.2)
+            .build()
+            .apply(&mut img);
+
+        let mut expected = original;
+        crate::dynamic_image::bokeh_blur(&mut expected, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (a, b) in img.as_bytes().iter().zip(expected.as_bytes()) {
+            assert!(a.abs_diff(*b) <= 1);
+        }
+    }
+}
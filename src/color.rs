@@ -0,0 +1,95 @@
+//! The colour space the pixels are convolved in.
+
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use num::Float;
+
+/// How the pixels are transformed into linear light before convolving, and
+/// back again afterwards.
+///
+/// Light adds linearly, so blurring in linear light keeps bright highlights
+/// bright rather than dimming them into their darker surroundings. Most 8-bit
+/// images are encoded with the sRGB transfer function, which the plain power
+/// law of [`ColorSpace::Gamma`] only approximates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    /// Each value is raised to this power before convolving, and to its
+    /// reciprocal afterwards, as the `gamma` of [`crate::bokeh_blur`]. `1.0`
+    /// for no change.
+    Gamma(f64),
+    /// The pixels are sRGB encoded, so are decoded with the piecewise sRGB
+    /// transfer function before convolving and encoded again afterwards
+    Srgb,
+    /// The pixels are already linear, so are convolved as they are
+    Linear,
+}
+
+impl Default for ColorSpace {
+    /// A gamma of `1.0`, i.e. no change
+    fn default() -> Self {
+        Self::Gamma(1.0)
+    }
+}
+
+impl ColorSpace {
+    /// Decodes `value` into linear light, where `max_value` is white
+    pub(crate) fn decode(self, value: f64, max_value: f64) -> f64 {
+        match self {
+            Self::Gamma(gamma) => value.powf(gamma),
+            Self::Srgb => {
+                let c = value / max_value;
+                max_value
+                    * if c <= 0.04045 {
+                        c / 12.92
+                    } else {
+                        ((c + 0.055) / 1.055).powf(2.4)
+                    }
+            }
+            Self::Linear => value,
+        }
+    }
+
+    /// Encodes `value` from linear light back into this colour space, where
+    /// `max_value` is white
+    pub(crate) fn encode(self, value: f64, max_value: f64) -> f64 {
+        match self {
+            Self::Gamma(gamma) => value.powf(1.0 / gamma),
+            Self::Srgb => {
+                let l = value / max_value;
+                max_value
+                    * if l <= 0.0031308 {
+                        l * 12.92
+                    } else {
+                        1.055 * l.powf(1.0 / 2.4) - 0.055
+                    }
+            }
+            Self::Linear => value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_known_values() {
+        for space in [ColorSpace::Gamma(2.2), ColorSpace::Srgb, ColorSpace::Linear] {
+            for value in [0.0, 1.0, 10.0, 127.5, 200.0, 255.0] {
+                let linear = space.decode(value, 255.0);
+                assert!((space.encode(linear, 255.0) - value).abs() < 1e-9);
+            }
+        }
+
+        // Mid-grey in sRGB is about a fifth of the light of white, and the
+        // linear segment near black meets the curve
+        assert!((ColorSpace::Srgb.decode(127.5, 255.0) / 255.0 - 0.2140).abs() < 1e-4);
+        let knee = 0.04045 * 255.0;
+        assert!(
+            (ColorSpace::Srgb.decode(knee, 255.0) - ColorSpace::Srgb.decode(knee + 1e-9, 255.0))
+                .abs()
+                < 1e-6
+        );
+        assert!((ColorSpace::Srgb.decode(65535.0, 65535.0) - 65535.0).abs() < 1e-9);
+    }
+}
@@ -1,23 +1,111 @@
+use crate::control::BlurControl;
+use crate::edge::EdgeMode;
+use crate::error::BlurError;
+use crate::mask::{mask_bounds, regions_where, Rect};
 use crate::params::KernelParamSet;
+use crate::plan::KernelPlan;
+use crate::tone::ToneMap;
+use crate::Image;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use num::traits::NumAssign;
 use num::Complex;
-use rayon::prelude::*;
 
 #[cfg(feature = "image")]
 use image::{DynamicImage, GenericImageView, Pixel};
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use num::Float as _;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+type ComplexPixel<const N: usize, T = f64> = [Complex<T>; N];
+pub(crate) type Kernel = Vec<Complex<f64>>;
+
+/// Floating point type the convolution can be carried out in, i.e. `f64` or
+/// `f32`
+pub(crate) trait Float: num::Float + NumAssign + Send + Sync {}
+
+impl<T: num::Float + NumAssign + Send + Sync> Float for T {}
+
+/// Converts `x` to the floating point type `T`
+fn cast<T: Float>(x: f64) -> T {
+    // Safety of unwrap: any `f64` can be converted to a float, if only to an
+    // infinity
+    T::from(x).unwrap()
+}
+
+/// Kernel radius, i.e. number of pixels either side of the centre of the
+/// kernel, used for a blur of radius `radius`.
+///
+/// The disc ends at `radius`, so sampling the kernel out to there captures
+/// the disc while keeping the kernel as short, and the blur as fast, as
+/// possible; only the faint tails of the components beyond it are cut off.
+/// [`bokeh_blur`] and the other blurs taking just a radius use this. See
+/// [`kernel_support_for_energy`] for a kernel radius capturing a chosen
+/// fraction of the tails.
+pub fn recommended_kernel_radius(radius: f64) -> usize {
+    radius.ceil() as usize
+}
 
-type ComplexPixel = [Complex<f64>; 4];
+/// Whether a blur of radius `radius` leaves the image unchanged, as its kernel
+/// is a single pixel, in which case the blur is skipped. Otherwise the gamma
+/// round-trip and clamping alone would shift the pixels slightly.
+pub(crate) fn is_identity(radius: f64) -> bool {
+    recommended_kernel_radius(radius) == 0
+}
 
-/// _UNNORMALISED_ complex gaussian kernel
+/// _UNNORMALISED_ complex gaussian kernel sampled to the
+/// [`recommended_kernel_radius`]
 ///
 /// Note: theoretically could break for overflowingly large kernel radii due to
 /// cast from f64 - but that would be ridiculously large
-fn complex_gaussian_kernel(radius: f64, scale: f64, a: f64, b: f64) -> Vec<Complex<f64>> {
-    let kernel_radius = radius.ceil() as usize;
+fn complex_gaussian_kernel_for_radius(
+    radius: f64,
+    scale: f64,
+    a: f64,
+    b: f64,
+) -> Vec<Complex<f64>> {
+    complex_gaussian_kernel(radius, recommended_kernel_radius(radius), scale, a, b)
+}
+
+/// _UNNORMALISED_ complex gaussian kernel of one component, for a blur of
+/// radius `radius`, sampled `kernel_radius` pixels either side of its centre.
+///
+/// Tap `i` from the centre is `exp(-(a - i b) x^2)` for `x = i * scale / radius`,
+/// where `a`, `b` and `scale` are those of the component, see
+/// [`KernelParamSet::a`], [`KernelParamSet::b`] and [`KernelParamSet::scale`].
+/// The components of a blur are normalised together, see
+/// [`normalised_kernels`] for the kernels as they're convolved.
+///
+/// A `kernel_radius` of `0` gives the unit kernel, which leaves the image
+/// unchanged. A `radius` of `0.0` keeps just the centre tap, as the others are
+/// infinitely far out.
+pub fn complex_gaussian_kernel(
+    radius: f64,
+    kernel_radius: usize,
+    scale: f64,
+    a: f64,
+    b: f64,
+) -> Vec<Complex<f64>> {
+    if kernel_radius == 0 {
+        return vec![Complex::new(1.0, 0.0)];
+    }
+
     let mut kernel: Vec<Complex<f64>> = vec![Complex::new(0.0, 0.0); 1 + 2 * (kernel_radius)];
 
     for i in -(kernel_radius as isize)..=(kernel_radius as isize) {
-        let ax = i as f64 * scale / radius;
+        // The centre is at `x = 0` whatever the radius, rather than `0 / 0`
+        let ax = if i == 0 {
+            0.0
+        } else {
+            i as f64 * scale / radius
+        };
         let ax2 = ax * ax;
+        if !ax2.is_finite() {
+            continue;
+        }
         let exp_a = (-a * ax2).exp();
         let val = Complex::new(exp_a * (b * ax2).cos(), exp_a * (b * ax2).sin());
         kernel[(i + kernel_radius as isize) as usize] = val;
@@ -26,24 +114,40 @@ fn complex_gaussian_kernel(radius: f64, scale: f64, a: f64, b: f64) -> Vec<Compl
     kernel
 }
 
-/// Build all the gaussian kernels and normalise w.r.t. params, ie so that after
-/// all the kernels are applied the pixel remains the same brightnes.
-///
-/// Takes `params` corresponding to the number of components to use and a kernel
-/// `radius`.
-pub fn kernel_gaussian_components(params: &KernelParamSet, radius: f64) -> Vec<Vec<Complex<f64>>> {
-    let mut kernels = (0..params.num_kernels())
-        .map(|i| complex_gaussian_kernel(radius, params.scale, params.a(i), params.b(i)))
-        .collect::<Vec<_>>();
+/// _UNNORMALISED_ complex gaussian kernel with its outermost taps scaled by the
+/// fractional part of `radius`, so the kernel grows smoothly with the radius
+/// rather than a whole tap at a time
+fn complex_gaussian_kernel_subpixel(radius: f64, scale: f64, a: f64, b: f64) -> Vec<Complex<f64>> {
+    let mut kernel = complex_gaussian_kernel_for_radius(radius, scale, a, b);
+
+    let fraction = radius - radius.floor();
+    if fraction > 0.0 {
+        let last = kernel.len() - 1;
+        kernel[0] *= fraction;
+        kernel[last] *= fraction;
+    }
+
+    kernel
+}
+
+/// Sum of the 2-D kernel formed by all the components, i.e. the factor the
+/// brightness of an image is scaled by when blurred with `kernels`.
+fn kernel_sum(params: &KernelParamSet, kernels: &[Kernel]) -> f64 {
+    kernel_pair_sum(params, kernels, kernels)
+}
 
-    let sum = kernels
+/// Sum of the 2-D kernel formed by all the components when the `horizontal`
+/// kernels are applied across and the `vertical` kernels down the image.
+fn kernel_pair_sum(params: &KernelParamSet, horizontal: &[Kernel], vertical: &[Kernel]) -> f64 {
+    horizontal
         .iter()
+        .zip(vertical)
         .enumerate()
-        .fold(0.0, |acc, (n, k)| {
+        .fold(0.0, |acc, (n, (h, v))| {
             acc + {
                 let mut s = 0.0;
-                for i in k {
-                    for j in k {
+                for i in h {
+                    for j in v {
                         s += params.real_component(n) * (i.re * j.re - i.im * j.im)
                             + params.imag_component(n) * (i.re * j.im + i.im * j.re)
                     }
@@ -51,7 +155,12 @@ pub fn kernel_gaussian_components(params: &KernelParamSet, radius: f64) -> Vec<V
                 s
             }
         })
-        .sqrt();
+}
+
+/// Normalise the kernels w.r.t. params, ie so that after all the kernels are
+/// applied the pixel's brightness is scaled by `target`.
+fn normalise(params: &KernelParamSet, kernels: &mut [Kernel], target: f64) {
+    let sum = (kernel_sum(params, kernels) / target).sqrt();
 
     for kernel in kernels.iter_mut() {
         for elem in kernel.iter_mut() {
@@ -61,141 +170,611 @@ pub fn kernel_gaussian_components(params: &KernelParamSet, radius: f64) -> Vec<V
 
     // Check normalisation
     debug_assert!(
-        {
-            let mut s = 0.0;
-            for (n, k) in kernels.iter().enumerate() {
-                for i in k {
-                    for j in k {
-                        s += params.real_component(n) * (i.re * j.re - i.im * j.im)
-                            + params.imag_component(n) * (i.re * j.im + i.im * j.re)
-                    }
-                }
-            }
-            s
-        } - 1.0
-            < 0.000000001,
-        "Kernel doesn't sum to 1: {}",
-        {
-            let mut s = 0.0;
-            for (n, k) in kernels.iter().enumerate() {
-                for i in k {
-                    for j in k {
-                        s += params.real_component(n) * (i.re * j.re - i.im * j.im)
-                            + params.imag_component(n) * (i.re * j.im + i.im * j.re)
-                    }
-                }
-            }
-            s
-        }
+        kernel_sum(params, kernels) - target < 0.000000001,
+        "Kernel doesn't sum to {}: {}",
+        target,
+        kernel_sum(params, kernels)
     );
+}
+
+/// Build all the gaussian kernels and normalise w.r.t. params, ie so that after
+/// all the kernels are applied the pixel remains the same brightnes.
+///
+/// Takes `params` corresponding to the number of components to use and a kernel
+/// `radius`.
+pub fn kernel_gaussian_components(params: &KernelParamSet, radius: f64) -> Vec<Vec<Complex<f64>>> {
+    kernel_gaussian_components_normalised_to(params, radius, 1.0)
+}
+
+/// Build all the gaussian kernels and normalise w.r.t. params so that after all
+/// the kernels are applied the pixel's brightness is scaled by `target`.
+///
+/// A `target` of `1.0` preserves the brightness, as
+/// [`kernel_gaussian_components`], while e.g. `2.0` doubles it. Takes `params`
+/// corresponding to the number of components to use and a kernel `radius`.
+pub fn kernel_gaussian_components_normalised_to(
+    params: &KernelParamSet,
+    radius: f64,
+    target: f64,
+) -> Vec<Vec<Complex<f64>>> {
+    let mut kernels = (0..params.num_kernels())
+        .map(|i| complex_gaussian_kernel_for_radius(radius, params.scale, params.a(i), params.b(i)))
+        .collect::<Vec<_>>();
+
+    normalise(params, &mut kernels, target);
 
     kernels
 }
 
-fn horizontal_filter(
-    input: &[ComplexPixel],
-    kernel: &[Complex<f64>],
-    w: usize,
-    h: usize,
-) -> Vec<ComplexPixel> {
-    debug_assert!(input.len() == w * h);
-    let mut output = vec![[Complex::new(0.0, 0.0); 4]; w * h];
-
-    let half_width = kernel.len() / 2;
-    debug_assert!(w >= half_width);
-    debug_assert!(h >= half_width);
-    for j in 0..h {
-        for i in half_width..(w - half_width) {
-            let mut out_pixel = [Complex::default(); 4];
-            for (n, k) in kernel.iter().enumerate() {
-                let x = i as isize - half_width as isize + n as isize;
-                debug_assert!(x >= 0);
-                let x = x as usize;
-
-                for (out_subpixel, in_subpixel) in
-                    out_pixel.iter_mut().zip(input[(j * w) + x].iter())
-                {
-                    *out_subpixel += in_subpixel * k;
-                }
-            }
+/// How far out, relative to the radius, the kernel is sampled when measuring
+/// its energy. The components have decayed to a negligible size by here.
+const ENERGY_REFERENCE_EXTENT: f64 = 4.0;
+
+/// Build all the gaussian kernels for a blur of radius `radius`, sampled
+/// `kernel_radius` pixels either side of their centres, normalised so that
+/// the pixel remains the same brightness.
+pub(crate) fn kernel_gaussian_components_sized(
+    params: &KernelParamSet,
+    radius: f64,
+    kernel_radius: usize,
+) -> Vec<Kernel> {
+    let mut kernels = unnormalised_components_sized(params, radius, kernel_radius);
+
+    normalise(params, &mut kernels, 1.0);
+
+    kernels
+}
+
+/// The kernels of each component of a blur of radius `radius`, sampled
+/// `kernel_radius` pixels either side of their centres, normalised as they
+/// are convolved with the image.
+///
+/// These are exactly the kernels the blurs use, e.g. [`bokeh_blur`] with the
+/// [`recommended_kernel_radius`], before the real and imaginary weights of
+/// each component are applied. Each is applied across and then down the
+/// image, and the results weighted and summed, see [`reconstruct_kernel_2d`]
+/// for the combined kernel. See [`complex_gaussian_kernel`] for a component
+/// before normalising.
+pub fn normalised_kernels(
+    param_set: &KernelParamSet,
+    radius: f64,
+    kernel_radius: usize,
+) -> Vec<Vec<Complex<f64>>> {
+    kernel_gaussian_components_sized(param_set, radius, kernel_radius)
+}
+
+/// _UNNORMALISED_ gaussian kernels for a blur of radius `radius`, sampled
+/// `kernel_radius` pixels either side of their centres
+fn unnormalised_components_sized(
+    params: &KernelParamSet,
+    radius: f64,
+    kernel_radius: usize,
+) -> Vec<Kernel> {
+    (0..params.num_kernels())
+        .map(|i| {
+            complex_gaussian_kernel(
+                radius,
+                kernel_radius,
+                params.scale,
+                params.a(i),
+                params.b(i),
+            )
+        })
+        .collect()
+}
+
+/// Sum of the 2-D kernel of a blur of radius `r`, sampled `kernel_radius`
+/// pixels either side of the centre, i.e. the factor the brightness of an
+/// image is scaled by when blurred.
+///
+/// The kernels are normalised by the square root of the sum of the components
+/// before normalising, so for a usable `param_set` this is `1.0` up to
+/// rounding. Coefficients which don't combine into a kernel with a positive
+/// sum, e.g. fitted with the wrong sign, can't be normalised and give `NaN` or
+/// an infinite gain, in which case the blur fills the image with the same, and
+/// the same check made with `debug_assert!` while building the kernels
+/// panics. This makes the check available in release builds, for validating a
+/// custom param set before use.
+pub fn kernel_dc_gain(param_set: &KernelParamSet, r: f64, kernel_radius: usize) -> f64 {
+    // Normalised as `normalise` does, without its check, which would panic in
+    // debug builds for exactly the param sets this is meant to catch
+    let mut kernels = unnormalised_components_sized(param_set, r, kernel_radius);
+    let norm = kernel_sum(param_set, &kernels).sqrt();
+    for elem in kernels.iter_mut().flatten() {
+        *elem /= norm;
+    }
+
+    kernel_sum(param_set, &kernels)
+}
+
+/// Smallest kernel radius, i.e. number of pixels either side of the centre of
+/// the kernel, capturing a fraction `energy` of the energy of the kernel for a
+/// blur of radius `radius`.
+///
+/// The components of the kernel are Gaussians, so in theory never reach zero.
+/// The energy is the sum of the magnitudes of the combined 2-D kernel, taken
+/// out to where the components are negligible. The kernels usually built,
+/// e.g. by [`kernel_gaussian_components`], stop at the radius of the blur,
+/// which cuts off some of the tails; an `energy` closer to `1.0` gives a
+/// larger, more accurate but slower kernel. `energy` is clamped to
+/// `0.0..=1.0`.
+pub fn kernel_support_for_energy(params: &KernelParamSet, radius: f64, energy: f64) -> usize {
+    let extent = (ENERGY_REFERENCE_EXTENT * radius / params.scale).ceil() as usize;
+    let kernels = weight_kernels(
+        params,
+        kernel_gaussian_components_sized(params, radius, extent),
+    );
 
-            output[(j * w) + i] = out_pixel;
+    // Energy of each ring of pixels `k` from the centre
+    let mut rings = vec![0.0; extent + 1];
+    for (i, ring_i) in (0..=2 * extent).map(|i| i.abs_diff(extent)).enumerate() {
+        for (j, ring_j) in (0..=2 * extent).map(|j| j.abs_diff(extent)).enumerate() {
+            let value = kernels
+                .iter()
+                .fold(0.0, |acc, (h, v)| acc + (h[i] * v[j]).re);
+            rings[ring_i.max(ring_j)] += value.abs();
         }
+    }
 
-        for i in (0..half_width).chain((w - half_width)..w) {
-            let mut out_pixel = [Complex::default(); 4];
-            for (n, k) in kernel.iter().enumerate() {
-                let x = i as isize - half_width as isize + n as isize;
-                if x < 0 || x >= w as isize {
-                    continue;
-                }
-                let x = x as usize;
+    let target = energy.clamp(0.0, 1.0) * rings.iter().sum::<f64>();
+    let mut captured = 0.0;
+    rings
+        .iter()
+        .position(|ring| {
+            captured += ring;
+            captured >= target
+        })
+        .unwrap_or(extent)
+}
 
-                for (out_subpixel, in_subpixel) in
-                    out_pixel.iter_mut().zip(input[(j * w) + x].iter())
-                {
-                    *out_subpixel += in_subpixel * k;
-                }
-            }
+/// Build all the gaussian kernels as [`kernel_gaussian_components`], but with
+/// sub-pixel sized radii.
+///
+/// The kernels always span a whole number of pixels, so normally the outermost
+/// taps appear with their full weight as soon as `radius` passes an integer,
+/// stepping the spread of the blur. Here the outermost taps are scaled by the
+/// fractional part of `radius` instead, as if the edge of the kernel were
+/// sampled part way through a pixel, so the spread grows smoothly as `radius`
+/// increases. Integer radii give the same kernels as
+/// [`kernel_gaussian_components`].
+pub fn kernel_gaussian_components_subpixel(
+    params: &KernelParamSet,
+    radius: f64,
+) -> Vec<Vec<Complex<f64>>> {
+    let mut kernels = (0..params.num_kernels())
+        .map(|i| complex_gaussian_kernel_subpixel(radius, params.scale, params.a(i), params.b(i)))
+        .collect::<Vec<_>>();
+
+    normalise(params, &mut kernels, 1.0);
+
+    kernels
+}
+
+/// Build the horizontal and vertical gaussian kernels of an elliptical kernel,
+/// with radius `radius_x` across and `radius_y` down the image.
+///
+/// Both sets are normalised together, so that after all the kernels are
+/// applied the pixel remains the same brightness. Equal radii give the same
+/// kernels as [`kernel_gaussian_components`] for both axes.
+fn kernel_gaussian_components_anisotropic(
+    params: &KernelParamSet,
+    radius_x: f64,
+    radius_y: f64,
+) -> (Vec<Kernel>, Vec<Kernel>) {
+    let build = |radius| {
+        (0..params.num_kernels())
+            .map(|i| {
+                complex_gaussian_kernel_for_radius(radius, params.scale, params.a(i), params.b(i))
+            })
+            .collect::<Vec<_>>()
+    };
+    let (mut horizontal, mut vertical) = (build(radius_x), build(radius_y));
 
-            output[(j * w) + i] = out_pixel;
+    let sum = kernel_pair_sum(params, &horizontal, &vertical).sqrt();
+    for kernel in horizontal.iter_mut().chain(vertical.iter_mut()) {
+        for elem in kernel.iter_mut() {
+            *elem /= sum;
         }
     }
 
-    output
+    (horizontal, vertical)
+}
+
+/// Build all the gaussian kernels as [`kernel_gaussian_components`], then trim
+/// the taps that contribute negligibly from their tails.
+///
+/// A tap is negligible if every value of the combined 2-D kernel in its row
+/// (equivalently column) has a magnitude below `threshold` times the largest
+/// value of the 2-D kernel. Only the tails of the kernels are trimmed, back to
+/// the outermost tap that isn't negligible, so all the kernels stay the same
+/// length and centred. The trimmed kernels are then normalised again so the
+/// brightness is still preserved. A `threshold` of `0.0` trims nothing, while
+/// larger values give shorter, faster kernels that approximate the disc less
+/// accurately.
+pub fn kernel_gaussian_components_pruned(
+    params: &KernelParamSet,
+    radius: f64,
+    threshold: f64,
+) -> Vec<Vec<Complex<f64>>> {
+    let mut kernels = kernel_gaussian_components(params, radius);
+
+    // Largest magnitude of the 2-D kernel in the row and column of each tap
+    let half_width = kernels[0].len() / 2;
+    let weighted = weight_kernels(params, kernels.clone());
+    let magnitudes = (0..kernels[0].len())
+        .map(|i| {
+            (0..kernels[0].len()).fold(0.0, |acc: f64, j| {
+                acc.max(
+                    weighted
+                        .iter()
+                        .fold(0.0, |sum, (h, v)| sum + (h[i] * v[j]).re)
+                        .abs(),
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    let peak = magnitudes.iter().fold(0.0, |acc: f64, &m| acc.max(m));
+    let keep = (0..=half_width)
+        .rev()
+        .find(|&i| {
+            magnitudes[half_width - i] >= threshold * peak
+                || magnitudes[half_width + i] >= threshold * peak
+        })
+        .unwrap_or(0);
+
+    if keep < half_width {
+        for kernel in kernels.iter_mut() {
+            kernel.truncate(half_width + keep + 1);
+            kernel.drain(..half_width - keep);
+        }
+        normalise(params, &mut kernels, 1.0);
+    }
+
+    kernels
+}
+
+/// Samples the profile of the combined kernel with radius `kernel_size` at
+/// `samples` evenly spaced points, for plotting its shape.
+///
+/// The profile is the weighted sum of the real and imaginary parts of each of
+/// the components corresponding to `param_set`, normalised as
+/// [`kernel_gaussian_components`], i.e. the shape of the kernel along one
+/// axis. The points run from `-kernel_size` to `kernel_size` pixels from the
+/// centre, so with `2 * kernel_size + 1` samples they land on the taps of the
+/// kernels, while more samples show the shape between the taps. An odd number
+/// of samples includes the centre.
+///
+/// As the kernel approximates a disc, the profile is flat across the middle,
+/// up to the ripples of the approximation, and falls to zero at the edges.
+/// Fewer components give larger ripples, so the profile may peak either side
+/// of the centre.
+pub fn sample_combined_kernel(
+    param_set: &KernelParamSet,
+    kernel_size: f64,
+    samples: usize,
+) -> Vec<f64> {
+    let kernels = (0..param_set.num_kernels())
+        .map(|i| {
+            complex_gaussian_kernel_for_radius(
+                kernel_size,
+                param_set.scale,
+                param_set.a(i),
+                param_set.b(i),
+            )
+        })
+        .collect::<Vec<_>>();
+    let norm = kernel_sum(param_set, &kernels).sqrt();
+
+    let step = 2.0 * kernel_size / samples.saturating_sub(1).max(1) as f64;
+    (0..samples)
+        .map(|i| {
+            let ax = (i as f64 * step - kernel_size) * param_set.scale / kernel_size;
+            let ax2 = ax * ax;
+            (0..param_set.num_kernels()).fold(0.0, |acc, n| {
+                let exp_a = (-param_set.a(n) * ax2).exp();
+                let (sin, cos) = (param_set.b(n) * ax2).sin_cos();
+                acc + param_set.real_component(n) * exp_a * cos
+                    + param_set.imag_component(n) * exp_a * sin
+            }) / norm
+        })
+        .collect()
+}
+
+/// Reconstructs the combined 2-D kernel of a blur of radius `radius`, sampled
+/// `kernel_radius` pixels either side of the centre, for inspecting or
+/// plotting the shape actually convolved with the image.
+///
+/// The blur applies each component as a horizontal then a vertical pass, so
+/// the 2-D kernel is the sum across the components of the weighted outer
+/// products of their 1-D kernels. Returns the kernel stored row by row along
+/// with its side length, `2 * kernel_radius + 1`. The values sum to roughly
+/// `1.0`, as the brightness is preserved.
+pub fn reconstruct_kernel_2d(
+    param_set: &KernelParamSet,
+    radius: f64,
+    kernel_radius: usize,
+) -> (Vec<f64>, usize) {
+    let side = 2 * kernel_radius + 1;
+    let kernels = weight_kernels(
+        param_set,
+        kernel_gaussian_components_sized(param_set, radius, kernel_radius),
+    );
+
+    let kernel = (0..side * side)
+        .map(|n| {
+            let (y, x) = (n / side, n % side);
+            kernels
+                .iter()
+                .fold(0.0, |sum, (h, v)| sum + (h[x] * v[y]).re)
+        })
+        .collect();
+
+    (kernel, side)
+}
+
+/// Pairs of (horizontal, vertical) kernels with each component's real and
+/// imaginary weights folded into the horizontal kernel.
+///
+/// As `re * z.re + im * z.im == (z * (re - i * im)).re`, scaling a component's
+/// horizontal kernel by `re - i * im` means only the real part of the filtered
+/// image needs to be taken, rather than weighting every pixel.
+pub(crate) fn weight_kernels(
+    params: &KernelParamSet,
+    kernels: Vec<Kernel>,
+) -> Vec<(Kernel, Kernel)> {
+    weight_kernel_pairs(params, kernels.clone(), kernels)
+}
+
+/// Same as [`weight_kernels`] but with different kernels for each axis
+fn weight_kernel_pairs(
+    params: &KernelParamSet,
+    horizontal: Vec<Kernel>,
+    vertical: Vec<Kernel>,
+) -> Vec<(Kernel, Kernel)> {
+    horizontal
+        .into_iter()
+        .zip(vertical)
+        .enumerate()
+        .map(|(n, (horizontal, vertical))| {
+            let weight = Complex::new(params.real_component(n), -params.imag_component(n));
+            (horizontal.iter().map(|k| k * weight).collect(), vertical)
+        })
+        .collect()
+}
+
+/// The taps of a kernel centred on one index along an axis, and the pixels
+/// they read
+enum Taps {
+    /// The taps in `taps` read consecutive pixels, starting from `first`
+    Contiguous { taps: Range<usize>, first: usize },
+    /// Each tap reads the pixel paired with it, for kernels overhanging the
+    /// edges of the image
+    Mapped(Vec<(usize, usize)>),
 }
 
-fn vertical_filter(
-    input: &[ComplexPixel],
-    kernel: &[Complex<f64>],
+/// For each index along an axis of length `len`, the taps of a kernel of
+/// length `kernel_len` centred on it and the pixels they read.
+///
+/// Tap `n` of the kernel centred on index `i` reads the pixel at
+/// `i + n - kernel_len / 2`. Where that is beyond the edges of the image, the
+/// pixel is found by `edge`; with [`EdgeMode::Zero`], and the modes corrected
+/// for by [`fill_edges`], those taps are skipped.
+fn axis_taps(len: usize, kernel_len: usize, edge: EdgeMode) -> Vec<Taps> {
+    let half_width = kernel_len / 2;
+    (0..len)
+        .map(|i| {
+            let taps = half_width.saturating_sub(i)..kernel_len.min(len + half_width - i);
+            if edge.convolves_black() || taps == (0..kernel_len) {
+                let first = i + taps.start - half_width;
+                Taps::Contiguous { taps, first }
+            } else {
+                Taps::Mapped(
+                    (0..kernel_len)
+                        .filter_map(|n| {
+                            let pos = i as isize + n as isize - half_width as isize;
+                            edge.resolve(pos, len).map(|p| (n, p))
+                        })
+                        .collect(),
+                )
+            }
+        })
+        .collect()
+}
+
+/// Corrects `blurred`, an image of size `w` by `h` convolved with `kernels`
+/// with the pixels beyond its edges black, for the edge modes which aren't
+/// sampled from the image.
+///
+/// The part of the kernel covering the image at each pixel is separable like
+/// the kernel itself, so is found from the taps of each component inside the
+/// image without convolving. With [`EdgeMode::Renormalize`] the pixels are
+/// scaled up by the part of the kernel beyond the edges, and with
+/// [`EdgeMode::ConstantColor`] that part of the kernel is filled with the
+/// colour, converted to the space convolved in by `decode` along with the index
+/// of its channel. Other modes are left unchanged.
+pub(crate) fn fill_edges(
+    blurred: &mut [[f64; 4]],
     w: usize,
     h: usize,
-) -> Vec<ComplexPixel> {
-    debug_assert!(input.len() == w * h);
-    let mut output = vec![[Complex::new(0.0, 0.0); 4]; w * h];
-
-    let half_width = kernel.len() / 2;
-    for i in 0..w {
-        for j in half_width..(h - half_width) {
-            let mut out_pixel = [Complex::default(); 4];
-            for (n, k) in kernel.iter().enumerate() {
-                let y = j as isize - half_width as isize + n as isize;
-                debug_assert!(y >= 0);
-                let y = y as usize;
-
-                for (o, p) in out_pixel.iter_mut().zip(input[(y * w) + i].iter()) {
-                    *o += p * k;
+    kernels: &[(Kernel, Kernel)],
+    edge: EdgeMode,
+    decode: impl Fn(usize, f64) -> f64,
+) {
+    debug_assert_eq!(blurred.len(), w * h);
+    if !matches!(edge, EdgeMode::Renormalize | EdgeMode::ConstantColor(_)) {
+        return;
+    }
+
+    // Sum of the taps of `kernel` inside an axis of length `len` when centred
+    // on each index along it
+    let inside = |kernel: &Kernel, len: usize| {
+        axis_taps(len, kernel.len(), EdgeMode::Zero)
+            .into_iter()
+            .map(|taps| match taps {
+                Taps::Contiguous { taps, .. } => kernel[taps].iter().sum::<Complex<f64>>(),
+                Taps::Mapped(_) => unreachable!("taps beyond the edges are skipped"),
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut total = 0.0;
+    let mut coverage = vec![0.0; w * h];
+    for (horizontal, vertical) in kernels {
+        let sum = |kernel: &Kernel| kernel.iter().sum::<Complex<f64>>();
+        total += (sum(horizontal) * sum(vertical)).re;
+
+        let (across, down) = (inside(horizontal, w), inside(vertical, h));
+        for (n, c) in coverage.iter_mut().enumerate() {
+            *c += (across[n % w] * down[n / w]).re;
+        }
+    }
+
+    for (pixel, c) in blurred.iter_mut().zip(coverage) {
+        match edge {
+            // Corners too far from the image to be covered by the kernel are
+            // left as they are
+            EdgeMode::Renormalize if c > f64::EPSILON * total => {
+                *pixel = pixel.map(|p| p * total / c);
+            }
+            EdgeMode::ConstantColor(color) => {
+                for (i, p) in pixel.iter_mut().enumerate() {
+                    *p += decode(i, color[i]) * (total - c);
                 }
             }
-
-            output[(j * w) + i] = out_pixel;
+            _ => {}
         }
+    }
+}
+
+/// Filters each row of `input` with `kernel`, writing the result to `output`.
+///
+/// With `parallel_rows`, and the `rayon` feature, the rows are filtered in
+/// parallel, on top of the components convolved in parallel, so blurs with few
+/// components still use every thread.
+fn horizontal_filter<const N: usize, T: Float>(
+    input: &[ComplexPixel<N, T>],
+    kernel: &[Complex<T>],
+    w: usize,
+    h: usize,
+    edge: EdgeMode,
+    output: &mut [ComplexPixel<N, T>],
+    #[cfg_attr(not(feature = "rayon"), allow(unused_variables))] parallel_rows: bool,
+) {
+    debug_assert!(input.len() == w * h && output.len() == w * h);
 
-        for j in (0..half_width).chain((h - half_width)..h) {
-            let mut out_pixel = [Complex::default(); 4];
-            for (n, k) in kernel.iter().enumerate() {
-                let y = j as isize - half_width as isize + n as isize;
-                if y < 0 || y >= h as isize {
-                    continue;
+    let taps = axis_taps(w, kernel.len(), edge);
+    let filter_row = |(in_row, out_row): (&[ComplexPixel<N, T>], &mut [ComplexPixel<N, T>])| {
+        for (out_pixel, taps) in out_row.iter_mut().zip(&taps) {
+            let mut sum = [Complex::new(T::zero(), T::zero()); N];
+            let mut add = |k: &Complex<T>, in_pixel: &ComplexPixel<N, T>| {
+                for (out_subpixel, in_subpixel) in sum.iter_mut().zip(in_pixel.iter()) {
+                    *out_subpixel += in_subpixel * k;
+                }
+            };
+            match taps {
+                Taps::Contiguous { taps, first } => {
+                    for (k, in_pixel) in kernel[taps.clone()].iter().zip(&in_row[*first..]) {
+                        add(k, in_pixel);
+                    }
+                }
+                Taps::Mapped(mapped) => {
+                    for &(n, x) in mapped {
+                        add(&kernel[n], &in_row[x]);
+                    }
                 }
-                let y = y as usize;
+            }
+            *out_pixel = sum;
+        }
+    };
+
+    #[cfg(feature = "rayon")]
+    if parallel_rows {
+        return input
+            .par_chunks_exact(w.max(1))
+            .zip(output.par_chunks_exact_mut(w.max(1)))
+            .for_each(filter_row);
+    }
+    input
+        .chunks_exact(w.max(1))
+        .zip(output.chunks_exact_mut(w.max(1)))
+        .for_each(filter_row);
+}
+
+/// Filters each column of `input` with `kernel`, writing the result to
+/// `output`.
+///
+/// Each row of the output reads several rows of the input, but the input is
+/// only read, so with `parallel_rows`, and the `rayon` feature, the output rows
+/// are still filled in parallel.
+fn vertical_filter<const N: usize, T: Float>(
+    input: &[ComplexPixel<N, T>],
+    kernel: &[Complex<T>],
+    w: usize,
+    h: usize,
+    edge: EdgeMode,
+    output: &mut [ComplexPixel<N, T>],
+    #[cfg_attr(not(feature = "rayon"), allow(unused_variables))] parallel_rows: bool,
+) {
+    debug_assert!(input.len() == w * h && output.len() == w * h);
 
-                for (o, p) in out_pixel.iter_mut().zip(input[(y * w) + i].iter()) {
+    let taps = axis_taps(h, kernel.len(), edge);
+    let filter_row = |(out_row, taps): (&mut [ComplexPixel<N, T>], &Taps)| {
+        for (i, out_pixel) in out_row.iter_mut().enumerate() {
+            let mut sum = [Complex::new(T::zero(), T::zero()); N];
+            let mut add = |k: &Complex<T>, in_pixel: &ComplexPixel<N, T>| {
+                for (o, p) in sum.iter_mut().zip(in_pixel.iter()) {
                     *o += p * k;
                 }
+            };
+            match taps {
+                Taps::Contiguous { taps, first } => {
+                    for (k, in_pixel) in kernel[taps.clone()]
+                        .iter()
+                        .zip(input[first * w + i..].iter().step_by(w))
+                    {
+                        add(k, in_pixel);
+                    }
+                }
+                Taps::Mapped(mapped) => {
+                    for &(n, y) in mapped {
+                        add(&kernel[n], &input[y * w + i]);
+                    }
+                }
             }
-
-            output[(j * w) + i] = out_pixel;
+            *out_pixel = sum;
         }
-    }
+    };
 
+    #[cfg(feature = "rayon")]
+    if parallel_rows {
+        return output
+            .par_chunks_exact_mut(w.max(1))
+            .zip(&taps)
+            .for_each(filter_row);
+    }
     output
+        .chunks_exact_mut(w.max(1))
+        .zip(&taps)
+        .for_each(filter_row);
 }
 
-struct ComplexImage {
-    pixels: Vec<ComplexPixel>,
+/// Buffers reused by every kernel component convolved on one thread, so each
+/// component doesn't allocate its own full-size images
+struct Scratch<const N: usize, T> {
+    /// The image after the horizontal filter
+    horizontal: Vec<ComplexPixel<N, T>>,
+    /// The image after both filters
+    vertical: Vec<ComplexPixel<N, T>>,
+    /// Running sum of the real parts of the components convolved so far
+    sum: Vec<[T; N]>,
+}
+
+/// An image with `N` channels, e.g. 4 for RGBA, held as complex numbers ready
+/// for convolution
+pub(crate) struct ComplexImage<const N: usize = 4, T = f64> {
+    pixels: Vec<ComplexPixel<N, T>>,
     w: usize,
     h: usize,
+    edge: EdgeMode,
 }
 
 impl ComplexImage {
@@ -221,197 +800,4232 @@ impl ComplexImage {
             pixels: input,
             w: w as usize,
             h: h as usize,
+            edge: EdgeMode::Zero,
         }
     }
+}
+
+/// Raises `c` to the power of `gamma`, treating values which aren't finite
+/// afterwards as black rather than letting them spread across the kernel
+fn apply_gamma<T: Float>(c: T, gamma: T) -> T {
+    let c = c.powf(gamma);
+    if c.is_finite() {
+        c
+    } else {
+        T::zero()
+    }
+}
+
+impl<const N: usize, T: Float> ComplexImage<N, T> {
+    /// From an image stored as a vector with `N` channels
+    pub fn from_slice(img: &[[T; N]], w: usize, h: usize, gamma: f64) -> Self {
+        Self::from_slice_per_channel(img, w, h, [gamma; N])
+    }
 
-    /// From an image stored as a vector with 4 channels
-    pub fn from_slice(img: &[[f64; 4]], w: usize, h: usize, gamma: f64) -> Self {
+    /// From an image stored as a vector with `N` channels, where each channel
+    /// has its own gamma
+    pub fn from_slice_per_channel(img: &[[T; N]], w: usize, h: usize, gamma: [f64; N]) -> Self {
+        let gamma = gamma.map(cast::<T>);
         let pixels = img
             .iter()
-            .map(|c| {
-                [
-                    Complex::new(c[0].powf(gamma), 0.0),
-                    Complex::new(c[1].powf(gamma), 0.0),
-                    Complex::new(c[2].powf(gamma), 0.0),
-                    Complex::new(c[3].powf(gamma), 0.0),
-                ]
-            })
+            .map(|c| core::array::from_fn(|i| Complex::new(apply_gamma(c[i], gamma[i]), T::zero())))
             .collect::<Vec<_>>();
 
-        Self { pixels, w, h }
+        Self {
+            pixels,
+            w,
+            h,
+            edge: EdgeMode::Zero,
+        }
     }
 
-    fn bokeh_blur(self, param_set: &KernelParamSet, radius: f64) -> Vec<[f64; 4]> {
-        kernel_gaussian_components(param_set, radius)
-            .par_iter()
-            .enumerate()
-            .map(|(n, kernel)| {
-                let temp = horizontal_filter(&self.pixels, kernel, self.w, self.h);
-                vertical_filter(&temp, kernel, self.w, self.h)
-                    .iter()
-                    .map(|pixel| {
-                        let re = param_set.real_component(n);
-                        let im = param_set.imag_component(n);
-                        [
-                            re * pixel[0].re + im * pixel[0].im,
-                            re * pixel[1].re + im * pixel[1].im,
-                            re * pixel[2].re + im * pixel[2].im,
-                            re * pixel[3].re + im * pixel[3].im,
-                        ]
-                    })
-                    .collect()
-            })
-            .reduce(
-                || vec![[0.0; 4]; self.w * self.h],
-                |mut a, b| {
-                    for (x, y) in a.iter_mut().zip(b.iter()) {
-                        x[0] += y[0];
-                        x[1] += y[1];
-                        x[2] += y[2];
-                        x[3] += y[3];
-                    }
-                    a
-                },
-            )
+    /// From the pixels of `region` of an image stored as a vector with `N`
+    /// channels
+    pub fn from_region(img: &[[T; N]], width: usize, region: Rect, gamma: f64) -> Self {
+        let gamma = cast(gamma);
+        let pixels = (region.y..region.y + region.height)
+            .flat_map(|j| &img[j * width + region.x..j * width + region.x + region.width])
+            .map(|c| c.map(|c| Complex::new(apply_gamma(c, gamma), T::zero())))
+            .collect::<Vec<_>>();
+
+        Self {
+            pixels,
+            w: region.width,
+            h: region.height,
+            edge: EdgeMode::Zero,
+        }
     }
-}
 
-/// Blurs an image using an approximation of a disc-shaped kernel to produce a
-/// Bokeh lens effect.
-///
-/// Takes an exclusive reference to a slice of size 4 arrays, where each array
-/// element corresponds to a pixel. Each element of the array corresponds to R,
-/// G, B, A. Also requires the `width` and `height` of the image. The image is
-/// blurred by a disc-shaped kernel with radius `radius`, built from
-/// components corresponding to `param_set`. The exposure can be modified using
-/// `gamma`, set to `1.0` for no change.
-pub fn bokeh_blur(
-    img: &mut [[f64; 4]],
-    width: usize,
-    height: usize,
-    radius: f64,
-    param_set: &KernelParamSet,
-    gamma: f64,
-) {
-    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
-        .bokeh_blur(param_set, radius)
-        .into_iter()
-        .enumerate()
-    {
-        // Clamp any values from floating point ops
-        img[n] = rgba.map(|i| i.powf(1.0 / gamma).clamp(0.0, 255.0));
+    /// Samples the pixels beyond the edges of the image according to `edge`
+    /// when convolving
+    pub(crate) fn with_edge_mode(self, edge: EdgeMode) -> Self {
+        Self { edge, ..self }
     }
-}
 
-/// Blurs the selected parts of an image using an approximation of a disc-shaped
-/// kernel to produce a Bokeh lens effect.
-///
-/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+    fn bokeh_blur(self, param_set: &KernelParamSet, radius: f64) -> Vec<[T; N]> {
+        self.convolve(KernelPlan::for_radius(param_set, radius).kernels())
+    }
+
+    /// Convolves the image with each pair of (horizontal, vertical) kernels and
+    /// sums the real parts of the results.
+    ///
+    /// The components are convolved in parallel but summed in the order of
+    /// `kernels`, so the result is the same bit for bit however rayon
+    /// schedules them, and the same as [`ComplexImage::convolve_sequential`].
+    #[cfg(feature = "rayon")]
+    pub(crate) fn convolve(self, kernels: &[(Kernel, Kernel)]) -> Vec<[T; N]> {
+        // Safety of unwrap: never cancelled
+        self.convolve_cancellable(kernels, &|| false, &|| {})
+            .unwrap()
+    }
+
+    /// Convolves the image with each pair of (horizontal, vertical) kernels and
+    /// sums the real parts of the results, on the current thread as rayon is
+    /// unavailable
+    #[cfg(not(feature = "rayon"))]
+    pub(crate) fn convolve(self, kernels: &[(Kernel, Kernel)]) -> Vec<[T; N]> {
+        self.convolve_sequential(kernels)
+    }
+
+    /// Same as [`ComplexImage::convolve`] but runs on the current thread
+    pub(crate) fn convolve_sequential(self, kernels: &[(Kernel, Kernel)]) -> Vec<[T; N]> {
+        let mut scratch = self.scratch();
+        for kernel in kernels {
+            self.convolve_component(kernel, &mut scratch, false);
+        }
+        scratch.sum
+    }
+
+    /// Same as [`ComplexImage::convolve`] but reports progress to, and stops
+    /// early if cancelled through, `control`.
+    ///
+    /// Returns `None` if the blur was cancelled.
+    fn convolve_controlled(
+        self,
+        kernels: &[(Kernel, Kernel)],
+        control: &BlurControl,
+    ) -> Option<Vec<[T; N]>> {
+        control.start(kernels.len());
+        self.convolve_cancellable(kernels, &|| control.is_cancelled(), &|| control.step())
+    }
+
+    /// Same as [`ComplexImage::convolve`] but polls `should_cancel` before
+    /// and after each component, stopping early once it returns `true`, and
+    /// calls `on_step` after each component.
+    ///
+    /// Returns `None` if the blur was cancelled.
+    #[cfg(feature = "rayon")]
+    fn convolve_cancellable(
+        self,
+        kernels: &[(Kernel, Kernel)],
+        should_cancel: &(impl Fn() -> bool + Sync),
+        on_step: &(impl Fn() + Sync),
+    ) -> Option<Vec<[T; N]>> {
+        // Each component is convolved into its own buffer and they are summed
+        // in order, as the order floats are added in changes the result. Only
+        // as many components as there are threads are held at once, in buffers
+        // reused from one batch to the next, bounding the memory used
+        let mut slots = (0..rayon::current_num_threads().min(kernels.len()))
+            .map(|_| self.scratch())
+            .collect::<Vec<_>>();
+        let mut sum = vec![[T::zero(); N]; self.w * self.h];
+        for batch in kernels.chunks(slots.len().max(1)) {
+            slots
+                .par_iter_mut()
+                .zip(batch)
+                .try_for_each(|(scratch, kernel)| {
+                    if should_cancel() {
+                        return None;
+                    }
+                    scratch.sum.fill([T::zero(); N]);
+                    self.convolve_component(kernel, scratch, true);
+                    on_step();
+                    Some(())
+                })?;
+            for scratch in &slots[..batch.len()] {
+                add_pixels(&mut sum, &scratch.sum);
+            }
+        }
+
+        Some(sum).filter(|_| !should_cancel())
+    }
+
+    /// Same as [`ComplexImage::convolve`] but polls `should_cancel` before
+    /// and after each component, stopping early once it returns `true`, and
+    /// calls `on_step` after each component, on the current thread as rayon is
+    /// unavailable.
+    ///
+    /// Returns `None` if the blur was cancelled.
+    #[cfg(not(feature = "rayon"))]
+    fn convolve_cancellable(
+        self,
+        kernels: &[(Kernel, Kernel)],
+        should_cancel: &(impl Fn() -> bool + Sync),
+        on_step: &(impl Fn() + Sync),
+    ) -> Option<Vec<[T; N]>> {
+        let mut scratch = self.scratch();
+        for kernel in kernels {
+            if should_cancel() {
+                return None;
+            }
+            self.convolve_component(kernel, &mut scratch, false);
+            on_step();
+        }
+        Some(scratch.sum).filter(|_| !should_cancel())
+    }
+
+    /// Empty buffers for convolving the image, see [`Scratch`]
+    fn scratch(&self) -> Scratch<N, T> {
+        let zero = [Complex::new(T::zero(), T::zero()); N];
+        Scratch {
+            horizontal: vec![zero; self.w * self.h],
+            vertical: vec![zero; self.w * self.h],
+            sum: vec![[T::zero(); N]; self.w * self.h],
+        }
+    }
+
+    /// Convolves the image with a pair of (horizontal, vertical) kernels,
+    /// adding the real part of the result to the sum in `scratch`, with the
+    /// rows filtered in parallel if `parallel_rows`
+    fn convolve_component(
+        &self,
+        (horizontal, vertical): &(Kernel, Kernel),
+        scratch: &mut Scratch<N, T>,
+        parallel_rows: bool,
+    ) {
+        let cast_kernel = |kernel: &Kernel| {
+            kernel
+                .iter()
+                .map(|k| Complex::new(cast(k.re), cast(k.im)))
+                .collect::<Vec<_>>()
+        };
+        horizontal_filter(
+            &self.pixels,
+            &cast_kernel(horizontal),
+            self.w,
+            self.h,
+            self.edge,
+            &mut scratch.horizontal,
+            parallel_rows,
+        );
+        vertical_filter(
+            &scratch.horizontal,
+            &cast_kernel(vertical),
+            self.w,
+            self.h,
+            self.edge,
+            &mut scratch.vertical,
+            parallel_rows,
+        );
+
+        for (sum, pixel) in scratch.sum.iter_mut().zip(&scratch.vertical) {
+            for (s, c) in sum.iter_mut().zip(pixel) {
+                *s += c.re;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+fn add_pixels<const N: usize, T: Float>(a: &mut [[T; N]], b: &[[T; N]]) {
+    for (x, y) in a.iter_mut().zip(b) {
+        for (x, y) in x.iter_mut().zip(y) {
+            *x += *y;
+        }
+    }
+}
+
+/// Blurs `region` of an image, reading the pixels up to the kernel radius
+/// around it so the result matches blurring the whole image.
+///
+/// Returns the blurred pixels of `region` row by row, before the gamma is
+/// inverted.
+pub(crate) fn blur_region(
+    img: &[[f64; 4]],
+    width: usize,
+    height: usize,
+    region: Rect,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) -> Vec<[f64; 4]> {
+    let kernels = weight_kernels(param_set, kernel_gaussian_components(param_set, radius));
+    blur_region_with_kernels(img, width, height, region, &kernels, gamma)
+}
+
+/// Same as [`blur_region`] but convolving with the weighted pairs of
+/// (horizontal, vertical) `kernels`
+pub(crate) fn blur_region_with_kernels(
+    img: &[[f64; 4]],
+    width: usize,
+    height: usize,
+    region: Rect,
+    kernels: &[(Kernel, Kernel)],
+    gamma: f64,
+) -> Vec<[f64; 4]> {
+    let half_width = kernels
+        .iter()
+        .map(|(h, v)| h.len().max(v.len()) / 2)
+        .max()
+        .unwrap_or(0);
+    let crop = region.expand(half_width, width, height);
+    let blurred = ComplexImage::from_region(img, width, crop, gamma).convolve(kernels);
+
+    (region.y..region.y + region.height)
+        .flat_map(|j| {
+            let start = (j - crop.y) * crop.width + region.x - crop.x;
+            &blurred[start..start + region.width]
+        })
+        .copied()
+        .collect()
+}
+
+/// Collects a `mask` for an image of size `width` by `height`.
+///
+/// Pixels beyond the end of a short mask are treated as `false`, although a
+/// mask of the wrong length panics in debug builds.
+pub(crate) fn collect_mask<'a>(
+    mask: impl IntoIterator<Item = &'a bool>,
+    width: usize,
+    height: usize,
+) -> Vec<bool> {
+    let mut mask = mask.into_iter().copied().collect::<Vec<_>>();
+    debug_assert!(
+        mask.len() == width * height,
+        "mask length doesn't match the {} pixels of the image",
+        width * height
+    );
+    mask.resize(width * height, false);
+
+    mask
+}
+
+/// Checks an image of `len` pixels has the dimensions `width` by `height` and
+/// that `radius` is usable
+fn check_image(len: usize, width: usize, height: usize, radius: f64) -> Result<(), BlurError> {
+    if len != width * height {
+        return Err(BlurError::DimensionMismatch {
+            expected: width * height,
+            got: len,
+        });
+    }
+    if !radius.is_finite() || radius < 0.0 {
+        return Err(BlurError::InvalidRadius);
+    }
+    Ok(())
+}
+
+/// Checks every channel of every pixel stays finite once raised to the power of
+/// `gamma`, as a single infinite or NaN value spreads across the whole kernel
+/// around it
+fn check_pixels<const N: usize>(img: &[[f64; N]], gamma: f64) -> Result<(), BlurError> {
+    match img
+        .iter()
+        .position(|pixel| pixel.iter().any(|c| !c.powf(gamma).is_finite()))
+    {
+        Some(index) => Err(BlurError::NonFiniteInput { index }),
+        None => Ok(()),
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel to produce a
+/// Bokeh lens effect.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
 /// element corresponds to a pixel. Each element of the array corresponds to R,
-/// G, B, A. Also takes a `mask` of the same length as the image where `true`'s
-/// correspond to the convolved image and `false`'s corresponsed to the
-/// original.  Also requires the `width` and `height` of the image. The image is
-/// blurred by a disc-shaped kernel with radius `radius`, built from components
-/// corresponding to `param_set`. The exposure can be modified using `gamma`,
-/// set to `1.0` for no change.
-pub fn bokeh_blur_with_mask<'a>(
+/// G, B, A. Also requires the `width` and `height` of the image. The image is
+/// blurred by a disc-shaped kernel with radius `radius` pixels, see
+/// [`crate::BlurRadius`] for converting from a Gaussian's sigma, built from
+/// components corresponding to `param_set`. The exposure can be modified using
+/// `gamma`, set to `1.0` for no change. A `radius` of `0.0` leaves the image
+/// exactly as it is. Channels which aren't finite once raised to the power of
+/// `gamma`, e.g. a negative value with a fractional gamma, are treated as
+/// `0.0`; use [`try_bokeh_blur`] to reject them instead.
+///
+/// # Panics
+///
+/// Panics if the image doesn't have `width * height` pixels, or `radius` is
+/// negative or not finite, see [`try_bokeh_blur`].
+pub fn bokeh_blur(
     img: &mut [[f64; 4]],
-    mask: impl IntoIterator<Item = &'a bool>,
     width: usize,
     height: usize,
     radius: f64,
     param_set: &KernelParamSet,
     gamma: f64,
 ) {
-    // TODO optimisation where only convolve regions not masked, have to look at
-    // places within kernel radius
-    for ((n, rgba), mask_i) in ComplexImage::from_slice(img, width, height, gamma)
+    bokeh_blur_channels(img, width, height, radius, param_set, gamma)
+}
+
+/// Checked version of [`bokeh_blur`].
+///
+/// Returns [`BlurError::DimensionMismatch`] if the image doesn't have
+/// `width * height` pixels, [`BlurError::InvalidRadius`] if `radius` is
+/// negative or not finite, and [`BlurError::NonFiniteInput`] if any channel of
+/// a pixel is infinite or NaN once raised to the power of `gamma`, e.g. a
+/// negative value with a fractional gamma, which would otherwise spread
+/// across every pixel the kernel covers. In each case the error is returned
+/// before doing any work and without modifying the image. Radii larger than
+/// the image are fine.
+pub fn try_bokeh_blur(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) -> Result<(), BlurError> {
+    try_bokeh_blur_channels(img, width, height, radius, param_set, gamma)
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel sampled
+/// `kernel_radius` pixels either side of its centre, rather than the
+/// [`recommended_kernel_radius`] of [`bokeh_blur`].
+///
+/// A larger `kernel_radius` keeps more of the tails of the kernel at the cost
+/// of a slower blur, while a smaller one truncates the disc. Use a
+/// [`crate::KernelPlan`] to reuse the kernels across several images.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_with_kernel_radius(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    kernel_radius: usize,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let plan = KernelPlan::new(param_set, radius, kernel_radius);
+    crate::plan::bokeh_blur_with_plan(img, width, height, &plan, gamma);
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, sampling the
+/// pixels beyond the edges of the image according to `edge_mode`.
+///
+/// [`EdgeMode::Zero`] is the same as [`bokeh_blur`], where the pixels beyond
+/// the edges are black and so the borders of bright images are darkened. The
+/// other modes fill them from the image itself, a constant colour or by
+/// renormalising the kernel, keeping the brightness of the borders.
+pub fn bokeh_blur_with_edge_mode(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    edge_mode: EdgeMode,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let plan = KernelPlan::for_radius(param_set, radius);
+    let mut blurred = ComplexImage::from_slice(img, width, height, gamma)
+        .with_edge_mode(edge_mode)
+        .convolve(plan.kernels());
+    fill_edges(
+        &mut blurred,
+        width,
+        height,
+        plan.kernels(),
+        edge_mode,
+        |_, c| c.powf(gamma),
+    );
+
+    for (n, rgba) in blurred.into_iter().enumerate() {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+/// Blurs an RGB image using an approximation of a disc-shaped kernel to produce
+/// a Bokeh lens effect.
+///
+/// The same as [`bokeh_blur`], but for images without an alpha channel, so
+/// only the three colour channels are convolved. Takes an exclusive reference
+/// to a slice of size 3 arrays, where each array element corresponds to a
+/// pixel. Each element of the array corresponds to R, G, B.
+pub fn bokeh_blur_rgb(
+    img: &mut [[f64; 3]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    bokeh_blur_channels(img, width, height, radius, param_set, gamma)
+}
+
+/// Blurs a single-channel image, e.g. greyscale or a depth map, using an
+/// approximation of a disc-shaped kernel to produce a Bokeh lens effect.
+///
+/// The same as [`bokeh_blur`], but takes an exclusive reference to a slice of
+/// plain values, one per pixel, rather than padding each pixel out to four
+/// channels. See [`bokeh_blur_channels`] for other numbers of channels.
+///
+/// # Panics
+///
+/// Panics as [`bokeh_blur_channels`].
+pub fn bokeh_blur_grey(
+    img: &mut [f64],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    let mut pixels = img.iter().map(|&v| [v]).collect::<Vec<_>>();
+    bokeh_blur_channels(&mut pixels, width, height, radius, param_set, gamma);
+    for (v, [blurred]) in img.iter_mut().zip(pixels) {
+        *v = blurred;
+    }
+}
+
+/// Blurs an image with `N` channels using an approximation of a disc-shaped
+/// kernel to produce a Bokeh lens effect.
+///
+/// The same as [`bokeh_blur`], which is `bokeh_blur_channels::<4>`, but for
+/// any number of channels, e.g. 2 channel or multispectral scientific images.
+/// Each channel is convolved and has `gamma` applied independently. Takes an
+/// exclusive reference to a slice of size `N` arrays, where each array element
+/// corresponds to a pixel. Channels which aren't finite with the gamma applied
+/// are treated as `0.0`, as by [`bokeh_blur`].
+///
+/// # Panics
+///
+/// Panics if the image doesn't have `width * height` pixels, or `radius` is
+/// negative or not finite, see [`try_bokeh_blur_channels`].
+pub fn bokeh_blur_channels<const N: usize>(
+    img: &mut [[f64; N]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if let Err(e) = check_image(img.len(), width, height, radius) {
+        panic!("{e}");
+    }
+    blur_channels(img, width, height, radius, param_set, gamma);
+}
+
+/// Checked version of [`bokeh_blur_channels`], see [`try_bokeh_blur`].
+pub fn try_bokeh_blur_channels<const N: usize>(
+    img: &mut [[f64; N]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) -> Result<(), BlurError> {
+    check_image(img.len(), width, height, radius)?;
+    check_pixels(img, gamma)?;
+    blur_channels(img, width, height, radius, param_set, gamma);
+    Ok(())
+}
+
+/// Blurs an image with `N` channels which has already been checked
+fn blur_channels<const N: usize>(
+    img: &mut [[f64; N]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    for (n, pixel) in ComplexImage::from_slice(img, width, height, gamma)
         .bokeh_blur(param_set, radius)
         .into_iter()
         .enumerate()
-        .zip(mask.into_iter())
     {
-        if *mask_i {
-            // Clamp any values from floating point ops
-            img[n] = rgba.map(|i| i.powf(1.0 / gamma).clamp(0.0, 255.0));
-        }
+        // Clamp any values from floating point ops
+        img[n] = pixel.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
     }
 }
 
-/// Equivalent blurring functions but operate on a
-/// [`image::DynamicImage`](image). See also the [`crate::Blur`] trait.
-#[cfg(feature = "image")]
-pub mod dynamic_image {
-    use super::ComplexImage;
-    use crate::params::KernelParamSet;
-    use image::{DynamicImage, GenericImage, Pixel};
-
-    /// Blurs an image using an approximation of a disc-shaped kernel to produce
-    /// a Bokeh lens effect.
-    ///
-    /// Takes an exclusive reference to a [`image::DynamicImage`](image). The
-    /// image is blurred by a disc-shaped kernel with radius `radius`, built
-    /// from components corresponding to `param_set`. The exposure can be
-    /// modified using `gamma`, set to `1.0` for no change.
-    pub fn bokeh_blur(img: &mut DynamicImage, radius: f64, param_set: &KernelParamSet, gamma: f64) {
-        let w = img.width();
+/// Blurs an image using an approximation of a disc-shaped kernel, with the
+/// colours premultiplied by their alpha, to produce a Bokeh lens effect.
+///
+/// The RGB channels are multiplied by the alpha before convolving and divided
+/// by the blurred alpha afterwards, so each pixel contributes colour in
+/// proportion to its opacity. Otherwise the colours of fully transparent
+/// pixels, which are usually meaningless, bleed into their neighbours. Pixels
+/// left fully transparent by the blur become transparent black.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_premultiplied(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
 
-        for (n, rgba) in ComplexImage::from_dynamic_image(img, gamma)
-            .bokeh_blur(param_set, radius)
-            .into_iter()
-            .enumerate()
-        {
-            // Safety: definitely in bounds due to iteration ranges
-            unsafe {
-                img.unsafe_put_pixel(
-                    n as u32 % w,
-                    n as u32 / w,
-                    // Clamp any values from floating point ops - ensure the cast to u8 is ok
-                    *Pixel::from_slice(&rgba.map(|i| i.powf(1.0 / gamma).clamp(0.0, 255.0) as u8)),
-                )
-            }
-        }
+    for pixel in img.iter_mut() {
+        *pixel = premultiply(*pixel, 255.0);
     }
+    bokeh_blur(img, width, height, radius, param_set, gamma);
+    for pixel in img.iter_mut() {
+        *pixel = unpremultiply(*pixel, 255.0);
+    }
+}
 
-    /// Blurs the selected parts of an image using an approximation of a
-    /// disc-shaped kernel to produce a Bokeh lens effect.
-    ///
-    /// Takes an exclusive reference to a [`image::DynamicImage`](image). Also
-    /// takes a `mask` of the same length as the image where `true`'s correspond
-    /// to the convolved image and `false`'s corresponsed to the original. The
-    /// image is blurred by a disc-shaped kernel with radius `radius`, built
-    /// from components corresponding to `param_set`. The exposure can be
-    /// modified using `gamma`, set to `1.0` for no change.
-    pub fn bokeh_blur_with_mask<'a>(
-        img: &mut DynamicImage,
-        mask: impl IntoIterator<Item = &'a bool>,
-        radius: f64,
-        param_set: &KernelParamSet,
-        gamma: f64,
-    ) {
-        // TODO optimisation where only convolve regions not masked, have to look at
-        // places within kernel radius
-        let w = img.width();
+/// Multiplies the RGB channels of a pixel by its alpha, where `max_value` is
+/// fully opaque
+pub(crate) fn premultiply([r, g, b, a]: [f64; 4], max_value: f64) -> [f64; 4] {
+    let alpha = a / max_value;
+    [r * alpha, g * alpha, b * alpha, a]
+}
 
-        for ((n, rgba), mask_i) in ComplexImage::from_dynamic_image(img, gamma)
-            .bokeh_blur(param_set, radius)
-            .into_iter()
-            .enumerate()
-            .zip(mask.into_iter())
-        {
-            if *mask_i {
-                // Safety: definitely in bounds due to iteration ranges
-                unsafe {
-                    img.unsafe_put_pixel(
-                        n as u32 % w,
-                        n as u32 / w,
-                        // Clamp any values from floating point ops - ensure the cast to u8 is ok
-                        *Pixel::from_slice(
-                            &rgba.map(|i| i.powf(1.0 / gamma).clamp(0.0, 255.0) as u8),
-                        ),
-                    )
-                }
-            }
+/// Inverse of [`premultiply`], giving transparent black where the alpha is
+/// zero
+pub(crate) fn unpremultiply([r, g, b, a]: [f64; 4], max_value: f64) -> [f64; 4] {
+    if a <= 0.0 {
+        return [0.0; 4];
+    }
+    let alpha = a / max_value;
+    let unmultiply = |c: f64| (c / alpha).clamp(0.0, max_value);
+    [unmultiply(r), unmultiply(g), unmultiply(b), a]
+}
+
+/// Blurs an image whose rows are `stride` pixels apart using an approximation
+/// of a disc-shaped kernel to produce a Bokeh lens effect.
+///
+/// The image is the first `width` pixels of each of `height` rows, with row
+/// `j` starting at pixel `j * stride`, e.g. a view into a larger frame or a
+/// buffer with rows padded for alignment. Only those pixels are read and
+/// written, so the padding, or the rest of the frame, is left untouched and
+/// doesn't bleed into the blur. A `stride` of `width` is the same as
+/// [`bokeh_blur`].
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. The image is blurred by a disc-shaped
+/// kernel with radius `radius`, built from components corresponding to
+/// `param_set`. The exposure can be modified using `gamma`, set to `1.0` for
+/// no change.
+///
+/// # Panics
+///
+/// Panics if `stride` is less than `width` or the slice is too short to hold
+/// `height` rows.
+pub fn bokeh_blur_with_stride(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    stride: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    assert!(
+        stride >= width,
+        "stride {stride} is less than the width {width}"
+    );
+    let region = Rect::new(0, 0, width, height);
+    if height > 0 {
+        let len = (height - 1) * stride + width;
+        assert!(
+            img.len() >= len,
+            "image has {} pixels but its rows need {len}",
+            img.len()
+        );
+    }
+    if is_identity(radius) {
+        return;
+    }
+
+    for (n, rgba) in ComplexImage::from_region(img, stride, region, gamma)
+        .bokeh_blur(param_set, radius)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[region.pixel_index(n, stride)] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+/// Blurs an image with `f32` channels using an approximation of a disc-shaped
+/// kernel to produce a Bokeh lens effect.
+///
+/// The same as [`bokeh_blur`], but the convolution is carried out in `f32`
+/// rather than `f64`, which halves the memory used and is faster on large
+/// images. The loss of precision is far below what 8-bit output can show.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_f32(
+    img: &mut [[f32; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let inverse_gamma = (1.0 / gamma) as f32;
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .bokeh_blur(param_set, radius)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(inverse_gamma).min(255.0));
+    }
+}
+
+/// Blurs an image stored as packed 8-bit RGBA using an approximation of a
+/// disc-shaped kernel to produce a Bokeh lens effect.
+///
+/// Takes an exclusive reference to a buffer of `width * height * 4` bytes,
+/// four per pixel in the order R, G, B, A and the pixels row by row, e.g. the
+/// data of an HTML canvas. The convolution is carried out in `f64`, as
+/// [`bokeh_blur`], and the result written back in place. Runs on the current
+/// thread without the `rayon` feature, e.g. on WebAssembly.
+///
+/// The image is blurred by a disc-shaped kernel with radius `radius`, built
+/// from components corresponding to `param_set`. The exposure can be modified
+/// using `gamma`, set to `1.0` for no change.
+///
+/// # Panics
+///
+/// Panics if the buffer doesn't hold `width * height` whole pixels or `radius`
+/// is negative or not finite, see [`try_bokeh_blur`].
+pub fn bokeh_blur_rgba_u8(
+    buf: &mut [u8],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    assert_eq!(buf.len() % 4, 0, "buffer doesn't hold whole RGBA pixels");
+
+    let mut pixels = buf
+        .chunks_exact(4)
+        .map(|p| [p[0], p[1], p[2], p[3]].map(f64::from))
+        .collect::<Vec<_>>();
+    bokeh_blur(&mut pixels, width, height, radius, param_set, gamma);
+
+    for (bytes, rgba) in buf.chunks_exact_mut(4).zip(pixels) {
+        for (b, c) in bytes.iter_mut().zip(rgba) {
+            *b = c.round() as u8;
+        }
+    }
+}
+
+/// Blurs an image with channels in the range `0.0..=max_value` using an
+/// approximation of a disc-shaped kernel to produce a Bokeh lens effect.
+///
+/// The same as [`bokeh_blur`], but the result is clamped to
+/// `0.0..=max_value` rather than `0.0..=255.0`, e.g. a `max_value` of
+/// `65535.0` keeps the full range of 16-bit images.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_with_max_value(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    max_value: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .bokeh_blur(param_set, radius)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(max_value));
+    }
+}
+
+/// Convolves an image with an approximation of a disc-shaped kernel, returning
+/// the raw result without inverting the gamma or clamping it.
+///
+/// [`bokeh_blur`] inverts `gamma` and clamps the result to `0.0..=255.0`,
+/// which crushes linear-light or HDR pixels outside that range. Here the
+/// blurred pixels are returned as they come out of the convolution, still
+/// raised to the power of `gamma`, including any small negative values from
+/// the ringing of the kernel, so they can be tone mapped or composited before
+/// inverting the gamma. With a `gamma` of `1.0` the result is simply the image
+/// convolved with the kernel.
+///
+/// Takes a reference to a slice of size 4 arrays, where each array element
+/// corresponds to a pixel. Also requires the `width` and `height` of the
+/// image. The image is blurred by a disc-shaped kernel with radius `radius`,
+/// built from components corresponding to `param_set`.
+pub fn bokeh_blur_raw(
+    img: &[[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) -> Vec<[f64; 4]> {
+    if is_identity(radius) {
+        return img.iter().map(|p| p.map(|c| c.powf(gamma))).collect();
+    }
+
+    ComplexImage::from_slice(img, width, height, gamma).bokeh_blur(param_set, radius)
+}
+
+/// Blurs an image `passes` times over using an approximation of a disc-shaped
+/// kernel, for a stronger effect than one pass of the same radius.
+///
+/// Each pass convolves the result of the one before, so the blur spreads
+/// further with every pass, at the cost of one pass of `radius` each rather
+/// than the cost of a much wider kernel. The result is smoother than a single
+/// disc, as repeated discs tend towards a Gaussian, and stepping `passes` gives
+/// a way of animating the strength of the blur. The gamma is applied once
+/// before the first pass and inverted once after the last, with the passes in
+/// between kept linear and unclamped, so rounding doesn't build up. A `passes`
+/// of `0` leaves the image unchanged and `1` is the same as [`bokeh_blur`].
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_iterated(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    passes: usize,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    if passes == 0 {
+        return;
+    }
+
+    let plan = KernelPlan::for_radius(param_set, radius);
+    let mut linear = ComplexImage::from_slice(img, width, height, gamma).convolve(plan.kernels());
+    for _ in 1..passes {
+        linear = ComplexImage::from_slice(&linear, width, height, 1.0).convolve(plan.kernels());
+    }
+
+    for (n, rgba) in linear.into_iter().enumerate() {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+/// Blurs an image with channels in the range `0.0..=1.0` using an
+/// approximation of a disc-shaped kernel to produce a Bokeh lens effect.
+///
+/// The same as [`bokeh_blur`], but the result is clamped to `0.0..=1.0` rather
+/// than `0.0..=255.0`, so normalised pixels don't have to be scaled up and back
+/// down. The gamma round-trip is independent of the scale of the pixels, so
+/// this gives the same result as scaling to `0.0..=255.0`, blurring and
+/// scaling back.
+pub fn bokeh_blur_unit(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    bokeh_blur_with_max_value(img, width, height, radius, param_set, gamma, 1.0)
+}
+
+/// Blurs an image of scene-linear light, e.g. from an HDR capture, using an
+/// approximation of a disc-shaped kernel to produce a Bokeh lens effect.
+///
+/// The pixels are already linear, so unlike [`bokeh_blur`] there is no gamma
+/// round-trip, and they aren't limited to `0.0..=255.0`: bright highlights
+/// keep their full intensity and spread into discs in proportion to it. Only
+/// negative values, from the ringing of the kernel, are clamped to `0.0`.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`.
+pub fn bokeh_blur_linear(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, 1.0)
+        .bokeh_blur(param_set, radius)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0));
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, with the
+/// gamma round-trip and clamping left out when `gamma` is `None`.
+///
+/// With `Some(gamma)` this is the same as [`bokeh_blur`]. With `None` the
+/// pixels are taken to be linear already, e.g. inside a renderer working in
+/// linear light, so they are convolved as they are and written back without
+/// any transform or clamp, keeping values above `255.0` and the small negative
+/// values from the ringing of the kernel. Unlike [`bokeh_blur_linear`] not
+/// even the negative values are clamped, so the result can be blurred again or
+/// composited without losing anything.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`.
+pub fn bokeh_blur_with_gamma(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: Option<f64>,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let Some(gamma) = gamma else {
+        let blurred = bokeh_blur_raw(img, width, height, radius, param_set, 1.0);
+        return img.copy_from_slice(&blurred);
+    };
+    bokeh_blur(img, width, height, radius, param_set, gamma)
+}
+
+/// Blurs the selected parts of an image using an approximation of a disc-shaped
+/// kernel to produce a Bokeh lens effect.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Each element of the array corresponds to R,
+/// G, B, A. Also takes a `mask` of the same length as the image where `true`'s
+/// correspond to the convolved image and `false`'s corresponsed to the
+/// original.  Also requires the `width` and `height` of the image. The image is
+/// blurred by a disc-shaped kernel with radius `radius`, built from components
+/// corresponding to `param_set`. The exposure can be modified using `gamma`,
+/// set to `1.0` for no change.
+///
+/// Only the rectangles around the `true` pixels and the pixels within the
+/// kernel radius of them are convolved, with parts of the mask further apart
+/// than the kernel reaches convolved separately, see [`crate::mask_regions`].
+pub fn bokeh_blur_with_mask<'a>(
+    img: &mut [[f64; 4]],
+    mask: impl IntoIterator<Item = &'a bool>,
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let mask = collect_mask(mask, width, height);
+    blur_where(
+        img,
+        width,
+        height,
+        |i| mask[i],
+        radius,
+        param_set,
+        gamma,
+        255.0,
+    );
+}
+
+/// Blurs the selected parts of an image using an approximation of a disc-shaped
+/// kernel, optionally filling the rest with a solid colour.
+///
+/// The same as [`bokeh_blur_with_mask`] when `fill` is `None`. Otherwise the
+/// pixels where the mask is `false` are set to `fill` rather than keeping their
+/// original values, e.g. to flatten the background behind a blurred subject for
+/// a redacted preview. The pixels where the mask is `true` are blurred from the
+/// original image, so still take in the original pixels around them rather
+/// than the fill.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+#[allow(clippy::too_many_arguments)]
+pub fn bokeh_blur_with_mask_fill<'a>(
+    img: &mut [[f64; 4]],
+    mask: impl IntoIterator<Item = &'a bool>,
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    fill: Option<[f64; 4]>,
+) {
+    let mask = collect_mask(mask, width, height);
+    if !is_identity(radius) {
+        blur_where(
+            img,
+            width,
+            height,
+            |i| mask[i],
+            radius,
+            param_set,
+            gamma,
+            255.0,
+        );
+    }
+
+    if let Some(fill) = fill {
+        for (pixel, _) in img.iter_mut().zip(&mask).filter(|(_, &m)| !m) {
+            *pixel = fill;
+        }
+    }
+}
+
+/// Blurs the selected parts of an image using an approximation of a disc-shaped
+/// kernel, reading the selection from a mask packed into bits.
+///
+/// The same as [`bokeh_blur_with_mask`], but the `mask` holds one bit per
+/// pixel rather than a [`bool`], using an eighth of the memory. Pixel `i` is
+/// selected by bit `i % 64` of `mask[i / 64]`, counting from the least
+/// significant bit, where a set bit corresponds to the convolved image and a
+/// clear bit to the original. Bits beyond the end of the image are ignored.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+///
+/// # Panics
+///
+/// Panics if `mask` has fewer than one bit per pixel.
+pub fn bokeh_blur_with_bitmask(
+    img: &mut [[f64; 4]],
+    mask: &[u64],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    assert!(
+        mask.len() * 64 >= width * height,
+        "mask has {} bits but the image has {} pixels",
+        mask.len() * 64,
+        width * height
+    );
+    if is_identity(radius) {
+        return;
+    }
+    let is_set = |i: usize| (mask[i / 64] >> (i % 64)) & 1 == 1;
+    blur_where(img, width, height, is_set, radius, param_set, gamma, 255.0);
+}
+
+/// Blurs the transparent parts of an image using an approximation of a
+/// disc-shaped kernel, keeping the opaque parts as they are.
+///
+/// The same as [`bokeh_blur_with_mask`], but the mask is read from the alpha
+/// channel of the image itself: pixels with an alpha of at least `threshold`
+/// keep their original values and the rest are replaced by the convolved
+/// image. The alpha is read before convolving, as it is blurred along with the
+/// colours. To blur the opaque parts instead, invert the alpha first or use
+/// [`bokeh_blur_with_mask`].
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_by_alpha(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    threshold: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    debug_assert_eq!(img.len(), width * height);
+    let mask = img.iter().map(|p| p[3] < threshold).collect::<Vec<_>>();
+    blur_where(
+        img,
+        width,
+        height,
+        |i| mask[i],
+        radius,
+        param_set,
+        gamma,
+        255.0,
+    );
+}
+
+/// Rectangles holding every pixel of an image of size `width` by `height` for
+/// which `is_set` returns `true`, split where the kernel of a blur of radius
+/// `radius` around the pixels of one wouldn't reach the others, see
+/// [`crate::mask_regions`]
+pub(crate) fn blur_regions(
+    width: usize,
+    height: usize,
+    radius: f64,
+    is_set: impl Fn(usize) -> bool,
+) -> Vec<Rect> {
+    regions_where(width, height, 2 * recommended_kernel_radius(radius), is_set)
+}
+
+/// Replaces the pixels of an image for which `is_blurred` returns `true` with
+/// their blurred values, convolving only the rectangles around them and the
+/// pixels within the kernel radius of those, see [`blur_regions`].
+#[allow(clippy::too_many_arguments)]
+fn blur_where(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    is_blurred: impl Fn(usize) -> bool,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    max_value: f64,
+) {
+    let regions = blur_regions(width, height, radius, &is_blurred);
+    if regions.is_empty() {
+        return;
+    }
+
+    // Every region is convolved before any is written, as the pixels around
+    // one can be part of another
+    let kernels = weight_kernels(param_set, kernel_gaussian_components(param_set, radius));
+    let blurred = regions
+        .iter()
+        .map(|&region| blur_region_with_kernels(img, width, height, region, &kernels, gamma))
+        .collect::<Vec<_>>();
+
+    for (region, blurred) in regions.into_iter().zip(blurred) {
+        for (n, rgba) in blurred.into_iter().enumerate() {
+            let i = region.pixel_index(n, width);
+            if is_blurred(i) {
+                // Clamp any values from floating point ops
+                img[i] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(max_value));
+            }
+        }
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, blending
+/// each pixel with its blurred value by a per-pixel weight.
+///
+/// Takes `weights` of the same length as the image, where each weight between
+/// `0.0` and `1.0` mixes the original and blurred pixel as
+/// `weight * blurred + (1 - weight) * original`. Unlike the hard edges of
+/// [`bokeh_blur_with_mask`], a soft matte gives a feathered boundary between
+/// the blurred and unblurred parts. Weights outside this range are clamped.
+/// The blend is of the final values, after the gamma is inverted, so the
+/// boundary isn't darkened. Only the parts of the image with non-zero weights
+/// and the pixels within the kernel radius of them are convolved.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_with_weights(
+    img: &mut [[f64; 4]],
+    weights: &[f64],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    debug_assert_eq!(
+        weights.len(),
+        width * height,
+        "weights length doesn't match the {} pixels of the image",
+        width * height
+    );
+    let weights = weights
+        .iter()
+        .map(|w| w.clamp(0.0, 1.0))
+        .collect::<Vec<_>>();
+    let mask = weights.iter().map(|&w| w > 0.0).collect::<Vec<_>>();
+    let Some(region) = mask_bounds(&mask, width, height) else {
+        return;
+    };
+
+    for (n, rgba) in blur_region(img, width, height, region, radius, param_set, gamma)
+        .into_iter()
+        .enumerate()
+    {
+        let i = region.pixel_index(n, width);
+        if !mask[i] {
+            continue;
+        }
+
+        // Clamp any values from floating point ops
+        let blurred = rgba.map(|c| c.max(0.0).powf(1.0 / gamma).min(255.0));
+        blend(&mut img[i], blurred, weights[i]);
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, blending
+/// each pixel with its blurred value by a soft mask.
+///
+/// The same as [`bokeh_blur_with_weights`], but the `mask` can be any iterable
+/// of `f32` or `f64` weights, as [`bokeh_blur_with_mask`] takes any iterable of
+/// [`bool`]s, e.g. a matte straight from a segmentation model. Each weight
+/// between `0.0` and `1.0` mixes the original and blurred pixel as
+/// `weight * blurred + (1 - weight) * original`. Pixels beyond the end of a
+/// short mask are treated as `0.0`, although a mask of the wrong length panics
+/// in debug builds.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_with_soft_mask<'a, W: Into<f64> + Copy + 'a>(
+    img: &mut [[f64; 4]],
+    mask: impl IntoIterator<Item = &'a W>,
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let mut weights = mask.into_iter().map(|&w| w.into()).collect::<Vec<_>>();
+    debug_assert!(
+        weights.len() == width * height,
+        "mask length doesn't match the {} pixels of the image",
+        width * height
+    );
+    weights.resize(width * height, 0.0);
+    bokeh_blur_with_weights(img, &weights, width, height, radius, param_set, gamma)
+}
+
+/// Checked version of [`bokeh_blur_with_mask`].
+///
+/// Returns [`BlurError::MaskLengthMismatch`] without modifying the image if
+/// `mask` doesn't have exactly one element per pixel, rather than only
+/// blurring the pixels the mask covers. The image, radius and pixels are
+/// checked as by [`try_bokeh_blur`].
+pub fn try_bokeh_blur_with_mask<'a>(
+    img: &mut [[f64; 4]],
+    mask: impl IntoIterator<Item = &'a bool>,
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) -> Result<(), BlurError> {
+    check_image(img.len(), width, height, radius)?;
+    let mask = mask.into_iter().collect::<Vec<_>>();
+    if mask.len() != width * height {
+        return Err(BlurError::MaskLengthMismatch {
+            expected: width * height,
+            got: mask.len(),
+        });
+    }
+    check_pixels(img, gamma)?;
+    if is_identity(radius) {
+        return Ok(());
+    }
+
+    bokeh_blur_with_mask(img, mask, width, height, radius, param_set, gamma);
+    Ok(())
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, reporting its
+/// progress to and allowing it to be cancelled by `control`.
+///
+/// Returns [`BlurError::Cancelled`] without modifying the image if the blur is
+/// cancelled through the [`crate::BlurHandle`] of `control`. Progress is
+/// reported once per kernel component. The image, radius and pixels are checked
+/// as by [`try_bokeh_blur`]. Otherwise the same as [`bokeh_blur`].
+pub fn try_bokeh_blur_controlled(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    control: &BlurControl,
+) -> Result<(), BlurError> {
+    check_image(img.len(), width, height, radius)?;
+    check_pixels(img, gamma)?;
+    if is_identity(radius) {
+        return Ok(());
+    }
+
+    let kernels = weight_kernels(param_set, kernel_gaussian_components(param_set, radius));
+    let blurred = ComplexImage::from_slice(img, width, height, gamma)
+        .convolve_controlled(&kernels, control)
+        .ok_or(BlurError::Cancelled)?;
+
+    for (n, rgba) in blurred.into_iter().enumerate() {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+    Ok(())
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, stopping
+/// early once `should_cancel` returns `true`.
+///
+/// `should_cancel` is polled before and after each kernel component is
+/// convolved, possibly from several threads at once, so it must be cheap, e.g.
+/// loading an `AtomicBool` shared with the thread which wants to cancel the
+/// blur. Returns [`BlurError::Cancelled`] without modifying the image if the
+/// blur is cancelled, as the image is only written once every component has
+/// been convolved. Checks the image and its pixels as [`try_bokeh_blur`]. Use
+/// [`try_bokeh_blur_controlled`] to also report progress.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn try_bokeh_blur_cancellable(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    should_cancel: impl Fn() -> bool + Sync,
+) -> Result<(), BlurError> {
+    check_image(img.len(), width, height, radius)?;
+    check_pixels(img, gamma)?;
+    if is_identity(radius) {
+        return Ok(());
+    }
+
+    let blurred = ComplexImage::from_slice(img, width, height, gamma)
+        .convolve_cancellable(
+            KernelPlan::for_radius(param_set, radius).kernels(),
+            &should_cancel,
+            &|| {},
+        )
+        .ok_or(BlurError::Cancelled)?;
+
+    for (n, rgba) in blurred.into_iter().enumerate() {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+    Ok(())
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel with its
+/// negligible taps trimmed, trading a little accuracy for speed with large
+/// kernels.
+///
+/// Taps with a magnitude below `threshold` times the largest tap are trimmed
+/// from the tails of the kernel, see [`kernel_gaussian_components_pruned`].
+/// Otherwise the same as [`bokeh_blur`].
+pub fn bokeh_blur_pruned(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    threshold: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let kernels = weight_kernels(
+        param_set,
+        kernel_gaussian_components_pruned(param_set, radius, threshold),
+    );
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .convolve(&kernels)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel sized to
+/// capture a fraction `accuracy` of the kernel's energy.
+///
+/// The kernel radius is chosen by [`kernel_support_for_energy`], so e.g. an
+/// `accuracy` of `0.99` gives a more faithful blur than `0.9`, at the cost of a
+/// larger kernel. Otherwise the same as [`bokeh_blur`].
+pub fn bokeh_blur_budget(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    accuracy: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let kernel_radius = kernel_support_for_energy(param_set, radius, accuracy);
+    let kernels = weight_kernels(
+        param_set,
+        kernel_gaussian_components_sized(param_set, radius, kernel_radius),
+    );
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .convolve(&kernels)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel normalised so
+/// that its brightness is scaled by `target`.
+///
+/// A `target` of `1.0` is the same as [`bokeh_blur`], while larger values
+/// brighten and smaller values darken the image, see
+/// [`kernel_gaussian_components_normalised_to`]. The scaling applies before
+/// `gamma` is inverted.
+pub fn bokeh_blur_normalised_to(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    target: f64,
+) {
+    if is_identity(radius) && target == 1.0 {
+        return;
+    }
+
+    let kernels = weight_kernels(
+        param_set,
+        kernel_gaussian_components_normalised_to(param_set, radius, target),
+    );
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .convolve(&kernels)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+/// Blurs a batch of images using an approximation of a disc-shaped kernel to
+/// produce a Bokeh lens effect.
+///
+/// The images are blurred in parallel, with the `rayon` feature, but each
+/// image is blurred on a single thread. Prefer this to blurring each image in
+/// turn for many small images, where the overhead of spreading each image's
+/// kernel components across threads outweighs the work; for a few large
+/// images, blurring them in turn keeps more threads busy. The kernel is only
+/// built once for the batch.
+///
+/// Each image is blurred by a disc-shaped kernel with radius `radius`, built
+/// from components corresponding to `param_set`. The exposure can be modified
+/// using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_batch(images: &mut [Image], radius: f64, param_set: &KernelParamSet, gamma: f64) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let kernels = weight_kernels(param_set, kernel_gaussian_components(param_set, radius));
+
+    let blur = |img: &mut Image| {
+        for (n, rgba) in ComplexImage::from_slice(&img.pixels, img.w, img.h, gamma)
+            .convolve_sequential(&kernels)
+            .into_iter()
+            .enumerate()
+        {
+            // Clamp any values from floating point ops
+            img.pixels[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+        }
+    };
+
+    #[cfg(feature = "rayon")]
+    images.par_iter_mut().for_each(blur);
+    #[cfg(not(feature = "rayon"))]
+    images.iter_mut().for_each(blur);
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, mapping the
+/// blurred colours back into range with `tone_map`.
+///
+/// [`ToneMap::Clamp`] is the same as [`bokeh_blur`], while
+/// [`ToneMap::Filmic`] rolls off the highlights of the Bokeh rather than
+/// clipping them. The curve is applied to the linear result before `gamma` is
+/// inverted.
+pub fn bokeh_blur_tone_mapped(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    tone_map: ToneMap,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    // Linear value of the brightest input
+    let peak = 255.0_f64.powf(gamma);
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .bokeh_blur(param_set, radius)
+        .into_iter()
+        .enumerate()
+    {
+        img[n] = [
+            255.0 * tone_map.apply(rgba[0] / peak).powf(1.0 / gamma),
+            255.0 * tone_map.apply(rgba[1] / peak).powf(1.0 / gamma),
+            255.0 * tone_map.apply(rgba[2] / peak).powf(1.0 / gamma),
+            // Clamp any values from floating point ops
+            rgba[3].max(0.0).powf(1.0 / gamma).min(255.0),
+        ];
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, boosting the
+/// highlights so bright points bloom into pronounced discs.
+///
+/// The colour channels above `threshold`, which should be above `0.0`, are
+/// raised to a further power of `boost` before convolving, on top of `gamma`,
+/// and brought back down afterwards. The curve is continuous at the
+/// threshold, so highlights stand out more the brighter they are, while
+/// channels at or below the threshold are transformed exactly as by
+/// [`bokeh_blur`]: an image without highlights is blurred the same. A `boost`
+/// of `1.0` is the same as [`bokeh_blur`]. The alpha is only transformed by
+/// `gamma`.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+#[allow(clippy::too_many_arguments)]
+pub fn bokeh_blur_with_bloom(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    threshold: f64,
+    boost: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    // Linear value of the threshold, where the curve steepens
+    let knee = threshold.powf(gamma);
+    let expand = |c: f64| {
+        if c <= threshold {
+            c.powf(gamma)
+        } else {
+            knee * (c / threshold).powf(gamma * boost)
+        }
+    };
+    let compress = |v: f64| {
+        if v <= knee {
+            v.max(0.0).powf(1.0 / gamma)
+        } else {
+            threshold * (v / knee).powf(1.0 / (gamma * boost))
+        }
+    };
+
+    let linear = img
+        .iter()
+        .map(|&[r, g, b, a]| [expand(r), expand(g), expand(b), a.powf(gamma)])
+        .collect::<Vec<_>>();
+    for (n, [r, g, b, a]) in ComplexImage::from_slice(&linear, width, height, 1.0)
+        .bokeh_blur(param_set, radius)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = [
+            compress(r),
+            compress(g),
+            compress(b),
+            a.max(0.0).powf(1.0 / gamma),
+        ]
+        .map(|i| i.min(255.0));
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel with a
+/// sub-pixel sized radius.
+///
+/// Otherwise the same as [`bokeh_blur`], but the spread of the blur grows
+/// smoothly with `radius` rather than stepping each time it passes an integer,
+/// see [`kernel_gaussian_components_subpixel`]. Useful when animating the
+/// radius.
+pub fn bokeh_blur_subpixel(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let kernels = weight_kernels(
+        param_set,
+        kernel_gaussian_components_subpixel(param_set, radius),
+    );
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .convolve(&kernels)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+/// Blurs an image using an elliptical Bokeh-like kernel, with independent radii
+/// `radius_x` across and `radius_y` down the image.
+///
+/// The horizontal pass uses kernels of radius `radius_x` and the vertical pass
+/// kernels of radius `radius_y`. Both sets of kernels are normalised together,
+/// as the brightness depends on the product of the two passes, so the pixel
+/// remains the same brightness. Stretching the disc along one axis gives the
+/// look of anamorphic lenses or motion. Equal radii are the same as
+/// [`bokeh_blur`].
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The kernel is built from components corresponding to
+/// `param_set`. The exposure can be modified using `gamma`, set to `1.0` for no
+/// change.
+pub fn bokeh_blur_anisotropic(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius_x: f64,
+    radius_y: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius_x) && is_identity(radius_y) {
+        return;
+    }
+
+    let (horizontal, vertical) =
+        kernel_gaussian_components_anisotropic(param_set, radius_x, radius_y);
+    let kernels = weight_kernel_pairs(param_set, horizontal, vertical);
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .convolve(&kernels)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel of a slightly
+/// different size for each colour, imitating the chromatic aberration of a
+/// real lens.
+///
+/// Lenses focus each wavelength of light a little differently, so the
+/// out-of-focus highlights of a fast lens have coloured fringes. Here the R, G
+/// and B channels are each blurred by a disc with radius `base_radius` scaled
+/// by their element of `channel_scale`, e.g. `[1.05, 1.0, 0.95]` for red fringes
+/// on the outside of each disc and blue on the inside, and the alpha channel by
+/// a disc of radius `base_radius`. The channels are convolved independently,
+/// so this costs about the same as [`bokeh_blur`], which it matches when every
+/// scale is `1.0`.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The kernels are built from components corresponding to
+/// `param_set`. The exposure can be modified using `gamma`, set to `1.0` for no
+/// change.
+pub fn bokeh_blur_chromatic(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    base_radius: f64,
+    channel_scale: [f64; 3],
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    let [r, g, b] = channel_scale.map(|scale| base_radius * scale);
+    // Channels with a radius of `0.0` are left as they are
+    let blurred = [r, g, b, base_radius]
+        .into_iter()
+        .enumerate()
+        .map(|(c, radius)| {
+            (!is_identity(radius)).then(|| {
+                let channel = img.iter().map(|p| [p[c]]).collect::<Vec<_>>();
+                ComplexImage::from_slice(&channel, width, height, gamma)
+                    .bokeh_blur(param_set, radius)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for (n, pixel) in img.iter_mut().enumerate() {
+        for (c, channel) in blurred.iter().enumerate() {
+            if let Some(channel) = channel {
+                // Clamp any values from floating point ops
+                pixel[c] = channel[n][0].max(0.0).powf(1.0 / gamma).min(255.0);
+            }
+        }
+    }
+}
+
+/// Blurs an image and then flattens it into `block` by `block` squares, giving
+/// a chunky, pixelated Bokeh effect.
+///
+/// The image is blurred first, exactly as [`bokeh_blur`], and each square is
+/// then replaced by the average of its blurred pixels. Blurring before
+/// blocking keeps the disc shapes of the highlights visible through the
+/// mosaic; blocking first would turn every highlight into a square before it
+/// reached the kernel. Squares along the right and bottom edges are cropped to
+/// the image. A `block` of `1` is the same as [`bokeh_blur`].
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_mosaic(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    block: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    bokeh_blur(img, width, height, radius, param_set, gamma);
+
+    if block <= 1 {
+        return;
+    }
+
+    for y0 in (0..height).step_by(block) {
+        for x0 in (0..width).step_by(block) {
+            let ys = y0..(y0 + block).min(height);
+            let xs = x0..(x0 + block).min(width);
+            let count = (ys.len() * xs.len()) as f64;
+
+            let mut sum = [0.0; 4];
+            for j in ys.clone() {
+                for p in &img[j * width + xs.start..j * width + xs.end] {
+                    for (s, c) in sum.iter_mut().zip(p) {
+                        *s += c;
+                    }
+                }
+            }
+
+            let average = sum.map(|s| s / count);
+            for j in ys {
+                img[j * width + xs.start..j * width + xs.end].fill(average);
+            }
+        }
+    }
+}
+
+/// Blurs a rectangular region of an image using an approximation of a
+/// disc-shaped kernel, leaving the pixels outside it unchanged.
+///
+/// Only `region` and the pixels within the kernel radius around it are
+/// convolved, so the cost depends on the size of the region rather than of the
+/// image, e.g. for previews or edits of part of a large image. The pixels of
+/// the region are the same as if the whole image was blurred by
+/// [`bokeh_blur`]. The part of `region` beyond the edges of the image is
+/// ignored. See [`bokeh_blur_region_feathered`] to blend the blur into the
+/// surrounding pixels instead of stopping at the region's boundary.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_region(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    region: Rect,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    debug_assert_eq!(img.len(), width * height);
+    let region = region.expand(0, width, height);
+    if is_identity(radius) || region.width == 0 || region.height == 0 {
+        return;
+    }
+
+    for (n, rgba) in blur_region(img, width, height, region, radius, param_set, gamma)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[region.pixel_index(n, width)] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+/// Blurs a rectangular region of an image, feathering the blur into the
+/// surrounding pixels so there is no hard edge at the region's boundary.
+///
+/// Pixels inside `region` are blurred as by [`bokeh_blur`]. Outside it, each
+/// pixel is blended with its blurred value using the weight
+/// `1 - d / (feather + 1)`, where `d` is its Euclidean distance in pixels from
+/// the nearest pixel of `region`. The weight falls linearly to `0`, so pixels
+/// `feather + 1` or more from the region are left unchanged. Only the region,
+/// the feathered border and the pixels within the kernel radius of them are
+/// convolved.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+#[allow(clippy::too_many_arguments)]
+pub fn bokeh_blur_region_feathered(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    region: Rect,
+    feather: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let region = region.expand(0, width, height);
+    if region.width == 0 || region.height == 0 {
+        return;
+    }
+    let outer = region.expand(feather, width, height);
+
+    for (n, rgba) in blur_region(img, width, height, outer, radius, param_set, gamma)
+        .into_iter()
+        .enumerate()
+    {
+        let (x, y) = (outer.x + n % outer.width, outer.y + n / outer.width);
+        let weight = 1.0 - region.distance(x, y) / (feather + 1) as f64;
+        if weight <= 0.0 {
+            continue;
+        }
+
+        // Clamp any values from floating point ops
+        let blurred = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+        blend(&mut img[y * width + x], blurred, weight);
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, blending each
+/// pixel with its blurred value by the pixel's own alpha.
+///
+/// Each pixel's alpha, from `0.0` to `255.0`, is the weight of the blurred
+/// value, from `0.0` to `1.0`: opaque pixels are blurred as by [`bokeh_blur`],
+/// fully transparent pixels are left unchanged and semi-transparent pixels get
+/// a partial blur, which suits feathered sprites. The weights are read from
+/// the image before it is blurred, and every channel, alpha included, is
+/// blended by the same weight.
+///
+/// The alpha is blurred like any other channel, so this works the same on
+/// straight and premultiplied colours: as colour and alpha are mixed by the
+/// same weight, premultiplied input gives a premultiplied result.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_alpha_weighted(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .bokeh_blur(param_set, radius)
+        .into_iter()
+        .enumerate()
+    {
+        let weight = (img[n][3] / 255.0).clamp(0.0, 1.0);
+        // Clamp any values from floating point ops
+        let blurred = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+        blend(&mut img[n], blurred, weight);
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, mixing the
+/// result with the original image by `strength` for a subtler effect.
+///
+/// Each pixel becomes `strength * blurred + (1 - strength) * original`, the
+/// global analogue of the per-pixel weights of [`bokeh_blur_with_weights`].
+/// Dialling the strength down is much cheaper than trying radii until the
+/// blur is weak enough, as the blur is only computed once. The mix is of the
+/// final values, after the gamma is inverted and clamped, so a `strength` of
+/// `1.0` gives exactly the result of [`bokeh_blur`] and `0.0` leaves the image
+/// unchanged without convolving it. `strength` is clamped to `0.0..=1.0`.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_mix(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    strength: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let strength = strength.clamp(0.0, 1.0);
+    if strength == 0.0 {
+        return;
+    }
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .bokeh_blur(param_set, radius)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        let blurred = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+        blend(&mut img[n], blurred, strength);
+    }
+}
+
+/// Mixes `blurred` into `pixel`, where a `weight` of `1.0` replaces the pixel
+/// and `0.0` leaves it unchanged
+fn blend(pixel: &mut [f64; 4], blurred: [f64; 4], weight: f64) {
+    for (p, b) in pixel.iter_mut().zip(blurred) {
+        *p = weight * b + (1.0 - weight) * *p;
+    }
+}
+
+/// Number of angular sectors the image is split into by
+/// [`bokeh_blur_swirl`]
+const SWIRL_SECTORS: usize = 16;
+/// Number of rings around the centre the image is split into by
+/// [`bokeh_blur_swirl`]
+const SWIRL_RINGS: usize = 4;
+
+/// Blurs an image using an approximation of a disc-shaped kernel stretched
+/// around the centre of the image, giving a swirly Bokeh like that of a
+/// Petzval lens.
+///
+/// At a distance `d` from the centre, the kernel is stretched along the
+/// tangent of the circle around the centre by `1 + swirl * d / d_max`, where
+/// `d_max` is the distance to the corners, so the swirl is strongest at the
+/// edges. A `swirl` of `0.0` is the same as [`bokeh_blur`].
+///
+/// This is a coarse approximation. Rotating the kernel for every pixel would
+/// break the separability of the kernel, so instead the image is split into
+/// 16 sectors around the centre and 4 rings out from it. Each part is blurred
+/// with a single elliptical kernel for the middle of that part, and the
+/// ellipses are kept aligned to the axes: the tangent is vertical to the left
+/// and right of the centre and horizontal above and below it, but along the
+/// diagonals the kernel is stretched equally on both axes instead of being
+/// rotated. The change in the kernel between neighbouring parts can also leave
+/// faint seams in large, smooth areas. Each part is convolved separately, so
+/// this is slower than [`bokeh_blur`].
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_swirl(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    swirl: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let (cx, cy) = (
+        width.saturating_sub(1) as f64 / 2.0,
+        height.saturating_sub(1) as f64 / 2.0,
+    );
+    let max_distance = cx.hypot(cy).max(f64::EPSILON);
+    let sector_angle = core::f64::consts::TAU / SWIRL_SECTORS as f64;
+
+    // Sector and ring of each pixel
+    let parts = (0..width * height)
+        .map(|n| {
+            let (dx, dy) = ((n % width) as f64 - cx, (n / width) as f64 - cy);
+            let angle = dy.atan2(dx) + core::f64::consts::PI;
+            let sector = (angle / sector_angle) as usize % SWIRL_SECTORS;
+            let ring =
+                ((dx.hypot(dy) / max_distance * SWIRL_RINGS as f64) as usize).min(SWIRL_RINGS - 1);
+            (sector, ring)
+        })
+        .collect::<Vec<_>>();
+
+    let mut blurred = vec![[0.0; 4]; width * height];
+    for sector in 0..SWIRL_SECTORS {
+        for ring in 0..SWIRL_RINGS {
+            let mask = parts
+                .iter()
+                .map(|&part| part == (sector, ring))
+                .collect::<Vec<_>>();
+            let Some(region) = mask_bounds(&mask, width, height) else {
+                continue;
+            };
+
+            // Tangent of the middle of the part is (-sin, cos)
+            let angle = (sector as f64 + 0.5) * sector_angle - core::f64::consts::PI;
+            let stretch = swirl * (ring as f64 + 0.5) / SWIRL_RINGS as f64;
+            let (horizontal, vertical) = kernel_gaussian_components_anisotropic(
+                param_set,
+                radius * (1.0 + stretch * angle.sin().powi(2)),
+                radius * (1.0 + stretch * angle.cos().powi(2)),
+            );
+            let kernels = weight_kernel_pairs(param_set, horizontal, vertical);
+
+            for (n, rgba) in blur_region_with_kernels(img, width, height, region, &kernels, gamma)
+                .into_iter()
+                .enumerate()
+            {
+                let (x, y) = (region.x + n % region.width, region.y + n / region.width);
+                if mask[y * width + x] {
+                    blurred[y * width + x] = rgba;
+                }
+            }
+        }
+    }
+
+    for (n, rgba) in blurred.into_iter().enumerate() {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+/// Equivalent blurring functions but operate on a
+/// [`image::DynamicImage`](image). See also the [`crate::Blur`] trait.
+#[cfg(feature = "image")]
+pub mod dynamic_image {
+    use super::{is_identity, ComplexImage};
+    use crate::error::BlurError;
+    use crate::params::KernelParamSet;
+    use crate::tone::ToneMap;
+    use image::codecs::hdr::HdrDecoder;
+    use image::{
+        ColorType, DynamicImage, GenericImage, GenericImageView, GrayImage, ImageBuffer,
+        ImageResult, Luma, Pixel, Rgb32FImage, Rgba32FImage, RgbaImage,
+    };
+    use std::io::BufRead;
+
+    /// Blurs an image using an approximation of a disc-shaped kernel to produce
+    /// a Bokeh lens effect.
+    ///
+    /// Takes an exclusive reference to a [`image::DynamicImage`](image). The
+    /// image is blurred by a disc-shaped kernel with radius `radius`, built
+    /// from components corresponding to `param_set`. The exposure can be
+    /// modified using `gamma`, set to `1.0` for no change.
+    ///
+    /// Images with 16 bits per channel are blurred at their full precision and
+    /// keep their colour type, see [`bokeh_blur_16`].
+    pub fn bokeh_blur(img: &mut DynamicImage, radius: f64, param_set: &KernelParamSet, gamma: f64) {
+        if is_identity(radius) {
+            return;
+        }
+        if matches!(
+            img.color(),
+            ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16
+        ) {
+            return bokeh_blur_16(img, radius, param_set, gamma);
+        }
+
+        let w = img.width();
+
+        for (n, rgba) in ComplexImage::from_dynamic_image(img, gamma)
+            .bokeh_blur(param_set, radius)
+            .into_iter()
+            .enumerate()
+        {
+            // Safety: definitely in bounds due to iteration ranges
+            unsafe {
+                img.unsafe_put_pixel(
+                    n as u32 % w,
+                    n as u32 / w,
+                    // Clamp any values from floating point ops - ensure the cast to u8 is ok
+                    *Pixel::from_slice(
+                        &rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0).round() as u8),
+                    ),
+                )
+            }
+        }
+    }
+
+    /// Blurs an image with 16 bits per channel using an approximation of a
+    /// disc-shaped kernel to produce a Bokeh lens effect.
+    ///
+    /// The pixels are read and written as 16-bit values, see
+    /// [`crate::bokeh_blur_with_max_value`], rather than being cut down to 8
+    /// bits. Greyscale and RGB images keep their colour type while everything
+    /// else becomes RGBA with 16 bits per channel. The image is blurred by a
+    /// disc-shaped kernel with radius `radius`, built from components
+    /// corresponding to `param_set`. The exposure can be modified using
+    /// `gamma`, set to `1.0` for no change.
+    pub fn bokeh_blur_16(
+        img: &mut DynamicImage,
+        radius: f64,
+        param_set: &KernelParamSet,
+        gamma: f64,
+    ) {
+        blur_pixels(img, |pixels, w, h, max_value| {
+            super::bokeh_blur_with_max_value(pixels, w, h, radius, param_set, gamma, max_value)
+        })
+    }
+
+    /// Reads the pixels of an image as RGBA, blurs them with `blur` and writes
+    /// them back at the same precision.
+    ///
+    /// `blur` is passed the pixels, the width and height of the image and the
+    /// largest value a channel can take: `65535.0` for images with 16 bits per
+    /// channel, whose greyscale and RGB colour types are kept, otherwise
+    /// `255.0`.
+    pub(crate) fn blur_pixels(
+        img: &mut DynamicImage,
+        blur: impl FnOnce(&mut [[f64; 4]], usize, usize, f64),
+    ) {
+        let (w, h) = img.dimensions();
+        let color = img.color();
+
+        if !matches!(
+            color,
+            ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16
+        ) {
+            let mut pixels = img
+                .pixels()
+                .map(|(_, _, p)| p.0.map(f64::from))
+                .collect::<Vec<_>>();
+            blur(&mut pixels, w as usize, h as usize, u8::MAX as f64);
+
+            for (n, rgba) in pixels.into_iter().enumerate() {
+                // Safety: definitely in bounds due to iteration ranges
+                unsafe {
+                    img.unsafe_put_pixel(
+                        n as u32 % w,
+                        n as u32 / w,
+                        *Pixel::from_slice(&rgba.map(|i| i.round() as u8)),
+                    )
+                }
+            }
+            return;
+        }
+
+        let mut pixels = img
+            .to_rgba16()
+            .pixels()
+            .map(|p| p.0.map(f64::from))
+            .collect::<Vec<_>>();
+        blur(&mut pixels, w as usize, h as usize, u16::MAX as f64);
+
+        let raw = pixels.iter().flatten().map(|&c| c.round() as u16).collect();
+        // Safety of unwrap: the buffer has exactly 4 channels per pixel
+        let blurred = DynamicImage::ImageRgba16(ImageBuffer::from_raw(w, h, raw).unwrap());
+        *img = match color {
+            ColorType::L16 => DynamicImage::ImageLuma16(blurred.into_luma16()),
+            ColorType::La16 => DynamicImage::ImageLumaA16(blurred.into_luma_alpha16()),
+            ColorType::Rgb16 => DynamicImage::ImageRgb16(blurred.into_rgb16()),
+            _ => blurred,
+        };
+    }
+
+    /// Blurs the selected parts of an image using an approximation of a
+    /// disc-shaped kernel to produce a Bokeh lens effect.
+    ///
+    /// Takes an exclusive reference to a [`image::DynamicImage`](image). Also
+    /// takes a `mask` of the same length as the image where `true`'s correspond
+    /// to the convolved image and `false`'s corresponsed to the original. The
+    /// image is blurred by a disc-shaped kernel with radius `radius`, built
+    /// from components corresponding to `param_set`. The exposure can be
+    /// modified using `gamma`, set to `1.0` for no change.
+    ///
+    /// Pixels where the mask is `false` keep their original values. Images
+    /// with 16 bits per channel are blurred at their full precision and keep
+    /// their colour type, as by [`bokeh_blur`].
+    pub fn bokeh_blur_with_mask<'a>(
+        img: &mut DynamicImage,
+        mask: impl IntoIterator<Item = &'a bool>,
+        radius: f64,
+        param_set: &KernelParamSet,
+        gamma: f64,
+    ) {
+        if is_identity(radius) {
+            return;
+        }
+
+        let (w, h) = img.dimensions();
+        let mask = super::collect_mask(mask, w as usize, h as usize);
+        if !mask.contains(&true) {
+            return;
+        }
+
+        blur_pixels(img, |pixels, w, h, max_value| {
+            let is_blurred = |i: usize| mask[i];
+            super::blur_where(
+                pixels, w, h, is_blurred, radius, param_set, gamma, max_value,
+            )
+        })
+    }
+
+    /// Blurs an image by the circle of confusion of each pixel, simulating the
+    /// depth of field of a camera focused at `focal_distance` from a
+    /// `depth_map`, see [`crate::bokeh_blur_with_depth_map`].
+    ///
+    /// Takes an exclusive reference to a [`image::DynamicImage`](image) and a
+    /// `depth_map` with the distance of each of its pixels. Images with 16
+    /// bits per channel are blurred at their full precision, as by
+    /// [`bokeh_blur`].
+    pub fn bokeh_blur_with_depth_map(
+        img: &mut DynamicImage,
+        depth_map: &[f64],
+        focal_distance: f64,
+        aperture: f64,
+        param_set: &KernelParamSet,
+        gamma: f64,
+    ) {
+        blur_pixels(img, |pixels, w, h, max_value| {
+            // The depth of field blur works in the range of 8-bit values
+            let scale = 255.0 / max_value;
+            for pixel in pixels.iter_mut() {
+                *pixel = pixel.map(|c| c * scale);
+            }
+            crate::bokeh_blur_with_depth_map(
+                pixels,
+                w,
+                h,
+                depth_map,
+                focal_distance,
+                aperture,
+                param_set,
+                gamma,
+            );
+            for pixel in pixels.iter_mut() {
+                *pixel = pixel.map(|c| c / scale);
+            }
+        })
+    }
+
+    /// Checked version of [`bokeh_blur_with_mask`].
+    ///
+    /// Returns [`BlurError::MaskLengthMismatch`] without modifying the image if
+    /// `mask` doesn't have exactly one element per pixel, rather than only
+    /// blurring the pixels the mask covers.
+    pub fn try_bokeh_blur_with_mask<'a>(
+        img: &mut DynamicImage,
+        mask: impl IntoIterator<Item = &'a bool>,
+        radius: f64,
+        param_set: &KernelParamSet,
+        gamma: f64,
+    ) -> Result<(), BlurError> {
+        let mask = mask.into_iter().collect::<Vec<_>>();
+        let expected = (img.width() * img.height()) as usize;
+        if mask.len() != expected {
+            return Err(BlurError::MaskLengthMismatch {
+                expected,
+                got: mask.len(),
+            });
+        }
+
+        bokeh_blur_with_mask(img, mask, radius, param_set, gamma);
+        Ok(())
+    }
+
+    /// Reads a Radiance `.hdr` image as linear RGB floats, ready for
+    /// [`bokeh_blur_linear`].
+    ///
+    /// Decoding a `.hdr` image through [`image::open`] gives 8-bit pixels with
+    /// the highlights clipped, losing the intensities that make linear Bokeh
+    /// worthwhile, so this keeps the full range instead.
+    pub fn read_hdr(reader: impl BufRead) -> ImageResult<DynamicImage> {
+        let decoder = HdrDecoder::new(reader)?;
+        let (w, h) = (decoder.metadata().width, decoder.metadata().height);
+        let raw = decoder
+            .read_image_hdr()?
+            .into_iter()
+            .flat_map(|p| p.0)
+            .collect();
+
+        // Safety of unwrap: the decoder returns one pixel per position
+        Ok(DynamicImage::ImageRgb32F(
+            Rgb32FImage::from_raw(w, h, raw).unwrap(),
+        ))
+    }
+
+    /// Blurs an image of scene-linear light, such as a Radiance `.hdr` image,
+    /// using an approximation of a disc-shaped kernel to produce a Bokeh lens
+    /// effect.
+    ///
+    /// The pixels are read as linear floats and blurred without a gamma
+    /// round-trip or clamping the highlights, see
+    /// [`crate::bokeh_blur_linear`]. The result is a float image: RGB float
+    /// images, as returned by [`read_hdr`], stay RGB and everything else
+    /// becomes RGBA. Images with integer channels are read as `0.0..=1.0` and
+    /// treated as linear. Use [`tone_map_hdr`] to bring the result into range
+    /// for display.
+    ///
+    /// The image is blurred by a disc-shaped kernel with radius `radius`, built
+    /// from components corresponding to `param_set`.
+    pub fn bokeh_blur_linear(img: &mut DynamicImage, radius: f64, param_set: &KernelParamSet) {
+        let (w, h) = img.dimensions();
+        let mut pixels = img
+            .to_rgba32f()
+            .pixels()
+            .map(|p| p.0.map(f64::from))
+            .collect::<Vec<_>>();
+
+        super::bokeh_blur_linear(&mut pixels, w as usize, h as usize, radius, param_set);
+
+        let raw = pixels.iter().flatten().map(|&c| c as f32).collect();
+        // Safety of unwrap: the buffer has exactly 4 channels per pixel
+        let blurred = DynamicImage::ImageRgba32F(Rgba32FImage::from_raw(w, h, raw).unwrap());
+        *img = match img {
+            DynamicImage::ImageRgb32F(_) => DynamicImage::ImageRgb32F(blurred.into_rgb32f()),
+            _ => blurred,
+        };
+    }
+
+    /// Maps an image of scene-linear light into an 8-bit RGBA image for
+    /// display, e.g. after [`bokeh_blur_linear`].
+    ///
+    /// A linear value of `1.0` is white. The RGB channels are rolled off by
+    /// `tone_map` and then encoded with `gamma`, i.e. raised to `1 / gamma`,
+    /// while the alpha is clamped to `0.0..=1.0`.
+    pub fn tone_map_hdr(img: &DynamicImage, tone_map: ToneMap, gamma: f64) -> DynamicImage {
+        let mut ldr = img.to_rgba32f();
+        for p in ldr.pixels_mut() {
+            for c in &mut p.0[..3] {
+                *c = tone_map.apply(*c as f64).powf(1.0 / gamma) as f32;
+            }
+            p.0[3] = p.0[3].clamp(0.0, 1.0);
+        }
+
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(ldr.width(), ldr.height(), |x, y| {
+            image::Rgba(ldr.get_pixel(x, y).0.map(|c| (c * 255.0).round() as u8))
+        }))
+    }
+
+    /// Blurs a copy of an image and returns a heatmap of how much each pixel
+    /// changed, see [`diff_heatmap`].
+    ///
+    /// The image itself is left unchanged. The image is blurred by a
+    /// disc-shaped kernel with radius `radius`, built from components
+    /// corresponding to `param_set`. The exposure can be modified using
+    /// `gamma`, set to `1.0` for no change.
+    pub fn bokeh_blur_diff(
+        img: &DynamicImage,
+        radius: f64,
+        param_set: &KernelParamSet,
+        gamma: f64,
+    ) -> DynamicImage {
+        let mut blurred = img.clone();
+        bokeh_blur(&mut blurred, radius, param_set, gamma);
+        diff_heatmap(img, &blurred)
+    }
+
+    /// Greyscale heatmap of the per-pixel absolute difference between two
+    /// images of the same size, e.g. before and after a blur.
+    ///
+    /// Each pixel of the heatmap is the largest absolute difference across the
+    /// RGBA channels of that pixel, normalised so the pixel that changed the
+    /// most is white. Pixels that didn't change are black, so the heatmap
+    /// shows exactly which pixels a masked or region blur touched. Two equal
+    /// images give a black heatmap.
+    pub fn diff_heatmap(before: &DynamicImage, after: &DynamicImage) -> DynamicImage {
+        debug_assert_eq!(before.dimensions(), after.dimensions());
+        let (w, h) = before.dimensions();
+
+        let diffs = before
+            .to_rgba8()
+            .pixels()
+            .zip(after.to_rgba8().pixels())
+            .map(|(b, a)| {
+                b.0.iter()
+                    .zip(a.0.iter())
+                    .map(|(&b, &a)| b.abs_diff(a))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+        let max = diffs.iter().copied().max().unwrap_or(0).max(1) as f64;
+
+        DynamicImage::ImageLuma8(GrayImage::from_fn(w, h, |x, y| {
+            Luma([(diffs[(y * w + x) as usize] as f64 * 255.0 / max).round() as u8])
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::{KERNEL1_PARAM_SET, KERNEL5_PARAM_SET, KERNEL9_PARAM_SET};
+
+    #[test]
+    fn reconstructed_kernel_is_a_symmetric_disc() {
+        let (kernel, side) = reconstruct_kernel_2d(&KERNEL9_PARAM_SET, 4.0, 6);
+        assert_eq!(side, 13);
+        assert_eq!(kernel.len(), side * side);
+
+        let sum = kernel.iter().sum::<f64>();
+        assert!((sum - 1.0).abs() < 1e-9);
+        for y in 0..side {
+            for x in 0..side {
+                let k = kernel[y * side + x];
+                assert!((k - kernel[x * side + y]).abs() < 1e-12);
+                assert!((k - kernel[y * side + side - 1 - x]).abs() < 1e-12);
+            }
+        }
+
+        // Flat inside the disc, negligible in the corners outside it
+        let centre = kernel[6 * side + 6];
+        assert!(centre > 0.0);
+        assert!(kernel[0].abs() < 0.1 * centre);
+    }
+
+    #[test]
+    fn anisotropic_blur_stretches_along_one_axis() {
+        let (w, h) = (21, 21);
+        let mut original = vec![[0.0; 4]; w * h];
+        original[10 * w + 10] = [255.0; 4];
+
+        let mut img = original.clone();
+        bokeh_blur_anisotropic(&mut img, w, h, 2.0, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        let mut expected = original.clone();
+        bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+
+        let mut img = original;
+        bokeh_blur_anisotropic(&mut img, w, h, 6.0, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        assert!(img[10 * w + 15][0] > 1.0);
+        assert!(img[15 * w + 10][0] < 1e-3);
+
+        // Brightness is preserved away from the edges
+        let mut img = vec![[100.0; 4]; w * h];
+        bokeh_blur_anisotropic(&mut img, w, h, 6.0, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        assert!((img[10 * w + 10][0] - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rgba_u8_matches_f64() {
+        let (w, h) = (9, 7);
+        let original = crate::testing::gradient_image(w, h);
+        let mut buf = original
+            .iter()
+            .flatten()
+            .map(|&c| c as u8)
+            .collect::<Vec<_>>();
+        bokeh_blur_rgba_u8(&mut buf, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+
+        let mut expected = original
+            .iter()
+            .map(|p| p.map(|c| (c as u8) as f64))
+            .collect::<Vec<_>>();
+        bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (b, e) in buf.iter().zip(expected.iter().flatten()) {
+            assert_eq!(*b, e.round() as u8);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "whole RGBA pixels")]
+    fn rgba_u8_rejects_partial_pixels() {
+        bokeh_blur_rgba_u8(&mut [0; 7], 2, 1, 1.0, &KERNEL9_PARAM_SET, 1.0);
+    }
+
+    #[test]
+    fn cancellable_blur_stops_part_way() {
+        let (w, h) = (9, 7);
+        let original = crate::testing::gradient_image(w, h);
+
+        let mut img = original.clone();
+        assert_eq!(
+            try_bokeh_blur_cancellable(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0, || true),
+            Err(BlurError::Cancelled)
+        );
+        assert_eq!(img, original);
+
+        // Cancelled after the first few polls, with the image left untouched
+        let polls = core::sync::atomic::AtomicUsize::new(0);
+        let cancel = || polls.fetch_add(1, core::sync::atomic::Ordering::Relaxed) >= 3;
+        assert_eq!(
+            try_bokeh_blur_cancellable(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0, cancel),
+            Err(BlurError::Cancelled)
+        );
+        assert_eq!(img, original);
+
+        let mut expected = original;
+        bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        try_bokeh_blur_cancellable(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0, || false).unwrap();
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn raw_blur_keeps_values_out_of_range() {
+        let (w, h) = (9, 7);
+        let original = crate::testing::gradient_image(w, h)
+            .into_iter()
+            .map(|p| p.map(|c| c * 4.0))
+            .collect::<Vec<_>>();
+
+        let raw = bokeh_blur_raw(&original, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        assert!(raw.iter().flatten().any(|&c| c > 255.0));
+
+        let mut expected = original;
+        bokeh_blur_with_max_value(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0, f64::MAX);
+        for (r, e) in raw.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((r.max(0.0) - e).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn strided_rows_skip_the_padding() {
+        let (w, h, stride) = (9, 7, 12);
+        let original = crate::testing::gradient_image(w, h);
+        let mut padded = vec![[255.0; 4]; (h - 1) * stride + w];
+        for (n, &p) in original.iter().enumerate() {
+            padded[n / w * stride + n % w] = p;
+        }
+
+        bokeh_blur_with_stride(&mut padded, w, h, stride, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        let mut expected = original;
+        bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (i, p) in padded.iter().enumerate() {
+            if i % stride < w {
+                let e = expected[i / stride * w + i % stride];
+                for c in 0..4 {
+                    assert!((p[c] - e[c]).abs() < 1e-9);
+                }
+            } else {
+                assert_eq!(*p, [255.0; 4]);
+            }
+        }
+    }
+
+    #[test]
+    fn bitmask_matches_bool_mask() {
+        let (w, h) = (13, 11);
+        let original = crate::testing::gradient_image(w, h);
+        let mask = (0..w * h).map(|i| i % 7 < 3 && i > 40).collect::<Vec<_>>();
+        let mut bits = vec![0u64; (w * h).div_ceil(64)];
+        for (i, _) in mask.iter().enumerate().filter(|(_, &m)| m) {
+            bits[i / 64] |= 1 << (i % 64);
+        }
+
+        let mut img = original.clone();
+        bokeh_blur_with_bitmask(&mut img, &bits, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        let mut expected = original.clone();
+        bokeh_blur_with_mask(&mut expected, &mask, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+
+        let mut img = original.clone();
+        bokeh_blur_with_bitmask(&mut img, &[0, 0, 0], w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        assert_eq!(img, original);
+    }
+
+    #[test]
+    fn alpha_selects_the_pixels_kept() {
+        let (w, h) = (13, 11);
+        let original = crate::testing::gradient_image(w, h)
+            .into_iter()
+            .enumerate()
+            .map(|(i, [r, g, b, _])| [r, g, b, if i % 5 < 2 { 255.0 } else { 64.0 }])
+            .collect::<Vec<_>>();
+        let mask = original.iter().map(|p| p[3] < 128.0).collect::<Vec<_>>();
+
+        let mut img = original.clone();
+        bokeh_blur_by_alpha(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2, 128.0);
+        let mut expected = original.clone();
+        bokeh_blur_with_mask(&mut expected, &mask, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        assert_eq!(img, expected);
+        for (a, b) in img.iter().zip(&original).filter(|(_, p)| p[3] >= 128.0) {
+            assert_eq!(a, b);
+        }
+
+        let mut img = original.clone();
+        bokeh_blur_by_alpha(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2, 0.0);
+        assert_eq!(img, original);
+    }
+
+    #[test]
+    fn fill_replaces_the_unmasked_pixels() {
+        let (w, h) = (13, 11);
+        let original = crate::testing::gradient_image(w, h);
+        let mask = (0..w * h).map(|i| i % 7 < 3).collect::<Vec<_>>();
+        let mut expected = original.clone();
+        bokeh_blur_with_mask(&mut expected, &mask, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+
+        let mut img = original.clone();
+        bokeh_blur_with_mask_fill(&mut img, &mask, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2, None);
+        assert_eq!(img, expected);
+
+        let grey = [128.0, 128.0, 128.0, 255.0];
+        let mut img = original.clone();
+        bokeh_blur_with_mask_fill(
+            &mut img,
+            &mask,
+            w,
+            h,
+            2.0,
+            &KERNEL9_PARAM_SET,
+            2.2,
+            Some(grey),
+        );
+        for ((a, b), m) in img.iter().zip(&expected).zip(&mask) {
+            assert_eq!(*a, if *m { *b } else { grey });
+        }
+    }
+
+    #[test]
+    fn no_gamma_skips_the_transform_and_clamp() {
+        let (w, h) = (9, 7);
+        let original = crate::testing::checkerboard(w, h, 2)
+            .into_iter()
+            .map(|p| p.map(|c| c * 4.0))
+            .collect::<Vec<_>>();
+
+        let mut img = original.clone();
+        bokeh_blur_with_gamma(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, None);
+        assert_eq!(
+            img,
+            bokeh_blur_raw(&original, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0)
+        );
+        assert!(img.iter().flatten().any(|&c| c > 255.0));
+
+        let mut img = original.clone();
+        bokeh_blur_with_gamma(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, Some(2.2));
+        let mut expected = original;
+        bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        assert_eq!(img, expected);
+    }
+
+    #[test]
+    fn non_finite_pixels_are_rejected() {
+        let (w, h) = (5, 4);
+        let original = crate::testing::gradient_image(w, h);
+        for (bad, gamma) in [(f64::NAN, 1.0), (f64::INFINITY, 1.0), (-1.0, 2.2)] {
+            let mut img = original.clone();
+            img[7][2] = bad;
+            let copy = img.clone();
+            assert_eq!(
+                try_bokeh_blur(&mut img, w, h, 1.0, &KERNEL9_PARAM_SET, gamma),
+                Err(BlurError::NonFiniteInput { index: 7 })
+            );
+            assert!(img
+                .iter()
+                .flatten()
+                .zip(copy.iter().flatten())
+                .all(|(a, b)| a.to_bits() == b.to_bits()));
+        }
+
+        // A negative value is fine without a gamma, and is clamped once blurred
+        let mut img = original.clone();
+        img[7][2] = -1.0;
+        try_bokeh_blur(&mut img, w, h, 1.0, &KERNEL9_PARAM_SET, 1.0).unwrap();
+        assert!(img.iter().flatten().all(|c| (0.0..=255.0).contains(c)));
+    }
+
+    #[test]
+    fn non_finite_pixels_are_treated_as_black() {
+        let (w, h) = (5, 4);
+        let original = crate::testing::gradient_image(w, h);
+        for (bad, gamma) in [(f64::NAN, 1.0), (f64::INFINITY, 1.0), (-1e-12, 2.2)] {
+            let mut img = original.clone();
+            img[7][2] = bad;
+            bokeh_blur(&mut img, w, h, 1.0, &KERNEL9_PARAM_SET, gamma);
+            let mut expected = original.clone();
+            expected[7][2] = 0.0;
+            bokeh_blur(&mut expected, w, h, 1.0, &KERNEL9_PARAM_SET, gamma);
+            assert_eq!(img, expected);
+
+            let mut grey = original.iter().map(|p| p[2]).collect::<Vec<_>>();
+            grey[7] = bad;
+            bokeh_blur_grey(&mut grey, w, h, 1.0, &KERNEL9_PARAM_SET, gamma);
+            assert!(grey.iter().zip(&expected).all(|(g, e)| *g == e[2]));
+
+            // The checked versions reject them instead
+            let mut img = original.clone();
+            img[7][2] = bad;
+            let mask = vec![true; w * h];
+            assert_eq!(
+                try_bokeh_blur_with_mask(&mut img, &mask, w, h, 1.0, &KERNEL9_PARAM_SET, gamma),
+                Err(BlurError::NonFiniteInput { index: 7 })
+            );
+            assert_eq!(
+                try_bokeh_blur_cancellable(&mut img, w, h, 1.0, &KERNEL9_PARAM_SET, gamma, || {
+                    false
+                }),
+                Err(BlurError::NonFiniteInput { index: 7 })
+            );
+        }
+    }
+
+    #[test]
+    fn negative_ringing_is_clamped_before_the_gamma() {
+        // The ringing of the kernel leaves the black column just below zero,
+        // which has no real root
+        let (w, h) = (6, 5);
+        let mut img = crate::testing::gradient_image(w, h);
+        bokeh_blur(&mut img, w, h, 1.0 / 3.0, &KERNEL9_PARAM_SET, 2.2);
+        assert!(img.iter().flatten().all(|c| (0.0..=255.0).contains(c)));
+        assert_eq!(img[0], [0.0; 4]);
+    }
+
+    #[test]
+    fn dc_gain_checks_the_normalisation() {
+        for param_set in [&KERNEL1_PARAM_SET, &KERNEL5_PARAM_SET, &KERNEL9_PARAM_SET] {
+            for (r, kernel_radius) in [(1.0, 1), (4.5, 5), (10.0, 3)] {
+                assert!((kernel_dc_gain(param_set, r, kernel_radius) - 1.0).abs() < 1e-9);
+            }
+        }
+
+        let flipped = [0.862325, 1.624835, -0.767583, -1.862321];
+        let param_set = KernelParamSet::from_params(&flipped).unwrap();
+        assert!(kernel_dc_gain(&param_set, 3.0, 3).is_nan());
+    }
+
+    #[test]
+    fn iterated_blur_applies_the_gamma_once() {
+        let (w, h) = (15, 13);
+        let original = crate::testing::gradient_image(w, h);
+
+        let mut img = original.clone();
+        bokeh_blur_iterated(&mut img, w, h, 2.0, 0, &KERNEL9_PARAM_SET, 2.2);
+        assert_eq!(img, original);
+
+        let mut img = original.clone();
+        bokeh_blur_iterated(&mut img, w, h, 2.0, 1, &KERNEL9_PARAM_SET, 2.2);
+        let mut expected = original.clone();
+        bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+
+        let mut img = original.clone();
+        bokeh_blur_iterated(&mut img, w, h, 2.0, 3, &KERNEL9_PARAM_SET, 2.2);
+        let mut linear = bokeh_blur_raw(&original, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        for _ in 0..2 {
+            linear = bokeh_blur_raw(&linear, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        }
+        for (a, b) in img.iter().flatten().zip(linear.iter().flatten()) {
+            assert!((a - b.powf(1.0 / 2.2).clamp(0.0, 255.0)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn chromatic_blur_scales_each_channel() {
+        let (w, h) = (15, 13);
+        let original = crate::testing::gradient_image(w, h);
+
+        let mut img = original.clone();
+        bokeh_blur_chromatic(&mut img, w, h, 3.0, [1.0; 3], &KERNEL9_PARAM_SET, 2.2);
+        let mut expected = original.clone();
+        bokeh_blur(&mut expected, w, h, 3.0, &KERNEL9_PARAM_SET, 2.2);
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+
+        let scale = [1.5, 1.0, 0.5];
+        let mut img = original.clone();
+        bokeh_blur_chromatic(&mut img, w, h, 3.0, scale, &KERNEL9_PARAM_SET, 2.2);
+        for (c, radius) in scale.map(|s| 3.0 * s).into_iter().chain([3.0]).enumerate() {
+            let mut expected = original.clone();
+            bokeh_blur(&mut expected, w, h, radius, &KERNEL9_PARAM_SET, 2.2);
+            for (a, b) in img.iter().zip(&expected) {
+                assert!((a[c] - b[c]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn mix_blends_by_strength() {
+        let (w, h) = (9, 7);
+        let original = crate::testing::gradient_image(w, h);
+        let mut blurred = original.clone();
+        bokeh_blur(&mut blurred, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+
+        let mut img = original.clone();
+        bokeh_blur_mix(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2, 1.0);
+        assert_eq!(img, blurred);
+
+        let mut img = original.clone();
+        bokeh_blur_mix(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2, -0.5);
+        assert_eq!(img, original);
+
+        let mut img = original.clone();
+        bokeh_blur_mix(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2, 0.25);
+        for ((a, o), b) in img
+            .iter()
+            .flatten()
+            .zip(original.iter().flatten())
+            .zip(blurred.iter().flatten())
+        {
+            assert!((a - (0.25 * b + 0.75 * o)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn zero_radius_leaves_pixels_untouched() {
+        let (w, h) = (7, 5);
+        let original = crate::testing::gradient_image(w, h)
+            .into_iter()
+            .map(|p| p.map(|c| c + 0.1))
+            .collect::<Vec<_>>();
+
+        let mut img = original.clone();
+        bokeh_blur(&mut img, w, h, 0.0, &KERNEL9_PARAM_SET, 2.2);
+        assert_eq!(img, original);
+        bokeh_blur_with_edge_mode(
+            &mut img,
+            w,
+            h,
+            0.0,
+            &KERNEL9_PARAM_SET,
+            2.2,
+            EdgeMode::Clamp,
+        );
+        assert_eq!(img, original);
+        bokeh_blur_with_kernel_radius(&mut img, w, h, 3.0, 0, &KERNEL9_PARAM_SET, 2.2);
+        assert_eq!(img, original);
+
+        let original = original
+            .iter()
+            .map(|p| p.map(|c| c as f32))
+            .collect::<Vec<_>>();
+        let mut img = original.clone();
+        bokeh_blur_f32(&mut img, w, h, 0.0, &KERNEL9_PARAM_SET, 2.2);
+        assert_eq!(img, original);
+    }
+
+    #[test]
+    fn zero_radius_leaves_every_entry_point_untouched() {
+        use crate::Blur;
+        type BlurFn<'a> = &'a dyn Fn(&mut [[f64; 4]]);
+
+        let (w, h) = (7, 5);
+        let original = crate::testing::gradient_image(w, h)
+            .into_iter()
+            .map(|p| p.map(|c| c + 100.3))
+            .collect::<Vec<_>>();
+        let mask = (0..w * h).map(|n| n % 3 == 0).collect::<Vec<_>>();
+        assert_eq!(
+            complex_gaussian_kernel(0.0, 0, 1.0, 1.0, 1.0),
+            vec![Complex::new(1.0, 0.0)]
+        );
+
+        let blurs: [BlurFn; 17] = [
+            &|img| bokeh_blur_with_mask(img, &mask, w, h, 0.0, &KERNEL9_PARAM_SET, 2.2),
+            &|img| bokeh_blur_with_mask_fill(img, &mask, w, h, 0.0, &KERNEL9_PARAM_SET, 2.2, None),
+            &|img| bokeh_blur_with_stride(img, w, h, w, 0.0, &KERNEL9_PARAM_SET, 2.2),
+            &|img| bokeh_blur_iterated(img, w, h, 0.0, 3, &KERNEL9_PARAM_SET, 2.2),
+            &|img| bokeh_blur_chromatic(img, w, h, 0.0, [1.1, 1.0, 0.9], &KERNEL9_PARAM_SET, 2.2),
+            &|img| bokeh_blur_premultiplied(img, w, h, 0.0, &KERNEL9_PARAM_SET, 2.2),
+            &|img| bokeh_blur_linear(img, w, h, 0.0, &KERNEL9_PARAM_SET),
+            &|img| {
+                try_bokeh_blur_with_mask(img, &mask, w, h, 0.0, &KERNEL9_PARAM_SET, 2.2).unwrap()
+            },
+            &|img| {
+                try_bokeh_blur_cancellable(img, w, h, 0.0, &KERNEL9_PARAM_SET, 2.2, || false)
+                    .unwrap()
+            },
+            &|img| crate::bokeh_blur_tiled(img, w, h, 0.0, &KERNEL9_PARAM_SET, 2.2, 3),
+            &|img| crate::bokeh_blur_sequential(img, w, h, 0.0, &KERNEL9_PARAM_SET, 2.2),
+            &|img| crate::bokeh_blur_layered(img, &mask, w, h, 0.0, &KERNEL9_PARAM_SET, 2.2),
+            &|img| {
+                let plan = KernelPlan::for_radius(&KERNEL9_PARAM_SET, 0.0);
+                crate::bokeh_blur_with_plan(img, w, h, &plan, 2.2)
+            },
+            &|img| {
+                let plan = KernelPlan::new(&KERNEL9_PARAM_SET, 0.0, 0);
+                crate::bokeh_blur_with_plan(img, w, h, &plan, 2.2)
+            },
+            &|img| {
+                let blur = crate::BokehBlur::builder().radius(0.0).gamma(2.2).build();
+                blur.apply_slice(img, w, h)
+            },
+            &|img| {
+                let options = crate::BokehBlur::builder().radius(0.0).gamma(2.2).build();
+                crate::Image::new(img, w, h).bokeh_blur_with(&options)
+            },
+            &|img| {
+                let mut images = [crate::Image::new(img, w, h)];
+                bokeh_blur_batch(&mut images, 0.0, &KERNEL9_PARAM_SET, 2.2)
+            },
+        ];
+        for (i, blur) in blurs.iter().enumerate() {
+            let mut img = original.clone();
+            blur(&mut img);
+            assert_eq!(img, original, "entry point {i}");
+        }
+
+        let mut rows = Vec::new();
+        crate::bokeh_blur_streaming(&original, w, h, 0.0, &KERNEL9_PARAM_SET, 2.2, |_, row| {
+            rows.extend_from_slice(row)
+        });
+        assert_eq!(rows, original);
+    }
+
+    #[test]
+    fn normalised_kernels_scale_the_raw_components() {
+        let (radius, kernel_radius) = (4.5, 6);
+        let kernels = normalised_kernels(&KERNEL5_PARAM_SET, radius, kernel_radius);
+        assert_eq!(kernels.len(), KERNEL5_PARAM_SET.num_kernels());
+
+        // Every component is divided by the same factor
+        let mut norms = Vec::new();
+        for (i, kernel) in kernels.iter().enumerate() {
+            let raw = complex_gaussian_kernel(
+                radius,
+                kernel_radius,
+                KERNEL5_PARAM_SET.scale(),
+                KERNEL5_PARAM_SET.a(i),
+                KERNEL5_PARAM_SET.b(i),
+            );
+            assert_eq!(raw.len(), 2 * kernel_radius + 1);
+            assert_eq!(raw[kernel_radius], Complex::new(1.0, 0.0));
+            for (r, k) in raw.iter().zip(kernel) {
+                norms.push(r / k);
+            }
+        }
+        for norm in &norms {
+            assert!((norm - norms[0]).norm() < 1e-9);
+        }
+
+        let (kernel_2d, _) = reconstruct_kernel_2d(&KERNEL5_PARAM_SET, radius, kernel_radius);
+        assert!((kernel_2d.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn weighted_kernels_match_per_pixel_weighting() {
+        let (w, h) = (16, 12);
+        let img = (0..w * h)
+            .map(|i| [(i % 7) as f64 * 30.0, (i % 5) as f64 * 50.0, 255.0, 128.0])
+            .collect::<Vec<_>>();
+        let input = ComplexImage::from_slice(&img, w, h, 2.0);
+
+        let mut expected = vec![[0.0; 4]; w * h];
+        for (n, kernel) in kernel_gaussian_components(&KERNEL9_PARAM_SET, 3.5)
+            .iter()
+            .enumerate()
+        {
+            let re = KERNEL9_PARAM_SET.real_component(n);
+            let im = KERNEL9_PARAM_SET.imag_component(n);
+            let mut temp = vec![[Complex::new(0.0, 0.0); 4]; w * h];
+            horizontal_filter(
+                &input.pixels,
+                kernel,
+                w,
+                h,
+                EdgeMode::Zero,
+                &mut temp,
+                false,
+            );
+            let mut filtered = temp.clone();
+            vertical_filter(&temp, kernel, w, h, EdgeMode::Zero, &mut filtered, false);
+            for (e, p) in expected.iter_mut().zip(filtered) {
+                for c in 0..4 {
+                    e[c] += re * p[c].re + im * p[c].im;
+                }
+            }
+        }
+
+        for (e, o) in expected
+            .iter()
+            .zip(input.bokeh_blur(&KERNEL9_PARAM_SET, 3.5))
+        {
+            for c in 0..4 {
+                assert!((e[c] - o[c]).abs() <= 1e-9 * e[c].abs().max(1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn mosaic_of_one_is_plain_blur() {
+        let img = (0..8 * 6)
+            .map(|i| [(i * 37 % 256) as f64; 4])
+            .collect::<Vec<_>>();
+        let mut expected = img.clone();
+        let mut mosaic = img;
+
+        bokeh_blur(&mut expected, 8, 6, 2.0, &KERNEL9_PARAM_SET, 3.0);
+        bokeh_blur_mosaic(&mut mosaic, 8, 6, 1, 2.0, &KERNEL9_PARAM_SET, 3.0);
+
+        assert_eq!(mosaic, expected);
+    }
+
+    #[test]
+    fn mosaic_blocks_are_flat() {
+        let mut img = (0..5 * 5)
+            .map(|i| [(i * 37 % 256) as f64; 4])
+            .collect::<Vec<_>>();
+
+        bokeh_blur_mosaic(&mut img, 5, 5, 2, 1.0, &KERNEL9_PARAM_SET, 1.0);
+
+        assert_eq!(img[0], img[1]);
+        assert_eq!(img[0], img[5]);
+        assert_eq!(img[0], img[6]);
+        assert_ne!(img[0], img[2]);
+        // cropped corner block is a single pixel
+        assert_ne!(img[24], img[23]);
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn dynamic_image_depth_map_matches_slice() {
+        use image::{DynamicImage, RgbaImage};
+
+        let buf = RgbaImage::from_fn(9, 7, |x, y| {
+            image::Rgba([(x * 29) as u8, (y * 31) as u8, (x * y) as u8, 255])
+        });
+        let depth_map = (0..9 * 7).map(|n| 1.0 + (n % 9) as f64).collect::<Vec<_>>();
+        let mut img = DynamicImage::ImageRgba8(buf.clone());
+        dynamic_image::bokeh_blur_with_depth_map(
+            &mut img,
+            &depth_map,
+            3.0,
+            2.5,
+            &KERNEL9_PARAM_SET,
+            2.2,
+        );
+
+        let mut expected = buf.pixels().map(|p| p.0.map(f64::from)).collect::<Vec<_>>();
+        crate::bokeh_blur_with_depth_map(
+            &mut expected,
+            9,
+            7,
+            &depth_map,
+            3.0,
+            2.5,
+            &KERNEL9_PARAM_SET,
+            2.2,
+        );
+        for (b, e) in img.as_bytes().iter().zip(expected.iter().flatten()) {
+            assert_eq!(*b, e.round() as u8);
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn dynamic_images_round_to_nearest() {
+        use image::{DynamicImage, RgbaImage};
+
+        let buf = RgbaImage::from_fn(9, 7, |x, y| {
+            image::Rgba([(x * 29) as u8, (y * 31) as u8, (x * y) as u8, 255])
+        });
+        let mut img = DynamicImage::ImageRgba8(buf.clone());
+        dynamic_image::bokeh_blur(&mut img, 2.0, &KERNEL9_PARAM_SET, 2.2);
+
+        let mut expected = buf.pixels().map(|p| p.0.map(f64::from)).collect::<Vec<_>>();
+        bokeh_blur(&mut expected, 9, 7, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (b, e) in img.as_bytes().iter().zip(expected.iter().flatten()) {
+            assert_eq!(*b, e.round() as u8);
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn dynamic_image_mask_length_is_checked() {
+        use image::{DynamicImage, RgbaImage};
+
+        let mut img = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 4, |x, y| {
+            image::Rgba([(x * 60) as u8, (y * 60) as u8, 100, 255])
+        }));
+        let original = img.clone();
+
+        assert_eq!(
+            dynamic_image::try_bokeh_blur_with_mask(
+                &mut img,
+                &[true; 8],
+                1.0,
+                &KERNEL9_PARAM_SET,
+                3.0
+            ),
+            Err(BlurError::MaskLengthMismatch {
+                expected: 16,
+                got: 8
+            })
+        );
+        assert_eq!(img, original);
+
+        assert_eq!(
+            dynamic_image::try_bokeh_blur_with_mask(
+                &mut img,
+                &[true; 20],
+                1.0,
+                &KERNEL9_PARAM_SET,
+                3.0
+            ),
+            Err(BlurError::MaskLengthMismatch {
+                expected: 16,
+                got: 20
+            })
+        );
+        assert_eq!(img, original);
+
+        assert_eq!(
+            dynamic_image::try_bokeh_blur_with_mask(
+                &mut img,
+                &[true; 16],
+                1.0,
+                &KERNEL9_PARAM_SET,
+                3.0
+            ),
+            Ok(())
+        );
+        assert_ne!(img, original);
+    }
+
+    #[test]
+    fn mismatched_mask_lengths_are_errors() {
+        let original = (0..12).map(|i| [(i * 20) as f64; 4]).collect::<Vec<_>>();
+        let mut img = original.clone();
+
+        for len in [6, 13] {
+            assert_eq!(
+                try_bokeh_blur_with_mask(
+                    &mut img,
+                    &vec![true; len],
+                    4,
+                    3,
+                    1.0,
+                    &KERNEL9_PARAM_SET,
+                    3.0
+                ),
+                Err(BlurError::MaskLengthMismatch {
+                    expected: 12,
+                    got: len
+                })
+            );
+            assert_eq!(img, original);
+        }
+
+        assert_eq!(
+            try_bokeh_blur_with_mask(&mut img, &[true; 12], 4, 3, 1.0, &KERNEL9_PARAM_SET, 3.0),
+            Ok(())
+        );
+        assert_ne!(img, original);
+    }
+
+    #[test]
+    fn small_mask_matches_full_blur() {
+        let (w, h) = (16, 12);
+        let original = crate::testing::gradient_image(w, h);
+        let mask = (0..w * h)
+            .map(|n| (5..8).contains(&(n % w)) && (4..6).contains(&(n / w)))
+            .collect::<Vec<_>>();
+
+        let mut img = original.clone();
+        bokeh_blur_with_mask(&mut img, &mask, w, h, 3.0, &KERNEL9_PARAM_SET, 3.0);
+
+        let mut blurred = original.clone();
+        bokeh_blur(&mut blurred, w, h, 3.0, &KERNEL9_PARAM_SET, 3.0);
+        for n in 0..w * h {
+            if mask[n] {
+                for (a, b) in img[n].iter().zip(&blurred[n]) {
+                    assert!((a - b).abs() < 1e-9);
+                }
+            } else {
+                assert_eq!(img[n], original[n]);
+            }
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "mask length")]
+    fn short_mask_panics_in_debug() {
+        let mut img = vec![[0.0; 4]; 12];
+        bokeh_blur_with_mask(&mut img, &[true; 6], 4, 3, 1.0, &KERNEL9_PARAM_SET, 3.0);
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn masked_out_pixels_keep_their_bytes() {
+        use image::{DynamicImage, RgbaImage};
+
+        let rgba = RgbaImage::from_fn(9, 7, |x, y| {
+            image::Rgba([
+                (x * 31 + y * 17) as u8,
+                (x * x + 3 * y) as u8,
+                (200 - 11 * y) as u8,
+                (255 - x * 13) as u8,
+            ])
+        });
+        let mask = (0..9 * 7).map(|i| i % 3 == 0).collect::<Vec<_>>();
+
+        for original in [
+            DynamicImage::ImageRgba8(rgba.clone()),
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).into_rgb8()),
+        ] {
+            let mut img = original.clone();
+            dynamic_image::bokeh_blur_with_mask(&mut img, &mask, 2.5, &KERNEL9_PARAM_SET, 2.2);
+
+            let channels = original.color().channel_count() as usize;
+            for (n, (a, b)) in original
+                .as_bytes()
+                .chunks(channels)
+                .zip(img.as_bytes().chunks(channels))
+                .enumerate()
+            {
+                if !mask[n] {
+                    assert_eq!(a, b);
+                }
+            }
+            assert_ne!(img, original);
+        }
+    }
+
+    #[test]
+    fn weights_blend_original_and_blurred() {
+        let (w, h) = (8, 6);
+        let original = crate::testing::checkerboard(w, h, 2);
+        let weights = (0..w * h)
+            .map(|n| (n % w) as f64 / (w - 1) as f64)
+            .collect::<Vec<_>>();
+
+        let mut img = original.clone();
+        bokeh_blur_with_weights(&mut img, &weights, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+
+        let mut blurred = original.clone();
+        bokeh_blur(&mut blurred, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        for n in 0..w * h {
+            for c in 0..4 {
+                let expected = weights[n] * blurred[n][c] + (1.0 - weights[n]) * original[n][c];
+                assert!((img[n][c] - expected).abs() < 1e-9);
+            }
+        }
+        assert_eq!(img[0], original[0]);
+    }
+
+    #[test]
+    fn soft_masks_take_either_float_type() {
+        let (w, h) = (8, 6);
+        let original = crate::testing::checkerboard(w, h, 2);
+        let weights = (0..w * h).map(|n| (n % 4) as f32 / 4.0).collect::<Vec<_>>();
+
+        let mut img = original.clone();
+        bokeh_blur_with_soft_mask(&mut img, &weights, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        let mut expected = original.clone();
+        let weights = weights.iter().map(|&w| f64::from(w)).collect::<Vec<_>>();
+        bokeh_blur_with_weights(&mut expected, &weights, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        assert_eq!(img, expected);
+
+        let mut img = original.clone();
+        bokeh_blur_with_soft_mask(&mut img, &weights, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        assert_eq!(img, expected);
+    }
+
+    #[test]
+    fn distant_mask_regions_are_blurred_apart() {
+        let (w, h) = (40, 30);
+        let original = (0..w * h)
+            .map(|i| [(i * 53 % 256) as f64; 4])
+            .collect::<Vec<_>>();
+        let mut full = original.clone();
+        bokeh_blur(&mut full, w, h, 3.0, &KERNEL9_PARAM_SET, 2.2);
+
+        // Spots in opposite corners, and one just within reach of the second
+        let mask = (0..w * h)
+            .map(|i| matches!((i % w, i / w), (1..=3, 2..=4) | (36, 26) | (30, 27)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            blur_regions(w, h, 3.0, |i| mask[i]),
+            [Rect::new(1, 2, 3, 3), Rect::new(30, 26, 7, 2)]
+        );
+
+        let mut img = original.clone();
+        bokeh_blur_with_mask(&mut img, &mask, w, h, 3.0, &KERNEL9_PARAM_SET, 2.2);
+        for (n, p) in img.iter().enumerate() {
+            let expected = if mask[n] { full[n] } else { original[n] };
+            for c in 0..4 {
+                assert!((p[c] - expected[c]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn region_matches_whole_image() {
+        let (w, h) = (20, 16);
+        let original = (0..w * h)
+            .map(|i| [(i * 53 % 256) as f64; 4])
+            .collect::<Vec<_>>();
+        let mut full = original.clone();
+        bokeh_blur(&mut full, w, h, 3.0, &KERNEL9_PARAM_SET, 2.2);
+
+        // Overhanging the bottom edge, and entirely beyond the image
+        for region in [Rect::new(4, 11, 7, 9), Rect::new(25, 0, 3, 3)] {
+            let mut img = original.clone();
+            bokeh_blur_region(&mut img, w, h, region, 3.0, &KERNEL9_PARAM_SET, 2.2);
+
+            for (n, p) in img.iter().enumerate() {
+                let inside = region.distance(n % w, n / w) == 0.0;
+                let expected = if inside { full[n] } else { original[n] };
+                for c in 0..4 {
+                    assert!((p[c] - expected[c]).abs() < 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn feathered_region_blends_into_surroundings() {
+        let (w, h) = (20, 16);
+        let original = (0..w * h)
+            .map(|i| [(i * 53 % 256) as f64; 4])
+            .collect::<Vec<_>>();
+        let mut full = original.clone();
+        bokeh_blur(&mut full, w, h, 2.0, &KERNEL9_PARAM_SET, 2.0);
+
+        let mut img = original.clone();
+        let region = Rect::new(6, 5, 6, 4);
+        bokeh_blur_region_feathered(&mut img, w, h, region, 3, 2.0, &KERNEL9_PARAM_SET, 2.0);
+
+        for y in 0..h {
+            for x in 0..w {
+                let n = y * w + x;
+                let d = region.distance(x, y);
+                if d == 0.0 {
+                    for c in 0..4 {
+                        assert!((img[n][c] - full[n][c]).abs() < 1e-9);
+                    }
+                } else if d >= 4.0 {
+                    assert_eq!(img[n], original[n]);
+                }
+            }
+        }
+
+        // the pixel just outside the region's edge is neither blurred nor original
+        let n = 5 * w + 5;
+        let (lo, hi) = if original[n][0] < full[n][0] {
+            (original[n][0], full[n][0])
+        } else {
+            (full[n][0], original[n][0])
+        };
+        assert!(lo < img[n][0] && img[n][0] < hi);
+    }
+
+    #[test]
+    fn pruned_kernels_stay_close_to_full() {
+        let full = kernel_gaussian_components(&KERNEL9_PARAM_SET, 10.0);
+        let pruned = kernel_gaussian_components_pruned(&KERNEL9_PARAM_SET, 10.0, 0.5);
+        assert!(pruned[0].len() < full[0].len());
+        assert_eq!(
+            kernel_gaussian_components_pruned(&KERNEL9_PARAM_SET, 10.0, 0.0),
+            full
+        );
+
+        let (w, h) = (40, 40);
+        let original = (0..w * h)
+            .map(|i| [((i * 53) % 256) as f64; 4])
+            .collect::<Vec<_>>();
+        let mut expected = original.clone();
+        bokeh_blur(&mut expected, w, h, 10.0, &KERNEL9_PARAM_SET, 1.0);
+        let mut img = original;
+        bokeh_blur_pruned(&mut img, w, h, 10.0, &KERNEL9_PARAM_SET, 1.0, 0.5);
+
+        for (e, o) in expected.iter().zip(img.iter()) {
+            assert!((e[0] - o[0]).abs() < 0.5);
+        }
+    }
+
+    #[test]
+    fn normalisation_target_scales_brightness() {
+        let (w, h) = (20, 20);
+        let mut img = vec![[50.0; 4]; w * h];
+
+        bokeh_blur_normalised_to(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0, 2.0);
+
+        for c in img[10 * w + 10] {
+            assert!((c - 100.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn batch_matches_individual_blurs() {
+        let mut originals = (0..5)
+            .map(|m| {
+                (0..(6 + m) * 5)
+                    .map(|i| [((i * 29 + m * 61) % 256) as f64; 4])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut expected = originals.clone();
+        for (m, pixels) in expected.iter_mut().enumerate() {
+            bokeh_blur(pixels, 6 + m, 5, 1.5, &KERNEL9_PARAM_SET, 3.0);
+        }
+
+        let mut images = originals
+            .iter_mut()
+            .enumerate()
+            .map(|(m, pixels)| Image::new(pixels, 6 + m, 5))
+            .collect::<Vec<_>>();
+        bokeh_blur_batch(&mut images, 1.5, &KERNEL9_PARAM_SET, 3.0);
+
+        for (e, o) in expected.iter().flatten().zip(originals.iter().flatten()) {
+            for c in 0..4 {
+                assert!((e[c] - o[c]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn subpixel_radius_spreads_smoothly() {
+        use crate::params::KERNEL1_PARAM_SET;
+
+        let spread = |radius: f64| {
+            let (w, h) = (31, 31);
+            let mut img = vec![[0.0; 4]; w * h];
+            img[15 * w + 15] = [255.0; 4];
+            bokeh_blur_subpixel(&mut img, w, h, radius, &KERNEL1_PARAM_SET, 1.0);
+
+            let (mut moment, mut total) = (0.0, 0.0);
+            for (n, p) in img.iter().enumerate() {
+                let (dx, dy) = ((n % w) as f64 - 15.0, (n / w) as f64 - 15.0);
+                moment += p[0] * (dx * dx + dy * dy);
+                total += p[0];
+            }
+            (moment / total).sqrt()
+        };
+
+        let spreads = (0..=20)
+            .map(|i| spread(3.0 + i as f64 * 0.05))
+            .collect::<Vec<_>>();
+        let mean_step = (spreads[20] - spreads[0]) / 20.0;
+        for step in spreads.windows(2).map(|s| s[1] - s[0]) {
+            assert!(step > 0.0);
+            assert!(step < 2.0 * mean_step);
+        }
+    }
+
+    #[test]
+    fn controlled_blur_reports_progress() {
+        let (w, h) = (8, 6);
+        let mut expected = (0..w * h)
+            .map(|i| [((i * 37) % 256) as f64; 4])
+            .collect::<Vec<_>>();
+        let mut img = expected.clone();
+        bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 3.0);
+
+        let (control, handle) = BlurControl::new();
+        let calls = alloc::sync::Arc::new(core::sync::atomic::AtomicUsize::new(0));
+        let counter = alloc::sync::Arc::clone(&calls);
+        let control = control.on_progress(move |completed, total| {
+            assert!(completed <= total);
+            assert_eq!(total, KERNEL9_PARAM_SET.num_kernels());
+            counter.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        });
+
+        try_bokeh_blur_controlled(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 3.0, &control).unwrap();
+
+        assert_eq!(calls.load(core::sync::atomic::Ordering::Relaxed), 9);
+        assert_eq!(handle.progress(), (9, 9));
+        assert_eq!(handle.fraction(), 1.0);
+        for (e, p) in expected.iter().zip(&img) {
+            for c in 0..4 {
+                assert!((e[c] - p[c]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn cancelled_blur_leaves_image_untouched() {
+        let (w, h) = (8, 6);
+        let original = (0..w * h)
+            .map(|i| [((i * 37) % 256) as f64; 4])
+            .collect::<Vec<_>>();
+        let mut img = original.clone();
+
+        // Cancel from the UI side part way through the blur
+        let (control, handle) = BlurControl::new();
+        let ui = handle.clone();
+        let control = control.on_progress(move |_, _| ui.cancel());
+
+        assert_eq!(
+            try_bokeh_blur_controlled(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 3.0, &control),
+            Err(BlurError::Cancelled)
+        );
+        assert!(handle.is_cancelled());
+        assert_eq!(img, original);
+    }
+
+    #[test]
+    fn controlled_blur_checks_its_input() {
+        let (control, handle) = BlurControl::new();
+        let mut img = vec![[10.0; 4]; 12];
+        assert_eq!(
+            try_bokeh_blur_controlled(&mut img, 4, 2, 2.0, &KERNEL9_PARAM_SET, 3.0, &control),
+            Err(BlurError::DimensionMismatch {
+                expected: 8,
+                got: 12
+            })
+        );
+        assert_eq!(
+            try_bokeh_blur_controlled(&mut img, 4, 3, -1.0, &KERNEL9_PARAM_SET, 3.0, &control),
+            Err(BlurError::InvalidRadius)
+        );
+        img[5][1] = f64::NAN;
+        assert_eq!(
+            try_bokeh_blur_controlled(&mut img, 4, 3, 2.0, &KERNEL9_PARAM_SET, 3.0, &control),
+            Err(BlurError::NonFiniteInput { index: 5 })
+        );
+        assert_eq!(handle.progress().0, 0);
+        assert!(img
+            .iter()
+            .flatten()
+            .filter(|c| !c.is_nan())
+            .all(|&c| c == 10.0));
+    }
+
+    #[test]
+    fn alpha_weights_the_blend() {
+        let (w, h) = (5, 5);
+        let mut img = (0..w * h)
+            .map(|i| [((i * 53) % 256) as f64, 40.0, 200.0, 255.0])
+            .collect::<Vec<_>>();
+        img[12][3] = 127.5;
+        img[6][3] = 0.0;
+        let original = img.clone();
+        let mut blurred = img.clone();
+        bokeh_blur(&mut blurred, w, h, 1.5, &KERNEL9_PARAM_SET, 2.0);
+
+        bokeh_blur_alpha_weighted(&mut img, w, h, 1.5, &KERNEL9_PARAM_SET, 2.0);
+
+        for c in 0..4 {
+            let half = 0.5 * (blurred[12][c] + original[12][c]);
+            assert!((img[12][c] - half).abs() < 1e-9);
+            assert!((img[0][c] - blurred[0][c]).abs() < 1e-9);
+        }
+        assert_eq!(img[6], original[6]);
+    }
+
+    #[test]
+    fn unit_range_matches_byte_range() {
+        let (w, h) = (6, 5);
+        let mut bytes = (0..w * h)
+            .map(|i| [((i * 71) % 256) as f64, 255.0, 0.0, 128.0])
+            .collect::<Vec<_>>();
+        let mut unit = bytes
+            .iter()
+            .map(|p| p.map(|c| c / 255.0))
+            .collect::<Vec<_>>();
+
+        bokeh_blur(&mut bytes, w, h, 2.0, &KERNEL9_PARAM_SET, 3.0);
+        bokeh_blur_unit(&mut unit, w, h, 2.0, &KERNEL9_PARAM_SET, 3.0);
+
+        for (b, u) in bytes.iter().zip(&unit) {
+            for c in 0..4 {
+                assert!((0.0..=1.0).contains(&u[c]));
+                assert!((b[c] / 255.0 - u[c]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn kernels_wider_than_the_image() {
+        // Both dimensions under the kernel radius, then just the height
+        for (w, h) in [(3, 2), (12, 2)] {
+            let mut img = (0..w * h)
+                .map(|i| [((i * 40) % 256) as f64; 4])
+                .collect::<Vec<_>>();
+
+            // Pixels outside the image count as black, so padding doesn't
+            // change the result
+            let (pad, padded_w) = (4, w + 8);
+            let mut padded = vec![[0.0; 4]; padded_w * (h + 8)];
+            for (n, p) in img.iter().enumerate() {
+                padded[(n / w + pad) * padded_w + n % w + pad] = *p;
+            }
+
+            bokeh_blur(&mut img, w, h, 4.0, &KERNEL9_PARAM_SET, 3.0);
+            bokeh_blur(&mut padded, padded_w, h + 8, 4.0, &KERNEL9_PARAM_SET, 3.0);
+
+            for (n, p) in img.iter().enumerate() {
+                let q = padded[(n / w + pad) * padded_w + n % w + pad];
+                for c in 0..4 {
+                    assert!((p[c] - q[c]).abs() < 1e-9, "{w}x{h}");
+                }
+            }
+        }
+
+        // A single pixel surrounded by copies of itself stays the same
+        for edge_mode in [EdgeMode::Clamp, EdgeMode::Reflect, EdgeMode::Wrap] {
+            let mut img = vec![[100.0, 150.0, 200.0, 255.0]];
+            bokeh_blur_with_edge_mode(&mut img, 1, 1, 10.0, &KERNEL9_PARAM_SET, 2.2, edge_mode);
+            for (a, b) in img[0].iter().zip([100.0, 150.0, 200.0, 255.0]) {
+                assert!((a - b).abs() < 1e-9, "{edge_mode:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn unswirled_blur_is_circular() {
+        let (w, h) = (17, 12);
+        let mut expected = (0..w * h)
+            .map(|i| [((i * 83) % 256) as f64; 4])
+            .collect::<Vec<_>>();
+        let mut img = expected.clone();
+
+        bokeh_blur(&mut expected, w, h, 2.5, &KERNEL9_PARAM_SET, 3.0);
+        bokeh_blur_swirl(&mut img, w, h, 2.5, &KERNEL9_PARAM_SET, 3.0, 0.0);
+
+        for (e, p) in expected.iter().zip(&img) {
+            for c in 0..4 {
+                assert!((e[c] - p[c]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn swirl_stretches_tangentially() {
+        use crate::params::KERNEL1_PARAM_SET;
+
+        // Impulse to the right of the centre, where the tangent is vertical
+        let (w, h) = (41, 41);
+        let mut img = vec![[0.0; 4]; w * h];
+        img[20 * w + 36] = [255.0; 4];
+
+        bokeh_blur_swirl(&mut img, w, h, 3.0, &KERNEL1_PARAM_SET, 1.0, 2.0);
+
+        let (mut across, mut down) = (0.0, 0.0);
+        for (n, p) in img.iter().enumerate() {
+            let (dx, dy) = ((n % w) as f64 - 36.0, (n / w) as f64 - 20.0);
+            across += p[0] * dx * dx;
+            down += p[0] * dy * dy;
+        }
+        assert!(down > 1.5 * across);
+    }
+
+    #[test]
+    fn sampled_kernel_is_symmetric_with_a_flat_top() {
+        use crate::params::{KERNEL1_PARAM_SET, KERNEL4_PARAM_SET};
+
+        for param_set in [&KERNEL1_PARAM_SET, &KERNEL4_PARAM_SET, &KERNEL9_PARAM_SET] {
+            let profile = sample_combined_kernel(param_set, 10.0, 201);
+            for (a, b) in profile.iter().zip(profile.iter().rev()) {
+                assert!((a - b).abs() < 1e-12);
+            }
+        }
+
+        // The top of the disc is flat up to the ripples of the approximation
+        let profile = sample_combined_kernel(&KERNEL9_PARAM_SET, 10.0, 201);
+        let (centre, peak) = (
+            profile[100],
+            profile.iter().fold(0.0, |a: f64, &p| a.max(p)),
+        );
+        assert!(centre > 0.999 * peak);
+        assert!(profile[0].abs() < 0.01 * centre);
+    }
+
+    #[test]
+    fn sampled_kernel_matches_taps() {
+        let kernels = kernel_gaussian_components(&KERNEL9_PARAM_SET, 5.0);
+        let profile = sample_combined_kernel(&KERNEL9_PARAM_SET, 5.0, 11);
+
+        for (m, p) in profile.iter().enumerate() {
+            let tap = kernels.iter().enumerate().fold(0.0, |acc, (n, k)| {
+                acc + KERNEL9_PARAM_SET.real_component(n) * k[m].re
+                    + KERNEL9_PARAM_SET.imag_component(n) * k[m].im
+            });
+            assert!((p - tap).abs() < 1e-12);
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn masked_blur_diff_is_inside_mask() {
+        use image::{DynamicImage, GenericImageView, RgbaImage};
+
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 6, |x, y| {
+            image::Rgba([((x * 97 + y * 31) % 256) as u8, (y * 40) as u8, 100, 255])
+        }));
+        let mask = (0..48)
+            .map(|n| (2..5).contains(&(n % 8)) && n / 8 >= 3)
+            .collect::<Vec<_>>();
+
+        let mut blurred = img.clone();
+        dynamic_image::bokeh_blur_with_mask(&mut blurred, &mask, 1.5, &KERNEL9_PARAM_SET, 3.0);
+        let heatmap = dynamic_image::diff_heatmap(&img, &blurred);
+
+        assert_eq!(heatmap.dimensions(), (8, 6));
+        for ((_, _, p), &m) in heatmap.pixels().zip(&mask) {
+            if !m {
+                assert_eq!(p.0[0], 0);
+            }
+        }
+        assert!(heatmap.pixels().any(|(_, _, p)| p.0[0] == 255));
+        assert!(
+            dynamic_image::bokeh_blur_diff(&img, 1.5, &KERNEL9_PARAM_SET, 3.0)
+                .pixels()
+                .any(|(_, _, p)| p.0[0] > 0)
+        );
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn hdr_highlights_bloom_by_intensity() {
+        use image::codecs::hdr::HdrEncoder;
+        use image::{GenericImageView, Rgb};
+
+        // A dim and a ten times brighter highlight, well apart
+        let (w, h) = (24, 11);
+        let mut data = vec![Rgb([0.0f32; 3]); w * h];
+        data[5 * w + 5] = Rgb([1.0; 3]);
+        data[5 * w + 17] = Rgb([10.0; 3]);
+        let mut encoded = Vec::new();
+        HdrEncoder::new(&mut encoded).encode(&data, w, h).unwrap();
+
+        let mut img = dynamic_image::read_hdr(encoded.as_slice()).unwrap();
+        dynamic_image::bokeh_blur_linear(&mut img, 2.0, &KERNEL9_PARAM_SET);
+
+        let img = img.as_rgb32f().unwrap();
+        let (dim, bright) = (img.get_pixel(6, 5).0[0], img.get_pixel(18, 5).0[0]);
+        assert!(dim > 0.0);
+        assert!((bright / dim - 10.0).abs() < 0.1);
+        let total = |x0: u32| {
+            (x0 - 3..=x0 + 3)
+                .flat_map(|x| (2..=8).map(move |y| (x, y)))
+                .map(|(x, y)| img.get_pixel(x, y).0[1])
+                .sum::<f32>()
+        };
+        assert!((total(5) - 1.0).abs() < 0.01);
+        assert!((total(17) - 10.0).abs() < 0.1);
+
+        let ldr = dynamic_image::tone_map_hdr(&img.clone().into(), ToneMap::Clamp, 1.0);
+        assert_eq!(ldr.dimensions(), (w as u32, h as u32));
+        assert_eq!(ldr.get_pixel(17, 5).0, [255; 4]);
+    }
+
+    #[test]
+    fn recommended_kernel_radius_is_the_default() {
+        assert_eq!(recommended_kernel_radius(0.0), 0);
+        assert_eq!(recommended_kernel_radius(2.0), 2);
+        assert_eq!(recommended_kernel_radius(2.1), 3);
+
+        let original = crate::testing::gradient_image(9, 8);
+        let mut img = original.clone();
+        bokeh_blur_with_kernel_radius(&mut img, 9, 8, 2.5, 3, &KERNEL9_PARAM_SET, 3.0);
+        let mut expected = original.clone();
+        bokeh_blur(&mut expected, 9, 8, 2.5, &KERNEL9_PARAM_SET, 3.0);
+        for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+
+        let mut wider = original;
+        bokeh_blur_with_kernel_radius(&mut wider, 9, 8, 2.5, 6, &KERNEL9_PARAM_SET, 3.0);
+        assert_ne!(wider, img);
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn sixteen_bit_images_keep_their_precision() {
+        use image::{DynamicImage, ImageBuffer, Rgba};
+
+        let (w, h) = (7, 5);
+        let buf = ImageBuffer::from_fn(w, h, |x, y| {
+            Rgba([
+                (x * 9000 + y * 301) as u16,
+                40000,
+                (y * 12345) as u16,
+                65535,
+            ])
+        });
+        let mut pixels = buf.pixels().map(|p| p.0.map(f64::from)).collect::<Vec<_>>();
+        bokeh_blur_with_max_value(&mut pixels, 7, 5, 1.0, &KERNEL9_PARAM_SET, 2.0, 65535.0);
+
+        let mut img = DynamicImage::ImageRgba16(buf.clone());
+        dynamic_image::bokeh_blur(&mut img, 1.0, &KERNEL9_PARAM_SET, 2.0);
+        let blurred = img.as_rgba16().unwrap();
+        for (p, expected) in blurred.pixels().zip(&pixels) {
+            for (&c, e) in p.0.iter().zip(expected) {
+                assert!((c as f64 - e).abs() <= 1.0);
+            }
+        }
+        assert!(blurred.pixels().any(|p| p.0[0] % 257 != 0));
+
+        let mut rgb = DynamicImage::ImageRgb16(DynamicImage::ImageRgba16(buf).into_rgb16());
+        dynamic_image::bokeh_blur(&mut rgb, 1.0, &KERNEL9_PARAM_SET, 2.0);
+        assert!(rgb.as_rgb16().is_some());
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn masked_sixteen_bit_images_keep_their_precision() {
+        use image::{DynamicImage, ImageBuffer, Luma, Rgba};
+
+        let (w, h) = (9, 5);
+        let buf = ImageBuffer::from_fn(w, h, |x, y| {
+            Rgba([
+                (x * 7000 + y * 301) as u16,
+                40000,
+                (y * 12345) as u16,
+                65535,
+            ])
+        });
+        let mask = (0..w * h).map(|i| i % w < 4).collect::<Vec<_>>();
+        let mut pixels = buf.pixels().map(|p| p.0.map(f64::from)).collect::<Vec<_>>();
+        bokeh_blur_with_max_value(&mut pixels, 9, 5, 1.0, &KERNEL9_PARAM_SET, 2.0, 65535.0);
+
+        let mut img = DynamicImage::ImageRgba16(buf.clone());
+        dynamic_image::bokeh_blur_with_mask(&mut img, &mask, 1.0, &KERNEL9_PARAM_SET, 2.0);
+        let blurred = img.as_rgba16().unwrap();
+        for ((p, original), (expected, &m)) in blurred
+            .pixels()
+            .zip(buf.pixels())
+            .zip(pixels.iter().zip(&mask))
+        {
+            if m {
+                for (&c, e) in p.0.iter().zip(expected) {
+                    assert!((c as f64 - e).abs() <= 1.0);
+                }
+            } else {
+                assert_eq!(p, original);
+            }
+        }
+        assert!(blurred.pixels().any(|p| p.0[0] % 257 != 0));
+
+        let grey = ImageBuffer::from_fn(w, h, |x, y| Luma([(x * 7001 + y * 3) as u16]));
+        let mut img = DynamicImage::ImageLuma16(grey.clone());
+        dynamic_image::bokeh_blur_with_mask(&mut img, &mask, 1.0, &KERNEL9_PARAM_SET, 2.0);
+        let blurred = img.as_luma16().unwrap();
+        for ((p, original), &m) in blurred.pixels().zip(grey.pixels()).zip(&mask) {
+            assert!(m || p == original);
+        }
+        assert!(blurred
+            .pixels()
+            .zip(&mask)
+            .any(|(p, &m)| m && p.0[0] % 257 != 0));
+    }
+
+    #[test]
+    fn f32_matches_f64() {
+        let (w, h) = (11, 9);
+        let mut img = crate::testing::gradient_image(w, h);
+        let mut img_f32 = img.iter().map(|p| p.map(|c| c as f32)).collect::<Vec<_>>();
+
+        bokeh_blur(&mut img, w, h, 3.0, &KERNEL9_PARAM_SET, 3.0);
+        bokeh_blur_f32(&mut img_f32, w, h, 3.0, &KERNEL9_PARAM_SET, 3.0);
+
+        for (a, b) in img.iter().flatten().zip(img_f32.iter().flatten()) {
+            assert!((a - *b as f64).abs() < 1e-2, "{a} != {b}");
+        }
+
+        // Negative ringing next to black pixels is clamped before the gamma
+        let (w, h) = (6, 5);
+        let mut img = crate::testing::gradient_image(w, h)
+            .iter()
+            .map(|p| p.map(|c| c as f32))
+            .collect::<Vec<_>>();
+        bokeh_blur_f32(&mut img, w, h, 1.0 / 3.0, &KERNEL9_PARAM_SET, 2.2);
+        assert!(img.iter().flatten().all(|c| (0.0..=255.0).contains(c)));
+    }
+
+    #[test]
+    fn premultiplied_blur_ignores_transparent_colours() {
+        let (w, h) = (10, 6);
+        // Opaque red on the left, transparent green on the right
+        let mut img = (0..w * h)
+            .map(|n| {
+                if n % w < w / 2 {
+                    [255.0, 0.0, 0.0, 255.0]
+                } else {
+                    [0.0, 255.0, 0.0, 0.0]
+                }
+            })
+            .collect::<Vec<_>>();
+
+        bokeh_blur_premultiplied(&mut img, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+
+        for p in &img {
+            assert!(p[1] < 1e-9, "{p:?}");
+            assert!(p[3] <= 0.0 || (p[0] - 255.0).abs() < 1e-6, "{p:?}");
+        }
+        assert!(img[w / 2][3] > 0.0);
+
+        let original = crate::testing::gradient_image(w, h)
+            .into_iter()
+            .map(|p| [p[0], p[1], p[2], 255.0])
+            .collect::<Vec<_>>();
+        let mut premultiplied = original.clone();
+        bokeh_blur_premultiplied(&mut premultiplied, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        let mut straight = original;
+        bokeh_blur(&mut straight, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        // Away from the edges, where the zero padding makes the alpha fall off
+        for (a, b) in premultiplied.iter().zip(&straight) {
+            if b[3] > 255.0 - 1e-6 {
+                for c in 0..4 {
+                    assert!((a[c] - b[c]).abs() < 1e-6);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bloom_only_boosts_highlights() {
+        let (w, h) = (11, 11);
+        let dim = crate::testing::gradient_image(w, h)
+            .into_iter()
+            .map(|p| p.map(|c| c * 0.5))
+            .collect::<Vec<_>>();
+        let mut bloomed = dim.clone();
+        bokeh_blur_with_bloom(&mut bloomed, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0, 200.0, 3.0);
+        let mut plain = dim;
+        bokeh_blur(&mut plain, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        for (a, b) in bloomed.iter().flatten().zip(plain.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+
+        let mut highlight = vec![[10.0, 10.0, 10.0, 255.0]; w * h];
+        highlight[5 * w + 5] = [255.0; 4];
+        let mut bloomed = highlight.clone();
+        bokeh_blur_with_bloom(&mut bloomed, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0, 200.0, 3.0);
+        let mut plain = highlight;
+        bokeh_blur(&mut plain, w, h, 2.0, &KERNEL9_PARAM_SET, 1.0);
+        assert!(bloomed[5 * w + 6][0] > plain[5 * w + 6][0] + 1.0);
+        assert!(bloomed.iter().flatten().all(|c| (0.0..=255.0).contains(c)));
+    }
+
+    #[test]
+    fn higher_accuracy_uses_larger_kernels() {
+        let support = |accuracy| kernel_support_for_energy(&KERNEL9_PARAM_SET, 20.0, accuracy);
+        assert!(support(0.8) < support(0.95));
+        assert!(support(0.95) < support(0.9999));
+        assert!(support(1.0) >= support(0.9999));
+
+        let (w, h) = (40, 40);
+        let original = (0..w * h)
+            .map(|i| [((i * 89) % 256) as f64; 4])
+            .collect::<Vec<_>>();
+        let blur = |accuracy| {
+            let mut img = original.clone();
+            bokeh_blur_budget(&mut img, w, h, 20.0, &KERNEL9_PARAM_SET, 3.0, accuracy);
+            img
+        };
+        let reference = blur(1.0);
+        let error = |accuracy| {
+            blur(accuracy)
+                .iter()
+                .zip(&reference)
+                .map(|(a, b)| (a[0] - b[0]).abs())
+                .sum::<f64>()
+        };
+
+        assert!(error(0.95) < error(0.8));
+        assert!(error(0.9999) < error(0.95));
+    }
+
+    #[test]
+    fn rgb_matches_rgba() {
+        let (w, h) = (7, 5);
+        let mut rgba = (0..w * h)
+            .map(|i| [(i * 13 % 256) as f64, (i * 71 % 256) as f64, 90.0, 255.0])
+            .collect::<Vec<_>>();
+        let mut rgb = rgba.iter().map(|p| [p[0], p[1], p[2]]).collect::<Vec<_>>();
+
+        bokeh_blur(&mut rgba, w, h, 2.0, &KERNEL9_PARAM_SET, 3.0);
+        bokeh_blur_rgb(&mut rgb, w, h, 2.0, &KERNEL9_PARAM_SET, 3.0);
+
+        for (a, b) in rgba.iter().zip(&rgb) {
+            for c in 0..3 {
+                assert!((a[c] - b[c]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn blurs_any_number_of_channels() {
+        let (w, h) = (9, 9);
+        let mut expected = vec![[0.0; 4]; w * h];
+        expected[4 * w + 4] = [255.0; 4];
+        bokeh_blur(&mut expected, w, h, 1.0, &KERNEL9_PARAM_SET, 3.0);
+
+        // 8 spectral bands, with the impulse at different strengths per band
+        let mut bands = vec![[0.0; 8]; w * h];
+        bands[4 * w + 4] = [255.0, 0.0, 255.0, 100.0, 255.0, 0.0, 255.0, 255.0];
+        bokeh_blur_channels(&mut bands, w, h, 1.0, &KERNEL9_PARAM_SET, 3.0);
+
+        let mut scaled = vec![[0.0; 4]; w * h];
+        scaled[4 * w + 4] = [100.0; 4];
+        bokeh_blur(&mut scaled, w, h, 1.0, &KERNEL9_PARAM_SET, 3.0);
+
+        for ((b, e), s) in bands.iter().zip(&expected).zip(&scaled) {
+            for c in [0, 2, 4, 6, 7] {
+                assert!((b[c] - e[0]).abs() < 1e-9);
+            }
+            assert_eq!(b[1], 0.0);
+            assert_eq!(b[5], 0.0);
+            assert!((b[3] - s[0]).abs() < 1e-9);
+        }
+
+        let mut grey = vec![0.0; w * h];
+        grey[4 * w + 4] = 255.0;
+        bokeh_blur_grey(&mut grey, w, h, 1.0, &KERNEL9_PARAM_SET, 3.0);
+        for (g, e) in grey.iter().zip(&expected) {
+            assert!((g - e[0]).abs() < 1e-9);
+        }
+
+        let mut two = vec![[0.0; 2]; w * h];
+        two[4 * w + 4] = [255.0, 255.0];
+        bokeh_blur_channels(&mut two, w, h, 1.0, &KERNEL9_PARAM_SET, 3.0);
+        for (t, e) in two.iter().zip(&expected) {
+            assert!((t[0] - e[0]).abs() < 1e-9 && (t[1] - e[1]).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn edge_modes_keep_bright_borders() {
+        let (w, h) = (10, 8);
+        let blur = |edge_mode| {
+            let mut img = vec![[200.0; 4]; w * h];
+            bokeh_blur_with_edge_mode(&mut img, w, h, 3.0, &KERNEL9_PARAM_SET, 3.0, edge_mode);
+            img
+        };
+
+        assert!(blur(EdgeMode::Zero)[0][0] < 150.0);
+        for edge_mode in [
+            EdgeMode::Clamp,
+            EdgeMode::Reflect,
+            EdgeMode::Wrap,
+            EdgeMode::ConstantColor([200.0; 4]),
+            EdgeMode::Renormalize,
+        ] {
+            for p in blur(edge_mode) {
+                assert!((p[0] - 200.0).abs() < 1e-6);
+            }
         }
     }
+
+    #[test]
+    fn constant_colour_matches_padding_the_image() {
+        let (w, h, pad) = (7, 6, 4);
+        let color = [40.0, 90.0, 250.0, 255.0];
+        let original = (0..w * h)
+            .map(|i| [((i * 67) % 256) as f64; 4])
+            .collect::<Vec<_>>();
+
+        // Surround the image with the colour, past the reach of the kernel
+        let (pw, ph) = (w + 2 * pad, h + 2 * pad);
+        let mut padded = (0..pw * ph)
+            .map(|n| {
+                let (x, y) = (n % pw, n / pw);
+                if (pad..pad + w).contains(&x) && (pad..pad + h).contains(&y) {
+                    original[(y - pad) * w + x - pad]
+                } else {
+                    color
+                }
+            })
+            .collect::<Vec<_>>();
+        bokeh_blur(&mut padded, pw, ph, 3.0, &KERNEL9_PARAM_SET, 2.2);
+
+        let mut img = original.clone();
+        bokeh_blur_with_edge_mode(
+            &mut img,
+            w,
+            h,
+            3.0,
+            &KERNEL9_PARAM_SET,
+            2.2,
+            EdgeMode::ConstantColor(color),
+        );
+        for (n, p) in img.iter().enumerate() {
+            let expected = padded[(n / w + pad) * pw + n % w + pad];
+            for c in 0..4 {
+                assert!((p[c] - expected[c]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn wrapped_blur_commutes_with_shifts() {
+        let (w, h) = (9, 7);
+        let original = (0..w * h)
+            .map(|i| [((i * 67) % 256) as f64; 4])
+            .collect::<Vec<_>>();
+        // Shifted 4 pixels right and 3 down, wrapping around
+        let shift = |img: &[[f64; 4]]| {
+            (0..w * h)
+                .map(|n| img[((n / w + h - 3) % h) * w + (n % w + w - 4) % w])
+                .collect::<Vec<_>>()
+        };
+
+        let mut blurred = original.clone();
+        bokeh_blur_with_edge_mode(
+            &mut blurred,
+            w,
+            h,
+            2.0,
+            &KERNEL9_PARAM_SET,
+            1.0,
+            EdgeMode::Wrap,
+        );
+        let mut shifted = shift(&original);
+        bokeh_blur_with_edge_mode(
+            &mut shifted,
+            w,
+            h,
+            2.0,
+            &KERNEL9_PARAM_SET,
+            1.0,
+            EdgeMode::Wrap,
+        );
+
+        for (a, b) in shift(&blurred).iter().zip(&shifted) {
+            assert!((a[0] - b[0]).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn zero_edge_mode_is_plain_blur() {
+        let (w, h) = (6, 6);
+        let mut expected = (0..w * h)
+            .map(|i| [((i * 67) % 256) as f64; 4])
+            .collect::<Vec<_>>();
+        let mut img = expected.clone();
+
+        bokeh_blur(&mut expected, w, h, 1.0, &KERNEL9_PARAM_SET, 3.0);
+        bokeh_blur_with_edge_mode(&mut img, w, h, 1.0, &KERNEL9_PARAM_SET, 3.0, EdgeMode::Zero);
+
+        for (e, p) in expected.iter().zip(&img) {
+            for c in 0..4 {
+                assert!((e[c] - p[c]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_dimensions_are_errors() {
+        let original = vec![[100.0; 4]; 12];
+        let mut img = original.clone();
+
+        assert_eq!(
+            try_bokeh_blur(&mut img, 5, 3, 1.0, &KERNEL9_PARAM_SET, 3.0),
+            Err(BlurError::DimensionMismatch {
+                expected: 15,
+                got: 12
+            })
+        );
+        for radius in [-1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(
+                try_bokeh_blur(&mut img, 4, 3, radius, &KERNEL9_PARAM_SET, 3.0),
+                Err(BlurError::InvalidRadius)
+            );
+        }
+        assert_eq!(
+            try_bokeh_blur_with_mask(&mut img, &[true; 10], 5, 2, 1.0, &KERNEL9_PARAM_SET, 3.0),
+            Err(BlurError::DimensionMismatch {
+                expected: 10,
+                got: 12
+            })
+        );
+        assert_eq!(img, original);
+
+        // Kernels wider than the image are fine
+        assert_eq!(
+            try_bokeh_blur(&mut img, 4, 3, 10.0, &KERNEL9_PARAM_SET, 3.0),
+            Ok(())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "image has 12 pixels")]
+    fn bad_dimensions_panic() {
+        bokeh_blur(&mut [[0.0; 4]; 12], 5, 3, 1.0, &KERNEL9_PARAM_SET, 3.0);
+    }
 }
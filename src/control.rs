@@ -0,0 +1,114 @@
+//! Progress reporting and cancellation of running blurs.
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+type ProgressCallback = Box<dyn Fn(usize, usize) + Send + Sync>;
+
+#[derive(Default)]
+struct Shared {
+    cancelled: AtomicBool,
+    completed: AtomicUsize,
+    total: AtomicUsize,
+}
+
+/// Passed into a blur to report its progress and allow it to be cancelled, see
+/// [`crate::try_bokeh_blur_controlled`].
+///
+/// Created with a [`BlurHandle`] by [`BlurControl::new`]. The blur reads the
+/// control while the handle is kept by, e.g., a UI thread to watch progress
+/// and cancel the blur.
+///
+/// # Threading
+///
+/// Both the control and the handle are [`Send`] and [`Sync`], and the handle
+/// can be cloned to share it between threads. The blur polls for cancellation
+/// before and after each kernel component is convolved, so a cancelled blur
+/// returns once the passes already running finish; a cancelled blur never
+/// modifies its image. Progress is updated after each component, from
+/// whichever worker thread convolved it, so a callback passed to
+/// [`BlurControl::on_progress`] must be cheap and must not block. A control
+/// should only be used by one blur at a time, and its progress is reset at the
+/// start of each blur. Once cancelled it stays cancelled.
+pub struct BlurControl {
+    shared: Arc<Shared>,
+    on_progress: Option<ProgressCallback>,
+}
+
+/// The other half of a [`BlurControl`], used to watch and cancel a blur from
+/// another thread.
+#[derive(Clone)]
+pub struct BlurHandle {
+    shared: Arc<Shared>,
+}
+
+impl BlurControl {
+    /// Creates a new control and the handle connected to it
+    pub fn new() -> (Self, BlurHandle) {
+        let shared = Arc::new(Shared::default());
+        (
+            Self {
+                shared: Arc::clone(&shared),
+                on_progress: None,
+            },
+            BlurHandle { shared },
+        )
+    }
+
+    /// Calls `callback` with the number of completed and total passes each
+    /// time the blur makes progress.
+    ///
+    /// The callback runs on the blur's worker threads, see the
+    /// [threading contract](BlurControl#threading).
+    pub fn on_progress(mut self, callback: impl Fn(usize, usize) + Send + Sync + 'static) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Resets the progress at the start of a blur of `total` passes
+    pub(crate) fn start(&self, total: usize) {
+        self.shared.total.store(total, Ordering::Relaxed);
+        self.shared.completed.store(0, Ordering::Relaxed);
+    }
+
+    /// Records that another pass has completed
+    pub(crate) fn step(&self) {
+        let completed = self.shared.completed.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(callback) = &self.on_progress {
+            callback(completed, self.shared.total.load(Ordering::Relaxed));
+        }
+    }
+}
+
+impl BlurHandle {
+    /// Asks the blur to stop as soon as possible
+    pub fn cancel(&self) {
+        self.shared.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether [`BlurHandle::cancel`] has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// The number of completed and total passes of the running blur
+    pub fn progress(&self) -> (usize, usize) {
+        (
+            self.shared.completed.load(Ordering::Relaxed),
+            self.shared.total.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Fraction of the running blur which has completed, between `0.0` and
+    /// `1.0`
+    pub fn fraction(&self) -> f64 {
+        match self.progress() {
+            (_, 0) => 0.0,
+            (completed, total) => completed as f64 / total as f64,
+        }
+    }
+}
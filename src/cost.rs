@@ -0,0 +1,84 @@
+//! Estimating the cost of a blur before running it.
+use crate::parallel::thread_count;
+use crate::params::KernelParamSet;
+use core::mem::size_of;
+use num::Complex;
+
+/// The approximate cost of a blur, see [`estimate_cost`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlurCost {
+    /// Most bytes allocated at once while convolving, not counting the image
+    /// itself
+    pub peak_bytes: usize,
+    /// Number of complex multiply-adds, one for each channel of each pixel
+    /// under each tap of each 1-D kernel
+    pub multiply_adds: u64,
+}
+
+/// Estimates the memory and time a blur of a `width` by `height` image takes,
+/// without running it, e.g. to turn away jobs that are too large.
+///
+/// The image is copied into complex numbers, then each component of
+/// `param_set` is convolved by a horizontal and a vertical 1-D kernel of
+/// length `2 * kernel_radius + 1`, through two complex buffers the size of the
+/// image, and its real part summed into a buffer of real numbers. With the
+/// `rayon` feature as many components are convolved at once as there are
+/// threads, see [`crate::thread_count`], each with its own buffers and its
+/// result held until it is summed. The work is the same however many threads
+/// are used.
+///
+/// To estimate [`crate::bokeh_blur`], pass the
+/// [`crate::recommended_kernel_radius`] of its radius. The kernels themselves
+/// are left out as they're tiny next to the image, and fewer taps are used
+/// near the edges of the image, so the work is an upper bound.
+pub fn estimate_cost(
+    width: usize,
+    height: usize,
+    param_set: &KernelParamSet,
+    kernel_radius: usize,
+) -> BlurCost {
+    let pixels = width * height;
+    let components = param_set.num_kernels();
+    let complex_image = pixels * size_of::<[Complex<f64>; 4]>();
+    let real_image = pixels * size_of::<[f64; 4]>();
+
+    // Each thread's pair of complex buffers and running sum
+    let scratch = 2 * complex_image + real_image;
+    #[cfg(feature = "rayon")]
+    let peak_bytes = {
+        let concurrent = thread_count().min(components).max(1);
+        complex_image + concurrent * (scratch + real_image) + real_image
+    };
+    #[cfg(not(feature = "rayon"))]
+    let peak_bytes = complex_image + thread_count() * scratch;
+
+    let kernel_len = 2 * kernel_radius as u64 + 1;
+    BlurCost {
+        peak_bytes,
+        multiply_adds: 2 * 4 * pixels as u64 * kernel_len * components as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::{KERNEL1_PARAM_SET, KERNEL5_PARAM_SET};
+
+    #[test]
+    fn grows_with_the_image_and_kernel() {
+        let cost = estimate_cost(10, 10, &KERNEL5_PARAM_SET, 3);
+        assert_eq!(cost.multiply_adds, 2 * 4 * 100 * 7 * 5);
+        assert_eq!(
+            estimate_cost(20, 10, &KERNEL5_PARAM_SET, 3).peak_bytes,
+            2 * cost.peak_bytes
+        );
+        assert_eq!(
+            estimate_cost(10, 10, &KERNEL1_PARAM_SET, 3).multiply_adds,
+            cost.multiply_adds / 5
+        );
+
+        // At least the complex copy of the image and a thread's buffers
+        assert!(cost.peak_bytes >= 100 * (3 * 64 + 32));
+        assert_eq!(estimate_cost(0, 10, &KERNEL5_PARAM_SET, 3).peak_bytes, 0);
+    }
+}
@@ -0,0 +1,464 @@
+//! Depth of field, blurring each pixel by how far it is from the focal plane.
+use crate::complex::{blur_region, collect_mask, is_identity, ComplexImage};
+use crate::mask::mask_bounds;
+use crate::params::KernelParamSet;
+use crate::plan::KernelPlan;
+use alloc::vec::Vec;
+
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use num::Float;
+
+/// Number of blur radii, including the unblurred focal plane, that
+/// [`bokeh_blur_with_depth`] composites between
+const DEPTH_LAYERS: usize = 8;
+
+/// Coverage of a layer below which [`bokeh_blur_layered`] treats it as absent
+const MIN_COVERAGE: f64 = 1e-6;
+
+/// Blurs an image using an approximation of a disc-shaped kernel whose radius
+/// depends on the depth of each pixel, producing a depth of field effect.
+///
+/// Takes a `depth` of the same length as the image, where depths are expected
+/// to be between `0.0` and `1.0`. Each pixel is blurred with radius
+/// `|depth - focal_depth| * max_radius`, capped at `max_radius`, so pixels at
+/// `focal_depth` are left unchanged and those furthest from it are blurred the
+/// most.
+///
+/// Convolving with a different kernel for every pixel would break the
+/// separability of the kernel, so instead the image is blurred at 7 evenly
+/// spaced radii up to `max_radius`. Each pixel is linearly interpolated
+/// between the two radii either side of its own, with the unblurred image as
+/// the radius `0.0`, which keeps the transitions between radii free of
+/// banding. Only the parts of the image needing each radius are convolved.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by disc-shaped kernels built from
+/// components corresponding to `param_set`. The exposure can be modified using
+/// `gamma`, set to `1.0` for no change.
+#[allow(clippy::too_many_arguments)]
+pub fn bokeh_blur_with_depth(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    depth: &[f64],
+    focal_depth: f64,
+    max_radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    debug_assert_eq!(
+        depth.len(),
+        width * height,
+        "depth length doesn't match the {} pixels of the image",
+        width * height
+    );
+    let step = max_radius / (DEPTH_LAYERS - 1) as f64;
+    if !step.is_finite() || step <= 0.0 {
+        return;
+    }
+
+    // Position of each pixel between the layers, e.g. `2.5` is halfway between
+    // the second and third blur radii
+    let positions = depth
+        .iter()
+        .map(|d| ((d - focal_depth).abs() * max_radius).min(max_radius) / step)
+        .collect::<Vec<_>>();
+
+    // Weight the unblurred layer, leaving pixels in focus untouched
+    let original = img.to_vec();
+    for (pixel, &position) in img.iter_mut().zip(&positions) {
+        if position > 0.0 {
+            *pixel = pixel.map(|c| c * (1.0 - position).max(0.0));
+        }
+    }
+
+    for layer in 1..DEPTH_LAYERS {
+        let centre = layer as f64;
+        let mask = positions
+            .iter()
+            .map(|&p| p > centre - 1.0 && p < centre + 1.0)
+            .collect::<Vec<_>>();
+        let Some(region) = mask_bounds(&mask, width, height) else {
+            continue;
+        };
+
+        for (n, rgba) in blur_region(
+            &original,
+            width,
+            height,
+            region,
+            centre * step,
+            param_set,
+            gamma,
+        )
+        .into_iter()
+        .enumerate()
+        {
+            let i = region.pixel_index(n, width);
+            if !mask[i] {
+                continue;
+            }
+
+            let weight = 1.0 - (positions[i] - centre).abs();
+            // Clamp any values from floating point ops
+            let blurred = rgba.map(|c| c.max(0.0).powf(1.0 / gamma).min(255.0));
+            for (p, b) in img[i].iter_mut().zip(blurred) {
+                *p += weight * b;
+            }
+        }
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel whose radius
+/// grows with the distance from a point of focus, a cheap portrait effect
+/// without a depth map.
+///
+/// Pixels within `inner_radius` pixels of `focus`, given as the `(x, y)`
+/// position in pixels, are left exactly as they are. Beyond that the radius of
+/// the blur ramps up to `max_blur` at the corner of the image furthest from
+/// the focus. The ramp eases in and out, so there is no visible edge where
+/// the blur starts or where it reaches its full radius. The pixels are
+/// composited between blurs at a few radii as [`bokeh_blur_with_depth`], with
+/// the distance along the ramp as the depth.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by disc-shaped kernels built from
+/// components corresponding to `param_set`. The exposure can be modified using
+/// `gamma`, set to `1.0` for no change.
+#[allow(clippy::too_many_arguments)]
+pub fn bokeh_blur_radial(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    focus: (f64, f64),
+    inner_radius: f64,
+    max_blur: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    let (fx, fy) = focus;
+    let distance = |x: f64, y: f64| (x - fx).hypot(y - fy);
+    let (right, bottom) = (
+        width.saturating_sub(1) as f64,
+        height.saturating_sub(1) as f64,
+    );
+    let furthest = [(0.0, 0.0), (right, 0.0), (0.0, bottom), (right, bottom)]
+        .into_iter()
+        .map(|(x, y)| distance(x, y))
+        .fold(0.0, f64::max);
+    if furthest <= inner_radius {
+        return;
+    }
+
+    let depth = (0..width * height)
+        .map(|n| {
+            let d = distance((n % width) as f64, (n / width) as f64);
+            let t = ((d - inner_radius) / (furthest - inner_radius)).clamp(0.0, 1.0);
+            // Smoothstep, so the ramp starts and ends without a kink
+            t * t * (3.0 - 2.0 * t)
+        })
+        .collect::<Vec<_>>();
+
+    bokeh_blur_with_depth(img, width, height, &depth, 0.0, max_blur, param_set, gamma);
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel whose radius
+/// is the circle of confusion of a lens focused at `focal_distance`, simulating
+/// the depth of field of a camera from a depth map.
+///
+/// Takes a `depth_map` of the same length as the image, holding the distance
+/// of each pixel from the camera in the same units as `focal_distance`, e.g.
+/// metres. A thin lens blurs a point at distance `d` into a disc whose radius
+/// is proportional to `|d - focal_distance| / d`, so the blur grows quickly in
+/// front of the focal plane and levels off behind it, towards `aperture`
+/// pixels for the far distance. The radius is capped at `aperture`, which
+/// anything nearer than half the focal distance reaches, and distances that
+/// aren't positive are treated as that near. Unlike the linear ramp of
+/// [`bokeh_blur_with_depth`] a subject stands out from a distant background
+/// while the foreground in front of it is blurred more strongly. The pixels
+/// are composited between blurs at a few radii as [`bokeh_blur_with_depth`].
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by disc-shaped kernels built from
+/// components corresponding to `param_set`. The exposure can be modified using
+/// `gamma`, set to `1.0` for no change.
+#[allow(clippy::too_many_arguments)]
+pub fn bokeh_blur_with_depth_map(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    depth_map: &[f64],
+    focal_distance: f64,
+    aperture: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    // The circle of confusion of each pixel, as a fraction of the aperture
+    let depth = depth_map
+        .iter()
+        .map(|&d| {
+            if d > 0.0 {
+                ((d - focal_distance).abs() / d).min(1.0)
+            } else {
+                1.0
+            }
+        })
+        .collect::<Vec<_>>();
+
+    bokeh_blur_with_depth(img, width, height, &depth, 0.0, aperture, param_set, gamma);
+}
+
+/// Blurs the near and far parts of an image separately using an approximation
+/// of a disc-shaped kernel, compositing the far layer under the near layer.
+///
+/// Takes a `near` mask of the same length as the image, where `true`'s
+/// correspond to the near layer, e.g. the subject, and `false`'s to the far
+/// layer behind it. Blurring the whole image at once spreads the colours of
+/// each layer into the other, so a bright highlight in the background glows
+/// over the edge of the subject. Here each layer is blurred on its own, along
+/// with its coverage of each pixel, and the far layer is filled in behind the
+/// near layer from the surrounding background rather than darkened by it. The
+/// near layer is then laid over the far layer: the pixels of the near layer
+/// only take colour from the near layer, so the background never shows through
+/// the subject, while the blurred edges of the near layer spread over the
+/// background in front of it, as an out of focus foreground would.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_layered<'a>(
+    img: &mut [[f64; 4]],
+    near: impl IntoIterator<Item = &'a bool>,
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    let near = collect_mask(near, width, height);
+    let plan = KernelPlan::for_radius(param_set, radius);
+
+    // Blurs the colours of one layer, premultiplied by whether each pixel is
+    // in the layer, along with that coverage as a fifth channel
+    let blur_layer = |is_near: bool| {
+        let pixels = img
+            .iter()
+            .zip(&near)
+            .map(|(&[r, g, b, a], &n)| {
+                let coverage = if n == is_near { 1.0 } else { 0.0 };
+                [r, g, b, a, 1.0].map(|c| c * coverage)
+            })
+            .collect::<Vec<_>>();
+        ComplexImage::from_slice_per_channel(
+            &pixels,
+            width,
+            height,
+            [gamma, gamma, gamma, gamma, 1.0],
+        )
+        .convolve(plan.kernels())
+    };
+    let (near_layer, far_layer) = (blur_layer(true), blur_layer(false));
+
+    // Normalises a blurred layer by its coverage, giving its colour where it
+    // is absent or only partly present
+    let fill = |layer: [f64; 5], c: usize| {
+        if layer[4] > MIN_COVERAGE {
+            layer[c] / layer[4]
+        } else {
+            0.0
+        }
+    };
+    for (n, (near_pixel, far_pixel)) in near_layer.into_iter().zip(far_layer).enumerate() {
+        let coverage = near_pixel[4].clamp(0.0, 1.0);
+        let rgba: [f64; 4] = core::array::from_fn(|c| {
+            if near[n] {
+                fill(near_pixel, c)
+            } else {
+                near_pixel[c] + (1.0 - coverage) * fill(far_pixel, c)
+            }
+        });
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bokeh_blur;
+    use crate::params::KERNEL5_PARAM_SET;
+    use crate::testing::checkerboard;
+    use alloc::vec;
+
+    fn assert_close(a: &[[f64; 4]], b: &[[f64; 4]]) {
+        for (a, b) in a.iter().zip(b) {
+            for (a, b) in a.iter().zip(b) {
+                assert!((a - b).abs() < 1e-9, "{a} != {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn depth_map_blurs_by_the_circle_of_confusion() {
+        let (w, h) = (12, 8);
+        let original = checkerboard(w, h, 2);
+        // In focus at 2, then a wall at 4, half the aperture, and the
+        // foreground at 1, blurred as far as anything can be
+        let depth_map = (0..w * h)
+            .map(|n| [2.0, 4.0, 1.0, 0.0][(n % w) / 3])
+            .collect::<Vec<_>>();
+
+        let mut img = original.clone();
+        bokeh_blur_with_depth_map(
+            &mut img,
+            w,
+            h,
+            &depth_map,
+            2.0,
+            3.5,
+            &KERNEL5_PARAM_SET,
+            2.2,
+        );
+
+        let depth = (0..w * h)
+            .map(|n| [0.0, 0.5, 1.0, 1.0][(n % w) / 3])
+            .collect::<Vec<_>>();
+        let mut expected = original.clone();
+        bokeh_blur_with_depth(
+            &mut expected,
+            w,
+            h,
+            &depth,
+            0.0,
+            3.5,
+            &KERNEL5_PARAM_SET,
+            2.2,
+        );
+        assert_close(&img, &expected);
+        for n in (0..w * h).filter(|n| n % w < 3) {
+            assert_eq!(img[n], original[n]);
+        }
+    }
+
+    #[test]
+    fn focal_plane_is_untouched() {
+        let (w, h) = (12, 8);
+        let mut img = checkerboard(w, h, 2);
+        let original = img.clone();
+        // Left half in focus, right half furthest away
+        let depth = (0..w * h)
+            .map(|n| if n % w < w / 2 { 0.25 } else { 1.25 })
+            .collect::<Vec<_>>();
+
+        bokeh_blur_with_depth(&mut img, w, h, &depth, 0.25, 3.5, &KERNEL5_PARAM_SET, 1.0);
+
+        let mut expected = original.clone();
+        bokeh_blur(&mut expected, w, h, 3.5, &KERNEL5_PARAM_SET, 1.0);
+        for n in 0..w * h {
+            if n % w < w / 2 {
+                assert_eq!(img[n], original[n]);
+            } else {
+                assert_close(&img[n..n + 1], &expected[n..n + 1]);
+            }
+        }
+    }
+
+    #[test]
+    fn interpolates_between_layers() {
+        let (w, h) = (10, 10);
+        let mut img = checkerboard(w, h, 3);
+        let original = img.clone();
+        // Halfway between the second and third radii, 1.0 and 1.5
+        let depth = vec![2.5 / 7.0; w * h];
+
+        bokeh_blur_with_depth(&mut img, w, h, &depth, 0.0, 3.5, &KERNEL5_PARAM_SET, 1.0);
+
+        let mut near = original.clone();
+        bokeh_blur(&mut near, w, h, 1.0, &KERNEL5_PARAM_SET, 1.0);
+        let mut far = original;
+        bokeh_blur(&mut far, w, h, 1.5, &KERNEL5_PARAM_SET, 1.0);
+        let expected = near
+            .iter()
+            .zip(&far)
+            .map(|(a, b)| [0, 1, 2, 3].map(|c| (a[c] + b[c]) / 2.0))
+            .collect::<Vec<_>>();
+        assert_close(&img, &expected);
+    }
+
+    #[test]
+    fn radial_blur_ramps_out_from_the_focus() {
+        let (w, h) = (15, 11);
+        let original = checkerboard(w, h, 2);
+        let mut img = original.clone();
+        bokeh_blur_radial(
+            &mut img,
+            w,
+            h,
+            (0.0, 0.0),
+            3.0,
+            2.0,
+            &KERNEL5_PARAM_SET,
+            2.2,
+        );
+
+        let mut expected = original.clone();
+        bokeh_blur(&mut expected, w, h, 2.0, &KERNEL5_PARAM_SET, 2.2);
+        for n in 0..w * h {
+            let (x, y) = ((n % w) as f64, (n / w) as f64);
+            if x.hypot(y) <= 3.0 {
+                assert_eq!(img[n], original[n]);
+            }
+        }
+        // The furthest corner gets the full blur
+        assert_close(&img[w * h - 1..], &expected[w * h - 1..]);
+
+        let mut img = original.clone();
+        bokeh_blur_radial(
+            &mut img,
+            w,
+            h,
+            (7.0, 5.0),
+            10.0,
+            2.0,
+            &KERNEL5_PARAM_SET,
+            2.2,
+        );
+        assert_eq!(img, original);
+    }
+
+    #[test]
+    fn background_highlight_does_not_halo_over_subject() {
+        let (w, h) = (21, 9);
+        // A dim subject on the left, with a highlight just behind its edge
+        let near = (0..w * h).map(|n| n % w <= 10).collect::<Vec<_>>();
+        let original = (0..w * h)
+            .map(|n| match n % w {
+                0..=10 => [20.0; 4],
+                11 => [255.0; 4],
+                _ => [0.0; 4],
+            })
+            .collect::<Vec<_>>();
+
+        let mut layered = original.clone();
+        bokeh_blur_layered(&mut layered, &near, w, h, 4.0, &KERNEL5_PARAM_SET, 1.0);
+        let mut single = original;
+        bokeh_blur(&mut single, w, h, 4.0, &KERNEL5_PARAM_SET, 1.0);
+
+        for n in 0..w * h {
+            if near[n] {
+                assert!((layered[n][0] - 20.0).abs() < 1e-9);
+            }
+        }
+        assert!(single[4 * w + 9][0] > 40.0);
+    }
+}
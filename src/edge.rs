@@ -0,0 +1,109 @@
+//! Handling of the pixels beyond the edges of an image.
+
+/// How a blur samples the pixels beyond the edges of an image, where the
+/// kernel overhangs the border.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EdgeMode {
+    /// Pixels beyond the edges are black, which darkens the borders of bright
+    /// images
+    #[default]
+    Zero,
+    /// Pixels beyond the edges repeat the nearest pixel on the edge
+    Clamp,
+    /// The image is mirrored at its edges, without repeating the edge pixels
+    Reflect,
+    /// The image is tiled, so pixels beyond one edge come from the opposite
+    /// edge
+    Wrap,
+    /// Pixels beyond the edges are the given R, G, B, A colour, in the same
+    /// range as the pixels of the image and before the gamma is applied
+    ConstantColor([f64; 4]),
+    /// Only the pixels inside the image are convolved, and each result is
+    /// divided by the part of the kernel covering the image, so the borders
+    /// are the average of the pixels around them rather than darkened
+    Renormalize,
+}
+
+impl EdgeMode {
+    /// Whether the pixels beyond the edges are convolved as black, any other
+    /// colour or weighting being corrected for after convolving
+    pub(crate) const fn convolves_black(self) -> bool {
+        matches!(
+            self,
+            Self::Zero | Self::ConstantColor(_) | Self::Renormalize
+        )
+    }
+
+    /// Index of the pixel sampled at position `pos` along an axis of length
+    /// `len`, or `None` if it is black
+    pub(crate) fn resolve(self, pos: isize, len: usize) -> Option<usize> {
+        if (0..len as isize).contains(&pos) {
+            return Some(pos as usize);
+        }
+
+        match self {
+            Self::Zero | Self::ConstantColor(_) | Self::Renormalize => None,
+            Self::Clamp => Some(pos.clamp(0, len as isize - 1) as usize),
+            Self::Reflect => {
+                let period = 2 * (len as isize - 1);
+                if period == 0 {
+                    return Some(0);
+                }
+                let pos = pos.rem_euclid(period);
+                Some(pos.min(period - pos) as usize)
+            }
+            Self::Wrap => Some(pos.rem_euclid(len as isize) as usize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn resolves_positions() {
+        let resolve = |edge: EdgeMode| (-4..8).map(|p| edge.resolve(p, 4)).collect::<Vec<_>>();
+
+        assert_eq!(
+            resolve(EdgeMode::Zero),
+            [
+                None,
+                None,
+                None,
+                None,
+                Some(0),
+                Some(1),
+                Some(2),
+                Some(3),
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+        assert_eq!(
+            resolve(EdgeMode::Clamp)
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>(),
+            [0, 0, 0, 0, 0, 1, 2, 3, 3, 3, 3, 3]
+        );
+        assert_eq!(
+            resolve(EdgeMode::Reflect)
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>(),
+            [2, 3, 2, 1, 0, 1, 2, 3, 2, 1, 0, 1]
+        );
+        assert_eq!(
+            resolve(EdgeMode::Wrap)
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>(),
+            [0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3]
+        );
+        assert_eq!(EdgeMode::Reflect.resolve(-3, 1), Some(0));
+    }
+}
@@ -0,0 +1,106 @@
+//! Errors returned by the fallible blurring functions and when building
+//! kernel parameters.
+use core::fmt;
+
+/// Errors that can occur when blurring an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlurError {
+    /// The mask doesn't have exactly one element per pixel of the image
+    MaskLengthMismatch {
+        /// Number of pixels in the image
+        expected: usize,
+        /// Length of the mask
+        got: usize,
+    },
+    /// The blur was cancelled through its [`crate::BlurHandle`]
+    Cancelled,
+    /// The image doesn't have exactly `width * height` pixels
+    DimensionMismatch {
+        /// `width * height` of the image
+        expected: usize,
+        /// Number of pixels of the image
+        got: usize,
+    },
+    /// The radius is negative, infinite or NaN
+    InvalidRadius,
+    /// The pixels don't have the number of channels the blur expects
+    ChannelMismatch {
+        /// Number of channels the blur expects
+        expected: usize,
+        /// Number of channels of the pixels
+        got: usize,
+    },
+    /// The rows of the image are closer together than its width
+    InvalidStride {
+        /// Number of pixels from the start of one row to the next
+        stride: usize,
+        /// Width of the image
+        width: usize,
+    },
+    /// A pixel is infinite or NaN, or becomes so once raised to the power of
+    /// the gamma, e.g. a negative value with a fractional gamma
+    NonFiniteInput {
+        /// Index of the first such pixel
+        index: usize,
+    },
+}
+
+impl fmt::Display for BlurError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MaskLengthMismatch { expected, got } => write!(
+                f,
+                "mask has {got} elements but the image has {expected} pixels"
+            ),
+            Self::Cancelled => write!(f, "blur was cancelled"),
+            Self::DimensionMismatch { expected, got } => write!(
+                f,
+                "image has {got} pixels but its dimensions give {expected}"
+            ),
+            Self::InvalidRadius => write!(f, "radius must be finite and not negative"),
+            Self::ChannelMismatch { expected, got } => {
+                write!(f, "pixels have {got} channels but {expected} are expected")
+            }
+            Self::InvalidStride { stride, width } => {
+                write!(f, "stride {stride} is less than the width {width}")
+            }
+            Self::NonFiniteInput { index } => {
+                write!(f, "pixel {index} isn't finite with the gamma applied")
+            }
+        }
+    }
+}
+
+impl core::error::Error for BlurError {}
+
+/// Errors that can occur when building a custom
+/// [`KernelParamSet`](crate::params::KernelParamSet)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamError {
+    /// There are no components
+    Empty,
+    /// The number of values isn't a multiple of the 4 values of each component
+    IncompleteComponent {
+        /// Number of values given
+        len: usize,
+    },
+    /// A value is infinite or NaN
+    NotFinite {
+        /// Position of the value
+        index: usize,
+    },
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "parameter set has no components"),
+            Self::IncompleteComponent { len } => {
+                write!(f, "{len} parameters given but each component needs 4")
+            }
+            Self::NotFinite { index } => write!(f, "parameter {index} is not finite"),
+        }
+    }
+}
+
+impl core::error::Error for ParamError {}
@@ -0,0 +1,135 @@
+//! A plain separable Gaussian blur, a cheap alternative to the Bokeh blur when
+//! the disc shape isn't needed, e.g. for softening masks.
+use crate::complex::{ComplexImage, Kernel};
+use alloc::vec::Vec;
+use num::Complex;
+
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use num::Float;
+
+/// Normalised 1-D Gaussian kernel with standard deviation `r`, sampled
+/// `kernel_radius` pixels either side of its centre.
+///
+/// The kernel has `2 * kernel_radius + 1` taps which sum to `1.0`. About
+/// `3 * r` captures all but a negligible part of the Gaussian. An `r` of `0.0`
+/// gives a kernel which leaves the image unchanged.
+pub fn gaussian_kernel(r: f64, kernel_radius: usize) -> Vec<f64> {
+    let kernel = (-(kernel_radius as isize)..=kernel_radius as isize)
+        .map(|i| {
+            if r > 0.0 {
+                let x = i as f64 / r;
+                (-0.5 * x * x).exp()
+            } else if i == 0 {
+                1.0
+            } else {
+                0.0
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let sum = kernel.iter().sum::<f64>();
+    kernel.into_iter().map(|k| k / sum).collect()
+}
+
+/// The Gaussian kernel as a (horizontal, vertical) pair for
+/// [`ComplexImage::convolve`]
+fn kernel_pair(r: f64, kernel_radius: usize) -> [(Kernel, Kernel); 1] {
+    let kernel = gaussian_kernel(r, kernel_radius)
+        .into_iter()
+        .map(|k| Complex::new(k, 0.0))
+        .collect::<Vec<_>>();
+    [(kernel.clone(), kernel)]
+}
+
+/// Blurs an image with a Gaussian kernel.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a Gaussian with standard deviation `r`,
+/// sampled `kernel_radius` pixels either side of its centre, see
+/// [`gaussian_kernel`].
+pub fn gaussian_blur(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    r: f64,
+    kernel_radius: usize,
+) {
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, 1.0)
+        .convolve(&kernel_pair(r, kernel_radius))
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.clamp(0.0, 255.0));
+    }
+}
+
+/// Equivalent blurring functions but operate on a
+/// [`image::DynamicImage`](image). See also the [`crate::Blur`] trait.
+#[cfg(feature = "image")]
+pub mod dynamic_image {
+    use super::kernel_pair;
+    use crate::complex::ComplexImage;
+    use image::{DynamicImage, GenericImage, Pixel};
+
+    /// Blurs an image with a Gaussian kernel.
+    ///
+    /// Takes an exclusive reference to a [`image::DynamicImage`](image). The
+    /// image is blurred by a Gaussian with standard deviation `r`, sampled
+    /// `kernel_radius` pixels either side of its centre, see
+    /// [`super::gaussian_kernel`].
+    pub fn gaussian_blur(img: &mut DynamicImage, r: f64, kernel_radius: usize) {
+        let w = img.width();
+
+        for (n, rgba) in ComplexImage::from_dynamic_image(img, 1.0)
+            .convolve(&kernel_pair(r, kernel_radius))
+            .into_iter()
+            .enumerate()
+        {
+            // Safety: definitely in bounds due to iteration ranges
+            unsafe {
+                img.unsafe_put_pixel(
+                    n as u32 % w,
+                    n as u32 / w,
+                    // Clamp any values from floating point ops - ensure the cast to u8 is ok
+                    *Pixel::from_slice(&rgba.map(|i| i.clamp(0.0, 255.0).round() as u8)),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_is_normalised_and_symmetric() {
+        let kernel = gaussian_kernel(1.5, 5);
+
+        assert_eq!(kernel.len(), 11);
+        assert!((kernel.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        for (a, b) in kernel.iter().zip(kernel.iter().rev()) {
+            assert_eq!(a, b);
+        }
+        assert!(kernel.windows(2).take(5).all(|k| k[0] < k[1]));
+        assert_eq!(gaussian_kernel(0.0, 2), [0.0, 0.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn blurs_impulse_into_gaussian() {
+        let (w, h) = (9, 9);
+        let mut img = crate::testing::impulse_image(w, h);
+
+        gaussian_blur(&mut img, w, h, 1.0, 4);
+
+        let kernel = gaussian_kernel(1.0, 4);
+        for (n, p) in img.iter().enumerate() {
+            let expected = 255.0 * kernel[n % w] * kernel[n / w];
+            assert!((p[0] - expected).abs() < 1e-9);
+        }
+        assert!((img.iter().map(|p| p[3]).sum::<f64>() - 255.0).abs() < 1e-9);
+    }
+}
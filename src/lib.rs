@@ -21,7 +21,10 @@
 //! of convolutions carried out on the image, i.e. using 8 components is 2 times
 //! slower than using 4.
 //!
-//! Currently only images with 4 channels are supported.
+//! Images with 4 (RGBA) channels are supported, as well as 3 (RGB) channel
+//! images through [`bokeh_blur_rgb`], single-channel images such as depth
+//! maps through [`bokeh_blur_grey`] and images with any number of channels
+//! through [`bokeh_blur_channels`].
 //!
 //! Seperate APIs are available which allow a mask to be passed. This mask
 //! allows pixels of the original image to be retained. This should be
@@ -32,10 +35,11 @@
 //!
 //! Using the [`image`](image) library (requires the default `image` feature):
 //! ```no_run
+//! # #[cfg(feature = "image")]
+//! # fn main() -> Result<(), image::ImageError> {
 //! use bokeh::{params::KERNEL9_PARAM_SET, Blur};
-//! use image::{io::Reader as ImageReader, ImageError};
+//! use image::io::Reader as ImageReader;
 //!
-//! # fn main() -> Result<(), ImageError> {
 //! // read the image
 //! let mut img = ImageReader::open("myimage.jpg")?.decode()?;
 //! // as the `bokeh::Blur` trait is imported
@@ -44,6 +48,8 @@
 //! img.save("output.png")?;
 //! # Ok(())
 //! # }
+//! # #[cfg(not(feature = "image"))]
+//! # fn main() {}
 //! ```
 //!
 //! Using functions directly:
@@ -61,13 +67,13 @@
 //! assert_eq!(
 //!     vec![
 //!         1.6428886692061846,
-//!         14.80242203513296,
+//!         14.802422035132876,
 //!         1.6428886692061846,
-//!         14.802422035132915,
-//!         254.93338630375473,
-//!         14.802422035132915,
+//!         14.802422035132999,
+//!         254.93338630375567,
+//!         14.802422035132999,
 //!         1.6428886692061846,
-//!         14.80242203513296,
+//!         14.802422035132876,
 //!         1.6428886692061846
 //!     ]
 //!     .iter()
@@ -91,19 +97,19 @@
 //! assert_eq!(
 //!     vec![
 //!         1.6428886692061846,
-//!         14.80242203513296,
+//!         14.802422035132876,
 //!         1.6428886692061846,
-//!         14.802422035132915,
-//!         254.93338630375473,
-//!         14.802422035132915,
+//!         14.802422035132999,
+//!         254.93338630375567,
+//!         14.802422035132999,
 //!         1.6428886692061846,
-//!         14.80242203513296,
+//!         14.802422035132876,
 //!         1.6428886692061846
 //!     ]
 //!     .iter()
 //!     .map(|&i| [i, i, i, i])
 //!     .collect::<Vec<_>>(),
-//!     img.pixels
+//!     img.pixels()
 //! );
 //! ```
 //!
@@ -122,38 +128,166 @@
 //! assert_eq!(
 //!     vec![
 //!         0.,
-//!         14.80242203513296,
+//!         14.802422035132876,
 //!         0.,
-//!         14.802422035132915,
+//!         14.802422035132999,
 //!         255.,
-//!         14.802422035132915,
+//!         14.802422035132999,
 //!         0.,
-//!         14.80242203513296,
+//!         14.802422035132876,
 //!         0.
 //!     ]
 //!     .iter()
 //!     .map(|&i| [i, i, i, i])
 //!     .collect::<Vec<_>>(),
-//!     img.pixels
+//!     img.pixels()
 //! );
 //! ```
 //! In the `assert!` statement above, comparing it to the previous example, it
 //! can be seen that the original pixel values are retained.
+//!
+//! # Features
+//!
+//! - `std` (default): links the standard library. Without it the crate is
+//!   `no_std`, needing only `alloc`, and the floating point maths comes from
+//!   `libm`, so the `libm` feature must be enabled instead.
+//! - `rayon` (default): convolves the kernel components, and the rows of each,
+//!   in parallel. Without it every blur runs on the current thread. The
+//!   components are always summed in the same order, so the result is
+//!   identical either way and whatever the number of threads.
+//! - `image` (default): support for the [`image`](https://docs.rs/image)
+//!   library.
+//! - `ndarray`: blurring of images held in [`ndarray`](https://docs.rs/ndarray)
+//!   arrays.
+//! - `testing`: helpers generating simple images for testing blurs.
+#![cfg_attr(not(feature = "std"), no_std)]
 #![deny(missing_docs)]
 
+#[cfg(not(any(feature = "std", feature = "libm")))]
+compile_error!(
+    "either the `std` or the `libm` feature must be enabled for the floating point maths"
+);
+
+// Without `std` the floating point maths comes from `num::Float`, imported
+// where it's used. Those imports may go unused when another crate in the build
+// links `std`, e.g. a dev-dependency under `cargo test`, which brings back the
+// inherent methods of `f64`.
+extern crate alloc;
+
+#[cfg(feature = "ndarray")]
+mod array;
+#[cfg(feature = "image")]
+mod buffer;
+mod builder;
+mod color;
 mod complex;
+mod control;
+mod cost;
+mod depth;
+mod edge;
+mod error;
+pub mod gaussian;
+mod mask;
+mod parallel;
 pub mod params;
+mod plan;
+mod polygon;
+mod preset;
+mod preview;
+mod radius;
+mod shape;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod tile;
+mod tone;
+mod view;
 
 use self::params::KernelParamSet;
+use alloc::vec::Vec;
 
 #[cfg(feature = "image")]
 use image::DynamicImage;
 
+#[cfg(feature = "ndarray")]
+pub use self::array::bokeh_blur_ndarray;
+pub use self::builder::{BlurOptions, BokehBlur, BokehBlurBuilder};
+pub use self::color::ColorSpace;
 pub use self::complex::bokeh_blur;
+pub use self::complex::bokeh_blur_alpha_weighted;
+pub use self::complex::bokeh_blur_anisotropic;
+pub use self::complex::bokeh_blur_batch;
+pub use self::complex::bokeh_blur_budget;
+pub use self::complex::bokeh_blur_by_alpha;
+pub use self::complex::bokeh_blur_channels;
+pub use self::complex::bokeh_blur_chromatic;
+pub use self::complex::bokeh_blur_f32;
+pub use self::complex::bokeh_blur_grey;
+pub use self::complex::bokeh_blur_iterated;
+pub use self::complex::bokeh_blur_linear;
+pub use self::complex::bokeh_blur_mix;
+pub use self::complex::bokeh_blur_mosaic;
+pub use self::complex::bokeh_blur_normalised_to;
+pub use self::complex::bokeh_blur_premultiplied;
+pub use self::complex::bokeh_blur_pruned;
+pub use self::complex::bokeh_blur_raw;
+pub use self::complex::bokeh_blur_region;
+pub use self::complex::bokeh_blur_region_feathered;
+pub use self::complex::bokeh_blur_rgb;
+pub use self::complex::bokeh_blur_rgba_u8;
+pub use self::complex::bokeh_blur_subpixel;
+pub use self::complex::bokeh_blur_swirl;
+pub use self::complex::bokeh_blur_tone_mapped;
+pub use self::complex::bokeh_blur_unit;
+pub use self::complex::bokeh_blur_with_bitmask;
+pub use self::complex::bokeh_blur_with_bloom;
+pub use self::complex::bokeh_blur_with_edge_mode;
+pub use self::complex::bokeh_blur_with_gamma;
+pub use self::complex::bokeh_blur_with_kernel_radius;
 pub use self::complex::bokeh_blur_with_mask;
+pub use self::complex::bokeh_blur_with_mask_fill;
+pub use self::complex::bokeh_blur_with_max_value;
+pub use self::complex::bokeh_blur_with_soft_mask;
+pub use self::complex::bokeh_blur_with_stride;
+pub use self::complex::bokeh_blur_with_weights;
+pub use self::complex::complex_gaussian_kernel;
 #[cfg(feature = "image")]
 pub use self::complex::dynamic_image;
+pub use self::complex::kernel_dc_gain;
 pub use self::complex::kernel_gaussian_components;
+pub use self::complex::kernel_gaussian_components_normalised_to;
+pub use self::complex::kernel_gaussian_components_pruned;
+pub use self::complex::kernel_gaussian_components_subpixel;
+pub use self::complex::kernel_support_for_energy;
+pub use self::complex::normalised_kernels;
+pub use self::complex::recommended_kernel_radius;
+pub use self::complex::reconstruct_kernel_2d;
+pub use self::complex::sample_combined_kernel;
+pub use self::complex::try_bokeh_blur;
+pub use self::complex::try_bokeh_blur_cancellable;
+pub use self::complex::try_bokeh_blur_channels;
+pub use self::complex::try_bokeh_blur_controlled;
+pub use self::complex::try_bokeh_blur_with_mask;
+pub use self::control::{BlurControl, BlurHandle};
+pub use self::cost::{estimate_cost, BlurCost};
+pub use self::depth::{
+    bokeh_blur_layered, bokeh_blur_radial, bokeh_blur_with_depth, bokeh_blur_with_depth_map,
+};
+pub use self::edge::EdgeMode;
+pub use self::error::{BlurError, ParamError};
+pub use self::gaussian::{gaussian_blur, gaussian_kernel};
+pub use self::mask::{mask_bounds, mask_regions, Rect};
+#[cfg(feature = "rayon")]
+pub use self::parallel::bokeh_blur_in_pool;
+pub use self::parallel::{bokeh_blur_sequential, is_parallel, thread_count};
+pub use self::plan::{bokeh_blur_with_plan, KernelPlan};
+pub use self::polygon::{bokeh_blur_polygon, polygon_kernel};
+pub use self::preset::BokehPreset;
+pub use self::preview::bokeh_blur_preview;
+pub use self::radius::BlurRadius;
+pub use self::shape::{bokeh_blur_shaped, KernelShape};
+pub use self::tile::{bokeh_blur_streaming, bokeh_blur_tiled};
+pub use self::tone::ToneMap;
+pub use self::view::PixelsMut;
 
 /// A trait that allows the blurring of images
 pub trait Blur {
@@ -180,10 +314,36 @@ pub trait Blur {
         param_set: &KernelParamSet,
         gamma: f64,
     );
+
+    /// Blurs the image using a Gaussian kernel, a cheaper alternative when the
+    /// disc shape isn't needed.
+    ///
+    /// The image is blurred by a Gaussian with standard deviation `r`, sampled
+    /// `kernel_radius` pixels either side of its centre.
+    fn gaussian_blur(&mut self, r: f64, kernel_radius: usize);
+
+    /// Blurs the image using the settings of `options`, built with
+    /// [`BokehBlur::builder`], which names each setting rather than taking
+    /// them as positional arguments.
+    fn bokeh_blur_with(&mut self, options: &BlurOptions);
+
+    /// Blurs the image using the settings of `preset`, scaling `base_radius`
+    /// by the preset's radius scale.
+    fn apply_preset(&mut self, preset: BokehPreset, base_radius: f64) {
+        self.bokeh_blur(
+            base_radius * preset.radius_scale(),
+            preset.param_set(),
+            preset.gamma(),
+        )
+    }
 }
 
 #[cfg(feature = "image")]
 impl Blur for DynamicImage {
+    fn bokeh_blur_with(&mut self, options: &BlurOptions) {
+        options.apply(self)
+    }
+
     fn bokeh_blur(&mut self, radius: f64, param_set: &KernelParamSet, gamma: f64) {
         dynamic_image::bokeh_blur(self, radius, param_set, gamma)
     }
@@ -197,28 +357,186 @@ impl Blur for DynamicImage {
     ) {
         dynamic_image::bokeh_blur_with_mask(self, mask, radius, param_set, gamma)
     }
+
+    fn gaussian_blur(&mut self, r: f64, kernel_radius: usize) {
+        gaussian::dynamic_image::gaussian_blur(self, r, kernel_radius)
+    }
 }
 
-#[derive(Debug, PartialEq, PartialOrd)]
-/// Utility wrapper struct representing an image
+/// Utility wrapper struct representing an image, either borrowing its pixels
+/// or owning them
+#[derive(Debug)]
 pub struct Image<'a> {
     /// Image's pixels
-    pub pixels: &'a mut [[f64; 4]],
+    pixels: Pixels<'a>,
     w: usize,
     h: usize,
 }
 
+/// Storage of the pixels of an [`Image`]
+#[derive(Debug)]
+enum Pixels<'a> {
+    Borrowed(&'a mut [[f64; 4]]),
+    Owned(Vec<[f64; 4]>),
+}
+
+impl core::ops::Deref for Pixels<'_> {
+    type Target = [[f64; 4]];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Borrowed(pixels) => pixels,
+            Self::Owned(pixels) => pixels,
+        }
+    }
+}
+
+impl core::ops::DerefMut for Pixels<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Self::Borrowed(pixels) => pixels,
+            Self::Owned(pixels) => pixels,
+        }
+    }
+}
+
 impl<'a> Image<'a> {
     /// Creates a new `Image` containing an exclusive reference to a slice of
     /// pixels
     pub fn new(pixels: &'a mut [[f64; 4]], w: usize, h: usize) -> Self {
-        Self { pixels, w, h }
+        Self {
+            pixels: Pixels::Borrowed(pixels),
+            w,
+            h,
+        }
+    }
+
+    /// Width of the image in pixels
+    pub fn width(&self) -> usize {
+        self.w
+    }
+
+    /// Height of the image in pixels
+    pub fn height(&self) -> usize {
+        self.h
+    }
+
+    /// Width and height of the image in pixels
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.w, self.h)
+    }
+
+    /// Image's pixels, row by row
+    pub fn pixels(&self) -> &[[f64; 4]] {
+        &self.pixels
+    }
+
+    /// Exclusive reference to the image's pixels, row by row
+    pub fn pixels_mut(&mut self) -> &mut [[f64; 4]] {
+        &mut self.pixels
+    }
+
+    /// Blurs the image one `tile_size` by `tile_size` tile at a time,
+    /// bounding the memory used for very large images, see
+    /// [`bokeh_blur_tiled`]. The overlap between tiles is the kernel radius,
+    /// so the result is the same as [`Blur::bokeh_blur`].
+    pub fn bokeh_blur_tiled(
+        &mut self,
+        tile_size: usize,
+        radius: f64,
+        param_set: &KernelParamSet,
+        gamma: f64,
+    ) {
+        bokeh_blur_tiled(
+            &mut self.pixels,
+            self.w,
+            self.h,
+            radius,
+            param_set,
+            gamma,
+            tile_size,
+        )
+    }
+
+    /// Blurs the image by the circle of confusion of each pixel, simulating
+    /// the depth of field of a camera focused at `focal_distance` from a
+    /// `depth_map`, see [`bokeh_blur_with_depth_map`].
+    pub fn bokeh_blur_with_depth_map(
+        &mut self,
+        depth_map: &[f64],
+        focal_distance: f64,
+        aperture: f64,
+        param_set: &KernelParamSet,
+        gamma: f64,
+    ) {
+        bokeh_blur_with_depth_map(
+            &mut self.pixels,
+            self.w,
+            self.h,
+            depth_map,
+            focal_distance,
+            aperture,
+            param_set,
+            gamma,
+        )
+    }
+}
+
+impl Image<'static> {
+    /// Creates a new `Image` owning its pixels, so it can be returned and
+    /// passed around without the slice it was made from
+    pub fn owned(pixels: Vec<[f64; 4]>, w: usize, h: usize) -> Self {
+        Self {
+            pixels: Pixels::Owned(pixels),
+            w,
+            h,
+        }
+    }
+
+    /// Creates a new `Image` owning its pixels, unpacked from a flat buffer of
+    /// 8-bit R, G, B, A values, e.g. from a decoder.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` doesn't hold 4 bytes for each of the `w * h` pixels.
+    pub fn from_rgba_bytes(bytes: &[u8], w: usize, h: usize) -> Self {
+        assert_eq!(
+            bytes.len(),
+            w * h * 4,
+            "buffer doesn't hold {w}x{h} RGBA pixels"
+        );
+        let pixels = bytes
+            .chunks_exact(4)
+            .map(|p| [p[0], p[1], p[2], p[3]].map(f64::from))
+            .collect();
+
+        Self::owned(pixels, w, h)
+    }
+
+    /// Returns the image's pixels, copying them if they're borrowed
+    pub fn into_pixels(self) -> Vec<[f64; 4]> {
+        match self.pixels {
+            Pixels::Borrowed(pixels) => pixels.to_vec(),
+            Pixels::Owned(pixels) => pixels,
+        }
+    }
+}
+
+impl PartialEq for Image<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.pixels(), self.w, self.h) == (other.pixels(), other.w, other.h)
+    }
+}
+
+impl PartialOrd for Image<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        (self.pixels(), self.w, self.h).partial_cmp(&(other.pixels(), other.w, other.h))
     }
 }
 
 impl<'a> Blur for Image<'a> {
     fn bokeh_blur(&mut self, radius: f64, param_set: &KernelParamSet, gamma: f64) {
-        bokeh_blur(self.pixels, self.w, self.h, radius, param_set, gamma)
+        bokeh_blur(&mut self.pixels, self.w, self.h, radius, param_set, gamma)
     }
 
     fn bokeh_blur_with_mask<'b>(
@@ -228,14 +546,30 @@ impl<'a> Blur for Image<'a> {
         param_set: &KernelParamSet,
         gamma: f64,
     ) {
-        bokeh_blur_with_mask(self.pixels, mask, self.w, self.h, radius, param_set, gamma)
+        bokeh_blur_with_mask(
+            &mut self.pixels,
+            mask,
+            self.w,
+            self.h,
+            radius,
+            param_set,
+            gamma,
+        )
+    }
+
+    fn gaussian_blur(&mut self, r: f64, kernel_radius: usize) {
+        gaussian_blur(&mut self.pixels, self.w, self.h, r, kernel_radius)
+    }
+
+    fn bokeh_blur_with(&mut self, options: &BlurOptions) {
+        options.apply_slice(&mut self.pixels, self.w, self.h)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::params::KERNEL9_PARAM_SET;
+    use crate::params::{KERNEL5_PARAM_SET, KERNEL9_PARAM_SET};
 
     macro_rules! image {
         ([$($f:expr),+]) => {{
@@ -245,27 +579,103 @@ mod tests {
 
     #[test]
     fn blurs() {
-        let mut pixels = image!([0., 0., 0., 0., 255., 0., 0., 0., 0.]);
+        let mut pixels = testing::impulse_image(3, 3);
         let mut img = Image::new(&mut pixels, 3, 3);
 
         img.bokeh_blur(1.0, &KERNEL9_PARAM_SET, 3.0);
 
         assert_eq!(
-            img.pixels,
+            img.pixels(),
             image!([
                 1.6428886692061846,
-                14.80242203513296,
+                14.802422035132876,
                 1.6428886692061846,
-                14.802422035132915,
-                254.93338630375473,
-                14.802422035132915,
+                14.802422035132999,
+                254.93338630375567,
+                14.802422035132999,
                 1.6428886692061846,
-                14.80242203513296,
+                14.802422035132876,
                 1.6428886692061846
             ])
         );
     }
 
+    #[test]
+    fn tiled_images_match_whole_images() {
+        let (w, h) = (13, 9);
+        let mut img = Image::owned(testing::gradient_image(w, h), w, h);
+        img.bokeh_blur_tiled(4, 2.0, &KERNEL9_PARAM_SET, 2.2);
+
+        let mut expected = Image::owned(testing::gradient_image(w, h), w, h);
+        expected.bokeh_blur(2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (a, b) in img
+            .pixels()
+            .iter()
+            .flatten()
+            .zip(expected.pixels().iter().flatten())
+        {
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn depth_map_on_images() {
+        let (w, h) = (6, 5);
+        let depth_map = (0..w * h)
+            .map(|n| 1.0 + n as f64 / 10.0)
+            .collect::<Vec<_>>();
+        let mut img = Image::owned(testing::gradient_image(w, h), w, h);
+        img.bokeh_blur_with_depth_map(&depth_map, 2.0, 2.0, &KERNEL9_PARAM_SET, 2.2);
+
+        let mut expected = testing::gradient_image(w, h);
+        bokeh_blur_with_depth_map(
+            &mut expected,
+            w,
+            h,
+            &depth_map,
+            2.0,
+            2.0,
+            &KERNEL9_PARAM_SET,
+            2.2,
+        );
+        assert_eq!(img.pixels(), expected);
+    }
+
+    #[test]
+    fn owned_images() {
+        let bytes = [[0, 0, 0, 0], [255, 128, 64, 255]].repeat(3).concat();
+        let mut img = Image::from_rgba_bytes(&bytes, 3, 2);
+        assert_eq!(img.dimensions(), (3, 2));
+        assert_eq!((img.width(), img.height()), (3, 2));
+        assert_eq!(img.pixels()[1], [255.0, 128.0, 64.0, 255.0]);
+
+        let mut expected = img.pixels().to_vec();
+        img.bokeh_blur(1.0, &KERNEL9_PARAM_SET, 3.0);
+        bokeh_blur(&mut expected, 3, 2, 1.0, &KERNEL9_PARAM_SET, 3.0);
+        assert_eq!(img.into_pixels(), expected);
+
+        let mut pixels = expected.clone();
+        assert_eq!(Image::new(&mut pixels, 3, 2), Image::owned(expected, 3, 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer doesn't hold 3x2 RGBA pixels")]
+    fn rgba_bytes_must_fill_the_image() {
+        Image::from_rgba_bytes(&[0; 20], 3, 2);
+    }
+
+    #[test]
+    fn applies_portrait_preset() {
+        let mut pixels = image!([0., 0., 0., 0., 255., 0., 0., 0., 0.]);
+        let mut expected = pixels;
+        Image::new(&mut expected, 3, 3).bokeh_blur(2.0, &KERNEL5_PARAM_SET, 3.0);
+
+        let mut img = Image::new(&mut pixels, 3, 3);
+        img.apply_preset(BokehPreset::Portrait, 2.0);
+
+        assert_eq!(img.pixels(), expected);
+    }
+
     #[test]
     fn blurs_with_mask() {
         let mut pixels = image!([0., 0., 0., 0., 255., 0., 0., 0., 0.]);
@@ -275,16 +685,16 @@ mod tests {
         img.bokeh_blur_with_mask(&mask, 1.0, &KERNEL9_PARAM_SET, 3.0);
 
         assert_eq!(
-            img.pixels,
+            img.pixels(),
             image!([
                 0.,
-                14.80242203513296,
+                14.802422035132876,
                 0.,
-                14.802422035132915,
+                14.802422035132999,
                 255.,
-                14.802422035132915,
+                14.802422035132999,
                 0.,
-                14.80242203513296,
+                14.802422035132876,
                 0.
             ])
         );
@@ -0,0 +1,215 @@
+//! Utilities for working with masks.
+use alloc::vec::Vec;
+use core::ops::Range;
+
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use num::Float;
+
+/// A rectangular region of an image, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rect {
+    /// Column of the top-left corner
+    pub x: usize,
+    /// Row of the top-left corner
+    pub y: usize,
+    /// Width of the region
+    pub width: usize,
+    /// Height of the region
+    pub height: usize,
+}
+
+impl Rect {
+    /// Creates a new `Rect` with its top-left corner at (`x`, `y`)
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Grows the rectangle by `by` pixels on every side, clipped to an image of
+    /// size `width` by `height`
+    pub(crate) fn expand(self, by: usize, width: usize, height: usize) -> Self {
+        let x = self.x.saturating_sub(by).min(width);
+        let y = self.y.saturating_sub(by).min(height);
+        Self::new(
+            x,
+            y,
+            (self.x + self.width + by).min(width).saturating_sub(x),
+            (self.y + self.height + by).min(height).saturating_sub(y),
+        )
+    }
+
+    /// Index, in an image `width` pixels wide stored row by row, of the `n`th
+    /// pixel of the rectangle, also counting row by row
+    pub(crate) fn pixel_index(self, n: usize, width: usize) -> usize {
+        (self.y + n / self.width) * width + self.x + n % self.width
+    }
+
+    /// Euclidean distance from the pixel at (`x`, `y`) to the nearest pixel of
+    /// the rectangle
+    pub(crate) fn distance(self, x: usize, y: usize) -> f64 {
+        let axis = |p: usize, start: usize, len: usize| {
+            if p < start {
+                start - p
+            } else {
+                p.saturating_sub(start + len.max(1) - 1)
+            }
+        };
+        let dx = axis(x, self.x, self.width) as f64;
+        let dy = axis(y, self.y, self.height) as f64;
+
+        (dx * dx + dy * dy).sqrt()
+    }
+}
+
+/// Finds the tightest rectangle containing all the `true` pixels of a `mask`.
+///
+/// Takes a `mask` of length `w * h` stored row by row. Returns `None` if the
+/// mask contains no `true` pixels.
+pub fn mask_bounds(mask: &[bool], w: usize, h: usize) -> Option<Rect> {
+    debug_assert_eq!(mask.len(), w * h);
+
+    bounds_where(w, h.min(mask.len() / w.max(1)), |i| mask[i])
+}
+
+/// Finds the tightest rectangle containing all the pixels of an image of size
+/// `w` by `h`, stored row by row, for which `is_set` returns `true`, see
+/// [`mask_bounds`]
+pub(crate) fn bounds_where(w: usize, h: usize, is_set: impl Fn(usize) -> bool) -> Option<Rect> {
+    let (mut min_x, mut min_y) = (usize::MAX, usize::MAX);
+    let (mut max_x, mut max_y) = (0, 0);
+
+    for j in 0..h {
+        let mut row = j * w..(j + 1) * w;
+        if let Some(first) = row.clone().position(&is_set) {
+            // Safety of unwrap: at least one pixel is set
+            let last = row.rposition(&is_set).unwrap();
+            min_x = min_x.min(first);
+            max_x = max_x.max(last);
+            min_y = min_y.min(j);
+            max_y = j;
+        }
+    }
+
+    (min_y != usize::MAX).then(|| Rect::new(min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
+}
+
+/// Finds rectangles containing all the `true` pixels of a `mask`, splitting
+/// them where they are more than `gap` pixels apart.
+///
+/// Takes a `mask` of length `w * h` stored row by row. The rows holding `true`
+/// pixels are grouped into bands, starting a new band after more than `gap`
+/// rows without any, and the columns of each band are grouped the same way.
+/// Each group is then shrunk to the tightest rectangle around its pixels. A
+/// blur of a masked image only needs to convolve these rectangles and the
+/// pixels within the kernel radius of them, so with a `gap` of twice the
+/// kernel radius, parts of the mask far apart don't pull in all the pixels
+/// between them, as a single [`mask_bounds`] would. Returns no rectangles if
+/// the mask contains no `true` pixels.
+pub fn mask_regions(mask: &[bool], w: usize, h: usize, gap: usize) -> Vec<Rect> {
+    debug_assert_eq!(mask.len(), w * h);
+
+    regions_where(w, h.min(mask.len() / w.max(1)), gap, |i| mask[i])
+}
+
+/// Finds rectangles containing all the pixels of an image of size `w` by `h`,
+/// stored row by row, for which `is_set` returns `true`, see [`mask_regions`]
+pub(crate) fn regions_where(
+    w: usize,
+    h: usize,
+    gap: usize,
+    is_set: impl Fn(usize) -> bool,
+) -> Vec<Rect> {
+    // Groups ascending indices into ranges, split by more than `gap` missing
+    let runs = |indices: &mut dyn Iterator<Item = usize>| {
+        let mut runs: Vec<Range<usize>> = Vec::new();
+        for i in indices {
+            match runs.last_mut() {
+                Some(run) if i - run.end <= gap => run.end = i + 1,
+                _ => runs.push(i..i + 1),
+            }
+        }
+        runs
+    };
+
+    let mut regions = Vec::new();
+    for band in runs(&mut (0..h).filter(|&j| (j * w..(j + 1) * w).any(&is_set))) {
+        let columns = runs(&mut (0..w).filter(|&i| band.clone().any(|j| is_set(j * w + i))));
+        for columns in columns {
+            let mut rows = band
+                .clone()
+                .filter(|&j| columns.clone().any(|i| is_set(j * w + i)));
+            // Safety of unwrap: the columns hold at least one pixel of the band
+            let first = rows.next().unwrap();
+            let last = rows.last().unwrap_or(first);
+            regions.push(Rect::new(
+                columns.start,
+                first,
+                columns.len(),
+                last - first + 1,
+            ));
+        }
+    }
+
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_mask_has_no_bounds() {
+        assert_eq!(mask_bounds(&[false; 12], 4, 3), None);
+        assert_eq!(mask_bounds(&[], 0, 0), None);
+    }
+
+    #[test]
+    fn single_pixel_bounds() {
+        let mut mask = [false; 12];
+        mask[6] = true;
+
+        assert_eq!(mask_bounds(&mask, 4, 3), Some(Rect::new(2, 1, 1, 1)));
+    }
+
+    #[test]
+    fn bounds_span_all_true_pixels() {
+        #[rustfmt::skip]
+        let mask = [
+            false, false, false, false, false,
+            false, false, true,  false, false,
+            false, true,  false, false, false,
+            false, false, false, true,  false,
+        ];
+
+        assert_eq!(mask_bounds(&mask, 5, 4), Some(Rect::new(1, 1, 3, 3)));
+        assert_eq!(mask_bounds(&[true; 6], 3, 2), Some(Rect::new(0, 0, 3, 2)));
+    }
+
+    #[test]
+    fn regions_split_at_gaps() {
+        #[rustfmt::skip]
+        let mask = [
+            true,  false, false, false, false, true,
+            false, false, false, false, false, false,
+            false, false, false, false, false, false,
+            false, false, true,  false, false, false,
+            false, false, false, true,  false, false,
+        ];
+
+        assert_eq!(
+            mask_regions(&mask, 6, 5, 1),
+            [
+                Rect::new(0, 0, 1, 1),
+                Rect::new(5, 0, 1, 1),
+                Rect::new(2, 3, 2, 2)
+            ]
+        );
+        assert_eq!(mask_regions(&mask, 6, 5, 2), [Rect::new(0, 0, 6, 5)]);
+        assert_eq!(mask_regions(&[false; 12], 4, 3, 0), []);
+    }
+}
@@ -0,0 +1,148 @@
+//! Reporting and control of how blurs are spread across threads.
+use crate::complex::{is_identity, ComplexImage};
+use crate::params::KernelParamSet;
+use crate::plan::KernelPlan;
+
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use num::Float;
+#[cfg(feature = "rayon")]
+use rayon::ThreadPool;
+
+/// Number of threads a blur started from the current thread can use.
+///
+/// This is the size of the rayon thread pool the blur runs in, i.e. the global
+/// pool unless called from within another pool. Always `1` without the `rayon`
+/// feature.
+pub fn thread_count() -> usize {
+    #[cfg(feature = "rayon")]
+    return rayon::current_num_threads();
+    #[cfg(not(feature = "rayon"))]
+    return 1;
+}
+
+/// Whether a blur started from the current thread can run on more than one
+/// thread, see [`thread_count`].
+pub fn is_parallel() -> bool {
+    thread_count() > 1
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, running on
+/// the threads of `pool` rather than the global rayon pool.
+///
+/// Useful where the caller already bounds the threads each task may use, e.g.
+/// a server with a pool per request. Otherwise the same as
+/// [`crate::bokeh_blur`].
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+#[cfg(feature = "rayon")]
+pub fn bokeh_blur_in_pool(
+    pool: &ThreadPool,
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    pool.install(|| crate::bokeh_blur(img, width, height, radius, param_set, gamma))
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel entirely on
+/// the current thread, without using rayon.
+///
+/// For small images, handing the kernel components out to other threads can
+/// cost more than it saves. Otherwise the same as [`crate::bokeh_blur`].
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_sequential(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    if is_identity(radius) {
+        return;
+    }
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .convolve_sequential(KernelPlan::for_radius(param_set, radius).kernels())
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+#[cfg(all(test, feature = "rayon"))]
+mod tests {
+    use super::*;
+    use crate::params::KERNEL9_PARAM_SET;
+
+    #[test]
+    fn reports_thread_pool() {
+        assert!(thread_count() >= 1);
+        assert_eq!(is_parallel(), thread_count() > 1);
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            assert_eq!(thread_count(), 1);
+            assert!(!is_parallel());
+        });
+    }
+
+    #[test]
+    fn pooled_and_sequential_blurs_match() {
+        let (w, h) = (9, 7);
+        let original = crate::testing::gradient_image(w, h);
+        let mut expected = original.clone();
+        crate::bokeh_blur(&mut expected, w, h, 2.5, &KERNEL9_PARAM_SET, 3.0);
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let mut pooled = original.clone();
+        bokeh_blur_in_pool(&pool, &mut pooled, w, h, 2.5, &KERNEL9_PARAM_SET, 3.0);
+        let mut sequential = original;
+        bokeh_blur_sequential(&mut sequential, w, h, 2.5, &KERNEL9_PARAM_SET, 3.0);
+
+        for img in [pooled, sequential] {
+            for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+                assert!((a - b).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn result_is_independent_of_thread_count() {
+        let (w, h) = (11, 9);
+        let original = crate::testing::gradient_image(w, h);
+        let mut expected = original.clone();
+        bokeh_blur_sequential(&mut expected, w, h, 2.5, &KERNEL9_PARAM_SET, 3.0);
+
+        for threads in 1..=4 {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let mut img = original.clone();
+            bokeh_blur_in_pool(&pool, &mut img, w, h, 2.5, &KERNEL9_PARAM_SET, 3.0);
+            assert_eq!(img, expected, "{threads} threads");
+        }
+    }
+}
@@ -4,8 +4,15 @@
 //! kernels
 //!
 //! Generated by Mike Pound, found [here](https://github.com/mikepound/convolve/blob/7f579ada8ab8c426cc157bf5f200a94dfdb50830/complex_kernels.py) and [here](https://github.com/mikepound/convolve/issues/2)
+use crate::error::ParamError;
+use alloc::vec;
+use alloc::vec::Vec;
 use paste::paste;
 
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use num::Float;
+
 const KERNEL_SCALES: [f64; 9] = [1.4, 1.2, 1.2, 1.2, 1.2, 1.2, 1.2, 1.2, 1.2];
 const KERNEL1_PARAMS: [f64; 4] = [0.862325, 1.624835, 0.767583, 1.862321];
 const KERNEL2_PARAMS: [f64; 4 * 2] = [
@@ -153,18 +160,62 @@ const KERNEL9_PARAMS: [f64; 4 * 9] = [
     -0.8591239990799346,
 ];
 
+/// Scale of a custom [`KernelParamSet`], that of all the precomputed sets of
+/// more than one component
+const DEFAULT_SCALE: f64 = 1.2;
+
 /// Utility struct for holding and retrieving kernel parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct KernelParamSet<'a> {
     params: &'a [f64],
     pub(crate) scale: f64,
 }
 
+impl<'a> KernelParamSet<'a> {
+    /// Creates a parameter set from custom coefficients, e.g. fitted for a
+    /// different kernel shape.
+    ///
+    /// Each component takes 4 consecutive values of `params`: the decay `a` and
+    /// frequency `b` of the complex Gaussian, then its real and imaginary
+    /// weights, as listed by [`describe_param_set`]. The set borrows `params`,
+    /// so they can be built at runtime. The scale, how far the Gaussians are
+    /// sampled relative to the kernel radius, is `1.2` as for the precomputed
+    /// sets, see [`KernelParamSet::with_scale`].
+    ///
+    /// Returns a [`ParamError`] if `params` is empty, its length isn't a
+    /// multiple of 4 or any of the values isn't finite.
+    pub fn from_params(params: &'a [f64]) -> Result<Self, ParamError> {
+        if params.is_empty() {
+            return Err(ParamError::Empty);
+        }
+        if !params.len().is_multiple_of(4) {
+            return Err(ParamError::IncompleteComponent { len: params.len() });
+        }
+        if let Some(index) = params.iter().position(|p| !p.is_finite()) {
+            return Err(ParamError::NotFinite { index });
+        }
+
+        Ok(Self {
+            params,
+            scale: DEFAULT_SCALE,
+        })
+    }
+
+    /// Sets the scale, how far the Gaussians are sampled relative to the
+    /// kernel radius
+    pub const fn with_scale(self, scale: f64) -> Self {
+        Self { scale, ..self }
+    }
+}
+
 impl KernelParamSet<'_> {
-    pub(crate) const fn a(&self, index: usize) -> f64 {
+    /// Decay of the Gaussian envelope of the `index` kernel
+    pub const fn a(&self, index: usize) -> f64 {
         self.params[4 * index]
     }
 
-    pub(crate) const fn b(&self, index: usize) -> f64 {
+    /// Frequency of the complex oscillation of the `index` kernel
+    pub const fn b(&self, index: usize) -> f64 {
         self.params[4 * index + 1]
     }
 
@@ -178,9 +229,338 @@ impl KernelParamSet<'_> {
         self.params[4 * index + 3]
     }
 
-    pub(crate) const fn num_kernels(&self) -> usize {
+    /// Number of complex Gaussian components, i.e. kernels
+    pub const fn num_kernels(&self) -> usize {
         self.params.len() / 4
     }
+
+    /// How far the Gaussians are sampled relative to the kernel radius
+    pub const fn scale(&self) -> f64 {
+        self.scale
+    }
+}
+
+/// Parameters of a single complex Gaussian component of a kernel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ComponentInfo {
+    /// Decay of the Gaussian envelope
+    pub a: f64,
+    /// Frequency of the complex oscillation
+    pub b: f64,
+    /// Weight of the real part of the component
+    pub real: f64,
+    /// Weight of the imaginary part of the component
+    pub imag: f64,
+}
+
+/// Lists the parameters of each component of `param_set`, in order
+pub fn describe_param_set(param_set: &KernelParamSet) -> Vec<ComponentInfo> {
+    (0..param_set.num_kernels())
+        .map(|i| ComponentInfo {
+            a: param_set.a(i),
+            b: param_set.b(i),
+            real: param_set.real_component(i),
+            imag: param_set.imag_component(i),
+        })
+        .collect()
+}
+
+/// Number of radii the target profile is sampled at when fitting
+const FIT_SAMPLES: usize = 256;
+
+/// Number of times the search is restarted around the best fit so far, as the
+/// simplex can collapse before reaching the minimum
+const FIT_RESTARTS: usize = 4;
+
+/// Fits the coefficients of a kernel of `num_components` complex Gaussians to
+/// `target_profile`, for use with [`KernelParamSet::from_params`].
+///
+/// `target_profile` gives the value the 2-D kernel should take at a distance
+/// from its centre, as a fraction of the blur radius, so the disc of the
+/// precomputed sets is `|r| if r <= 1.0 { 1.0 } else { 0.0 }`. The kernel is
+/// sampled over a square reaching the radius along each axis, so the profile
+/// is read out to `sqrt(2)` at its corners. Its overall scale doesn't matter,
+/// as the kernels are normalised to preserve the brightness when blurring.
+///
+/// The weights of the components are found by linear least squares for each
+/// choice of decays and frequencies, which are themselves searched with the
+/// Nelder-Mead method, starting from the precomputed set of the same number of
+/// components or, beyond 9, from the 9-component set with more components of
+/// higher frequency. The error is weighted by the area of the square at each
+/// distance, as for the kernel convolved. The search takes time growing with
+/// the square of `num_components`, so fit once and keep the result rather than
+/// fitting for each blur.
+///
+/// Returns [`ParamError::Empty`] if `num_components` is `0`, and
+/// [`ParamError::NotFinite`] if the fit isn't finite, e.g. as
+/// `target_profile` isn't.
+pub fn fit_kernel_params(
+    num_components: usize,
+    target_profile: impl Fn(f64) -> f64,
+) -> Result<Vec<f64>, ParamError> {
+    if num_components == 0 {
+        return Err(ParamError::Empty);
+    }
+
+    let samples = FitSamples::new(target_profile);
+    let mut best = initial_decays_and_frequencies(num_components);
+    let mut error = samples.error(&best);
+    for _ in 0..FIT_RESTARTS {
+        (best, error) = nelder_mead(&best, error, |x| samples.error(x));
+    }
+
+    // Weights which can't be solved for are reported as not finite below
+    let weights = samples
+        .weights(&best)
+        .unwrap_or_else(|| vec![f64::NAN; best.len()]);
+    let params = best
+        .chunks_exact(2)
+        .zip(weights.chunks_exact(2))
+        .flat_map(|(ab, w)| [ab[0], ab[1], w[0], w[1]])
+        .collect::<Vec<_>>();
+    match params.iter().position(|p| !p.is_finite()) {
+        Some(index) => Err(ParamError::NotFinite { index }),
+        None => Ok(params),
+    }
+}
+
+/// Decays and frequencies of each component, interleaved, to start the search
+/// for a fit of `num_components` components from
+fn initial_decays_and_frequencies(num_components: usize) -> Vec<f64> {
+    let sets = [
+        &KERNEL1_PARAMS[..],
+        &KERNEL2_PARAMS,
+        &KERNEL3_PARAMS,
+        &KERNEL4_PARAMS,
+        &KERNEL5_PARAMS,
+        &KERNEL6_PARAMS,
+        &KERNEL7_PARAMS,
+        &KERNEL8_PARAMS,
+        &KERNEL9_PARAMS,
+    ];
+    let params = sets[num_components.min(sets.len()) - 1];
+    let mut start = params
+        .chunks_exact(4)
+        .flat_map(|c| [c[0], c[1]])
+        .collect::<Vec<_>>();
+
+    // Continue the spacing of the frequencies of the largest set
+    if num_components > sets.len() {
+        let last = start.len() - 2;
+        let (a, b) = (start[last], start[last + 1]);
+        let step = b - start[last - 1];
+        for n in 1..=num_components - sets.len() {
+            start.extend([a, b + step * n as f64]);
+        }
+    }
+
+    start
+}
+
+/// The target profile sampled at evenly spaced distances from the centre of
+/// the kernel, in the coordinates the Gaussians are sampled in
+struct FitSamples {
+    /// Squared distance of each sample
+    x2: Vec<f64>,
+    /// Value of the profile at each sample
+    target: Vec<f64>,
+    /// Length of the circle through each sample inside the square the kernel
+    /// covers
+    weight: Vec<f64>,
+}
+
+impl FitSamples {
+    fn new(target_profile: impl Fn(f64) -> f64) -> Self {
+        let step = core::f64::consts::SQRT_2 / FIT_SAMPLES as f64;
+        let radii = (0..FIT_SAMPLES).map(|j| (j as f64 + 0.5) * step);
+
+        Self {
+            x2: radii.clone().map(|r| (r * DEFAULT_SCALE).powi(2)).collect(),
+            target: radii.clone().map(target_profile).collect(),
+            weight: radii
+                .map(|r| {
+                    // Beyond the radius, the circle only crosses the corners
+                    let outside = if r > 1.0 { 8.0 * (1.0 / r).acos() } else { 0.0 };
+                    r * (2.0 * core::f64::consts::PI - outside)
+                })
+                .collect(),
+        }
+    }
+
+    /// Value of the real and imaginary part of each component, interleaved, at
+    /// sample `j`
+    fn basis(&self, decays_and_frequencies: &[f64], j: usize) -> Vec<f64> {
+        decays_and_frequencies
+            .chunks_exact(2)
+            .flat_map(|ab| {
+                let envelope = (-ab[0] * self.x2[j]).exp();
+                let (sin, cos) = (ab[1] * self.x2[j]).sin_cos();
+                [envelope * cos, envelope * sin]
+            })
+            .collect()
+    }
+
+    /// Least-squares weights of the real and imaginary part of each component,
+    /// interleaved, or `None` if they can't be solved for
+    fn weights(&self, decays_and_frequencies: &[f64]) -> Option<Vec<f64>> {
+        let n = decays_and_frequencies.len();
+
+        // Normal equations, as the augmented matrix `[A^T W A | A^T W t]`
+        let mut system = vec![vec![0.0; n + 1]; n];
+        for j in 0..self.x2.len() {
+            let basis = self.basis(decays_and_frequencies, j);
+            for (row, &p) in system.iter_mut().zip(&basis) {
+                for (s, &q) in row.iter_mut().zip(&basis) {
+                    *s += self.weight[j] * p * q;
+                }
+                row[n] += self.weight[j] * p * self.target[j];
+            }
+        }
+
+        // The components can be nearly dependent, so damp the solution
+        // slightly rather than let the weights grow without bound
+        let trace = (0..n).map(|i| system[i][i]).sum::<f64>();
+        for (i, row) in system.iter_mut().enumerate() {
+            row[i] += 1e-10 * trace / n as f64;
+        }
+
+        solve(system)
+    }
+
+    /// Weighted squared error of the best fit with `decays_and_frequencies`,
+    /// infinite for decays which aren't positive, as the kernel would grow
+    /// away from its centre
+    fn error(&self, decays_and_frequencies: &[f64]) -> f64 {
+        if decays_and_frequencies
+            .chunks_exact(2)
+            .any(|ab| ab[0] <= 0.0)
+        {
+            return f64::INFINITY;
+        }
+        let Some(weights) = self.weights(decays_and_frequencies) else {
+            return f64::INFINITY;
+        };
+
+        let error = (0..self.x2.len())
+            .map(|j| {
+                let fit = self
+                    .basis(decays_and_frequencies, j)
+                    .iter()
+                    .zip(&weights)
+                    .map(|(p, w)| p * w)
+                    .sum::<f64>();
+                self.weight[j] * (fit - self.target[j]).powi(2)
+            })
+            .sum::<f64>();
+
+        if error.is_finite() {
+            error
+        } else {
+            f64::INFINITY
+        }
+    }
+}
+
+/// Solves the augmented matrix `system` by Gaussian elimination with partial
+/// pivoting, or `None` if it is singular
+fn solve(mut system: Vec<Vec<f64>>) -> Option<Vec<f64>> {
+    let n = system.len();
+    for col in 0..n {
+        let pivot =
+            (col..n).max_by(|&i, &j| system[i][col].abs().total_cmp(&system[j][col].abs()))?;
+        if system[pivot][col] == 0.0 || !system[pivot][col].is_finite() {
+            return None;
+        }
+        system.swap(col, pivot);
+
+        let (above, below) = system.split_at_mut(col + 1);
+        let pivot = &above[col];
+        for row in below {
+            let factor = row[col] / pivot[col];
+            for (r, p) in row[col..].iter_mut().zip(&pivot[col..]) {
+                *r -= factor * p;
+            }
+        }
+    }
+
+    let mut solution = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum = (row + 1..n)
+            .map(|k| system[row][k] * solution[k])
+            .sum::<f64>();
+        solution[row] = (system[row][n] - sum) / system[row][row];
+    }
+
+    Some(solution)
+}
+
+/// Minimises `f` by the Nelder-Mead method, from a simplex around `start`
+/// where `f` is `value`. Returns the best point found and the value there,
+/// which is never worse than `start`.
+fn nelder_mead(start: &[f64], value: f64, f: impl Fn(&[f64]) -> f64) -> (Vec<f64>, f64) {
+    let dims = start.len();
+    let mut simplex = vec![(start.to_vec(), value)];
+    for i in 0..dims {
+        let mut point = start.to_vec();
+        point[i] += 0.1 * point[i].abs().max(0.5);
+        let value = f(&point);
+        simplex.push((point, value));
+    }
+
+    let along = |from: &[f64], to: &[f64], t: f64| {
+        from.iter()
+            .zip(to)
+            .map(|(a, b)| a + t * (b - a))
+            .collect::<Vec<_>>()
+    };
+
+    for _ in 0..200 * dims {
+        simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let (best, worst) = (simplex[0].1, simplex[dims].1);
+        if worst - best <= 1e-12 * best.abs() {
+            break;
+        }
+
+        // Reflect the worst point through the centroid of the others
+        let mut centroid = vec![0.0; dims];
+        for (point, _) in &simplex[..dims] {
+            for (c, p) in centroid.iter_mut().zip(point) {
+                *c += p / dims as f64;
+            }
+        }
+        let reflected = along(&centroid, &simplex[dims].0, -1.0);
+        let reflected_value = f(&reflected);
+
+        if reflected_value < best {
+            let expanded = along(&centroid, &simplex[dims].0, -2.0);
+            let expanded_value = f(&expanded);
+            simplex[dims] = if expanded_value < reflected_value {
+                (expanded, expanded_value)
+            } else {
+                (reflected, reflected_value)
+            };
+        } else if reflected_value < simplex[dims - 1].1 {
+            simplex[dims] = (reflected, reflected_value);
+        } else {
+            let contracted = along(&centroid, &simplex[dims].0, 0.5);
+            let contracted_value = f(&contracted);
+            if contracted_value < worst {
+                simplex[dims] = (contracted, contracted_value);
+            } else {
+                // Shrink everything towards the best point
+                let first = simplex[0].0.clone();
+                for (point, value) in simplex.iter_mut().skip(1) {
+                    *point = along(&first, point, 0.5);
+                    *value = f(point);
+                }
+            }
+        }
+    }
+
+    simplex
+        .into_iter()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .expect("the simplex has at least one point")
 }
 
 macro_rules! param_set {
@@ -205,3 +585,85 @@ param_set!(6);
 param_set!(7);
 param_set!(8);
 param_set!(9);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_each_component() {
+        let components = describe_param_set(&KERNEL9_PARAM_SET);
+
+        assert_eq!(components.len(), 9);
+        for (c, raw) in components.iter().zip(KERNEL9_PARAMS.chunks(4)) {
+            assert_eq!([c.a, c.b, c.real, c.imag], raw);
+        }
+    }
+
+    fn disc(r: f64) -> f64 {
+        if r <= 1.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    #[test]
+    fn fits_improve_on_the_precomputed_sets() {
+        let samples = FitSamples::new(disc);
+        for (n, shipped) in [(1, &KERNEL1_PARAMS[..]), (2, &KERNEL2_PARAMS)] {
+            let fitted = fit_kernel_params(n, disc).unwrap();
+            assert_eq!(fitted.len(), 4 * n);
+
+            let ab = |p: &[f64]| p.chunks(4).flat_map(|c| [c[0], c[1]]).collect::<Vec<_>>();
+            let (fit_error, shipped_error) =
+                (samples.error(&ab(&fitted)), samples.error(&ab(shipped)));
+            assert!(fit_error <= shipped_error);
+
+            let param_set = KernelParamSet::from_params(&fitted).unwrap();
+            assert!((crate::kernel_dc_gain(&param_set, 5.0, 5) - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn fits_other_profiles() {
+        let cone = |r: f64| (1.0 - r).max(0.0);
+        let fitted = fit_kernel_params(3, cone).unwrap();
+        let profile = |r: f64| {
+            let x2 = (r * DEFAULT_SCALE).powi(2);
+            fitted
+                .chunks(4)
+                .map(|c| (-c[0] * x2).exp() * (c[2] * (c[1] * x2).cos() + c[3] * (c[1] * x2).sin()))
+                .sum::<f64>()
+        };
+        for r in [0.1, 0.3, 0.5, 0.7, 0.9, 1.2] {
+            assert!((profile(r) - cone(r)).abs() < 0.05, "{r}: {}", profile(r));
+        }
+
+        assert_eq!(fit_kernel_params(0, disc), Err(ParamError::Empty));
+        assert!(matches!(
+            fit_kernel_params(1, |_| f64::NAN),
+            Err(ParamError::NotFinite { .. })
+        ));
+    }
+
+    #[test]
+    fn builds_custom_param_sets() {
+        let params = KERNEL2_PARAMS.to_vec();
+        let custom = KernelParamSet::from_params(&params).unwrap();
+
+        assert_eq!(custom, KERNEL2_PARAM_SET);
+        assert_eq!(custom.num_kernels(), 2);
+        assert_eq!(custom.with_scale(1.4).scale(), 1.4);
+
+        assert_eq!(KernelParamSet::from_params(&[]), Err(ParamError::Empty));
+        assert_eq!(
+            KernelParamSet::from_params(&params[..6]),
+            Err(ParamError::IncompleteComponent { len: 6 })
+        );
+        assert_eq!(
+            KernelParamSet::from_params(&[1.0, 2.0, f64::NAN, 0.0]),
+            Err(ParamError::NotFinite { index: 2 })
+        );
+    }
+}
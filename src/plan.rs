@@ -0,0 +1,174 @@
+//! Kernels built once and reused across many blurs.
+use crate::complex::{
+    kernel_gaussian_components_sized, recommended_kernel_radius, weight_kernels, ComplexImage,
+    Kernel,
+};
+use crate::params::KernelParamSet;
+use alloc::vec::Vec;
+use num::Complex;
+
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use num::Float;
+
+/// The kernels of a blur, built once and reused for many images with
+/// [`bokeh_blur_with_plan`].
+///
+/// Every call to [`crate::bokeh_blur`] builds and normalises its kernels from
+/// scratch. When blurring many images with the same settings, e.g. the frames
+/// of a video, building a plan up front takes that work out of the loop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KernelPlan {
+    /// Pairs of (horizontal, vertical) kernels, weighted ready for convolving
+    kernels: Vec<(Kernel, Kernel)>,
+}
+
+impl KernelPlan {
+    /// Builds the kernels of a disc-shaped kernel with radius `radius`, built
+    /// from components corresponding to `param_set`, sampled `kernel_radius`
+    /// pixels either side of their centres.
+    ///
+    /// The kernels are normalised so the blur preserves the brightness of the
+    /// image. [`crate::bokeh_blur`] uses the
+    /// [`crate::recommended_kernel_radius`], see [`KernelPlan::for_radius`].
+    pub fn new(param_set: &KernelParamSet, radius: f64, kernel_radius: usize) -> Self {
+        Self {
+            kernels: weight_kernels(
+                param_set,
+                kernel_gaussian_components_sized(param_set, radius, kernel_radius),
+            ),
+        }
+    }
+
+    /// Builds the same kernels as [`crate::bokeh_blur`] uses for a blur of
+    /// radius `radius`, built from components corresponding to `param_set`.
+    pub fn for_radius(param_set: &KernelParamSet, radius: f64) -> Self {
+        Self::new(param_set, radius, recommended_kernel_radius(radius))
+    }
+
+    /// Number of components the kernel is built from
+    pub fn num_components(&self) -> usize {
+        self.kernels.len()
+    }
+
+    /// Number of pixels either side of the centre the kernels are sampled to
+    pub fn kernel_radius(&self) -> usize {
+        self.kernel_len() / 2
+    }
+
+    /// Length of each 1-D kernel, `2 * kernel_radius + 1`
+    pub fn kernel_len(&self) -> usize {
+        self.kernels
+            .first()
+            .map_or(1, |(horizontal, _)| horizontal.len())
+    }
+
+    /// Sum of the combined 2-D kernel, i.e. the factor the brightness of an
+    /// image is scaled by when blurred, `1.0` up to rounding for a usable
+    /// param set, see [`crate::kernel_dc_gain`]
+    pub fn dc_gain(&self) -> f64 {
+        self.kernels
+            .iter()
+            .map(|(horizontal, vertical)| {
+                (horizontal.iter().sum::<Complex<f64>>() * vertical.iter().sum::<Complex<f64>>()).re
+            })
+            .sum()
+    }
+
+    pub(crate) fn kernels(&self) -> &[(Kernel, Kernel)] {
+        &self.kernels
+    }
+
+    /// Whether the kernels are a single pixel, so leave the image unchanged
+    pub(crate) fn is_identity(&self) -> bool {
+        self.kernels
+            .iter()
+            .all(|(horizontal, vertical)| horizontal.len() == 1 && vertical.len() == 1)
+    }
+}
+
+/// Blurs an image using the prepared kernels of `plan`.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The exposure can be modified using `gamma`, set to `1.0` for no
+/// change. The same as [`crate::bokeh_blur`] for a plan built with
+/// [`KernelPlan::for_radius`].
+pub fn bokeh_blur_with_plan(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    plan: &KernelPlan,
+    gamma: f64,
+) {
+    if plan.is_identity() {
+        return;
+    }
+
+    for (n, rgba) in ComplexImage::from_slice(img, width, height, gamma)
+        .convolve(plan.kernels())
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bokeh_blur;
+    use crate::params::KERNEL9_PARAM_SET;
+    use crate::testing::{checkerboard, gradient_image};
+
+    #[test]
+    fn describes_its_kernels() {
+        let plan = KernelPlan::for_radius(&KERNEL9_PARAM_SET, 6.5);
+        assert_eq!(plan.num_components(), 9);
+        assert_eq!(plan.kernel_radius(), 7);
+        assert_eq!(plan.kernel_len(), 15);
+        assert!((plan.dc_gain() - 1.0).abs() < 1e-9);
+
+        let plan = KernelPlan::new(&crate::params::KERNEL3_PARAM_SET, 6.5, 2);
+        assert_eq!(plan.num_components(), 3);
+        assert_eq!((plan.kernel_radius(), plan.kernel_len()), (2, 5));
+        assert!(
+            (plan.dc_gain() - crate::kernel_dc_gain(&crate::params::KERNEL3_PARAM_SET, 6.5, 2))
+                .abs()
+                < 1e-9
+        );
+    }
+
+    #[test]
+    fn plan_matches_one_shot_blurs() {
+        let (w, h) = (10, 7);
+        let plan = KernelPlan::for_radius(&KERNEL9_PARAM_SET, 2.5);
+
+        for frame in [checkerboard(w, h, 2), gradient_image(w, h)] {
+            let mut img = frame.clone();
+            bokeh_blur_with_plan(&mut img, w, h, &plan, 3.0);
+            let mut expected = frame;
+            bokeh_blur(&mut expected, w, h, 2.5, &KERNEL9_PARAM_SET, 3.0);
+
+            for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+                assert!((a - b).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn kernel_radius_sets_the_length() {
+        let plan = KernelPlan::new(&KERNEL9_PARAM_SET, 2.5, 6);
+
+        assert_eq!(plan.kernels().len(), KERNEL9_PARAM_SET.num_kernels());
+        assert!(plan
+            .kernels()
+            .iter()
+            .all(|(h, v)| h.len() == 13 && v.len() == 13));
+        assert_eq!(
+            KernelPlan::for_radius(&KERNEL9_PARAM_SET, 2.5),
+            KernelPlan::new(&KERNEL9_PARAM_SET, 2.5, 3)
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Polygonal apertures, giving the hexagonal and octagonal Bokeh of lenses
+//! with a few straight aperture blades.
+use crate::complex::{is_identity, recommended_kernel_radius};
+use alloc::vec::Vec;
+use core::f64::consts::PI;
+
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use num::Float;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+/// Number of samples along each axis of a pixel when measuring how much of the
+/// pixel the polygon covers
+const SUPERSAMPLES: usize = 4;
+
+/// Builds the 2-D kernel of a regular polygon with `sides` sides, the shape
+/// of an aperture with that many blades.
+///
+/// The corners of the polygon lie `radius` pixels from its centre, so it fits
+/// inside the disc of the same radius. With a `rotation` of `0.0` a corner
+/// points along the rows of the image, and the polygon is turned clockwise by
+/// `rotation` radians, as rows run down the image. Fewer than 3 sides are
+/// treated as 3. Each value is the fraction of the pixel inside the polygon,
+/// so the edges are anti-aliased, and the values are normalised to sum to
+/// `1.0` to preserve the brightness.
+///
+/// Returns the kernel stored row by row along with its side length,
+/// `2 * kernel_radius + 1` for the [`crate::recommended_kernel_radius`].
+pub fn polygon_kernel(radius: f64, sides: usize, rotation: f64) -> (Vec<f64>, usize) {
+    let kernel_radius = recommended_kernel_radius(radius);
+    let side = 2 * kernel_radius + 1;
+    let sides = sides.max(3);
+
+    // A point is inside if it is no further along the normal of any edge than
+    // the apothem
+    let apothem = radius * (PI / sides as f64).cos();
+    let normals = (0..sides)
+        .map(|k| (rotation + PI * (2 * k + 1) as f64 / sides as f64).sin_cos())
+        .collect::<Vec<_>>();
+    let inside = |x: f64, y: f64| {
+        normals
+            .iter()
+            .all(|&(sin, cos)| x * cos + y * sin <= apothem)
+    };
+    let offset = |i: usize| (i as f64 + 0.5) / SUPERSAMPLES as f64 - 0.5;
+
+    let mut kernel = (0..side * side)
+        .map(|n| {
+            let x = (n % side) as f64 - kernel_radius as f64;
+            let y = (n / side) as f64 - kernel_radius as f64;
+            (0..SUPERSAMPLES * SUPERSAMPLES)
+                .filter(|s| inside(x + offset(s % SUPERSAMPLES), y + offset(s / SUPERSAMPLES)))
+                .count() as f64
+        })
+        .collect::<Vec<_>>();
+
+    let sum = kernel.iter().sum::<f64>();
+    if sum > 0.0 {
+        for k in kernel.iter_mut() {
+            *k /= sum;
+        }
+    } else {
+        // Too small to cover any samples, so leave the image unchanged
+        kernel[side * side / 2] = 1.0;
+    }
+
+    (kernel, side)
+}
+
+/// Convolves an image with the 2-D `kernel` of side length `side`, stored row
+/// by row, after applying `gamma` to its pixels.
+///
+/// Pixels beyond the edges of the image are zero. The result is linear, i.e.
+/// the gamma still needs inverting.
+pub(crate) fn convolve_2d(
+    img: &[[f64; 4]],
+    width: usize,
+    height: usize,
+    kernel: &[f64],
+    side: usize,
+    gamma: f64,
+) -> Vec<[f64; 4]> {
+    let input = img
+        .iter()
+        .map(|p| p.map(|c| c.powf(gamma)))
+        .collect::<Vec<_>>();
+
+    // Only the taps inside the shape contribute. The kernel is flipped as it
+    // is convolved, so an impulse spreads into the shape of the kernel itself
+    let half = side / 2;
+    let taps = kernel
+        .iter()
+        .enumerate()
+        .filter(|(_, &k)| k != 0.0)
+        .map(|(n, &k)| (n % side, n / side, k))
+        .collect::<Vec<_>>();
+
+    let row = |y: usize| {
+        (0..width)
+            .map(|x| {
+                let mut sum = [0.0; 4];
+                for &(dx, dy, k) in &taps {
+                    let (Some(ix), Some(iy)) =
+                        ((x + half).checked_sub(dx), (y + half).checked_sub(dy))
+                    else {
+                        continue;
+                    };
+                    if ix < width && iy < height {
+                        for (s, c) in sum.iter_mut().zip(input[iy * width + ix]) {
+                            *s += k * c;
+                        }
+                    }
+                }
+                sum
+            })
+            .collect::<Vec<_>>()
+    };
+
+    #[cfg(feature = "rayon")]
+    return (0..height).into_par_iter().flat_map_iter(row).collect();
+    #[cfg(not(feature = "rayon"))]
+    return (0..height).flat_map(row).collect();
+}
+
+/// Blurs an image using a regular polygon as the kernel, producing the
+/// hexagonal or octagonal Bokeh of a lens with `sides` aperture blades.
+///
+/// A polygon isn't separable, so unlike the disc of [`crate::bokeh_blur`]
+/// it can't be approximated by 1-D passes. Instead the image is convolved
+/// directly with the 2-D kernel of [`polygon_kernel`], which takes time
+/// proportional to the square of `radius` for each pixel, so is much slower
+/// for large radii.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The corners of the polygon lie `radius` pixels from its centre,
+/// and it is turned clockwise by `rotation` radians, see [`polygon_kernel`].
+/// The exposure can be modified using `gamma`, set to `1.0` for no change. A
+/// `radius` of `0.0` leaves the image exactly as it is.
+pub fn bokeh_blur_polygon(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    sides: usize,
+    rotation: f64,
+    gamma: f64,
+) {
+    debug_assert_eq!(img.len(), width * height);
+    if is_identity(radius) {
+        return;
+    }
+
+    let (kernel, side) = polygon_kernel(radius, sides, rotation);
+
+    for (n, rgba) in convolve_2d(img, width, height, &kernel, side, gamma)
+        .into_iter()
+        .enumerate()
+    {
+        // Clamp any values from floating point ops
+        img[n] = rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::impulse_image;
+
+    #[test]
+    fn hexagon_kernel_shape() {
+        let (kernel, side) = polygon_kernel(6.0, 6, 0.0);
+        assert_eq!(side, 13);
+        assert!((kernel.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+
+        // Corners point along the rows, flat edges face up and down
+        let at = |x: isize, y: isize| kernel[((y + 6) as usize) * side + (x + 6) as usize];
+        assert!(at(5, 0) > 0.0);
+        assert_eq!(at(0, -6), 0.0);
+        assert_eq!(at(0, 6), 0.0);
+        for y in -6..=6 {
+            for x in -6..=6 {
+                assert_eq!(at(x, y), at(-x, y));
+                assert_eq!(at(x, y), at(x, -y));
+            }
+        }
+
+        // Turning by a quarter turn puts the corners up and down instead
+        let (turned, _) = polygon_kernel(6.0, 6, PI / 2.0);
+        assert!(turned[side + 6] > 0.0);
+        assert_eq!(turned[6 * side], 0.0);
+    }
+
+    #[test]
+    fn impulse_takes_the_shape_of_the_kernel() {
+        let (w, h) = (15, 15);
+        let mut img = impulse_image(w, h);
+        bokeh_blur_polygon(&mut img, w, h, 5.0, 5, 0.3, 1.0);
+
+        let (kernel, side) = polygon_kernel(5.0, 5, 0.3);
+        for y in 0..side {
+            for x in 0..side {
+                let pixel = img[(y + 2) * w + x + 2];
+                assert!((pixel[0] - 255.0 * kernel[y * side + x]).abs() < 1e-9);
+            }
+        }
+
+        let mut img = impulse_image(w, h);
+        bokeh_blur_polygon(&mut img, w, h, 0.0, 6, 0.0, 3.0);
+        for (a, b) in img
+            .iter()
+            .flatten()
+            .zip(impulse_image(w, h).iter().flatten())
+        {
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+}
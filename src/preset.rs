@@ -0,0 +1,52 @@
+//! Named combinations of blur settings.
+use crate::params::{KernelParamSet, KERNEL3_PARAM_SET, KERNEL5_PARAM_SET, KERNEL9_PARAM_SET};
+
+/// A named combination of blur settings, applied with
+/// [`crate::Blur::apply_preset`].
+///
+/// | Preset       | Radius scale | Components | Gamma |
+/// |--------------|--------------|------------|-------|
+/// | `Portrait`   | `1.0`        | 5          | `3.0` |
+/// | `Cinematic`  | `1.5`        | 9          | `4.0` |
+/// | `Dreamy`     | `2.0`        | 3          | `1.5` |
+///
+/// The radius scale multiplies the base radius passed when applying the
+/// preset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BokehPreset {
+    /// Moderate background blur with well-defined highlights
+    Portrait,
+    /// Wide, high quality blur with pronounced highlights
+    Cinematic,
+    /// Very soft, low contrast blur
+    Dreamy,
+}
+
+impl BokehPreset {
+    /// Factor the base radius is multiplied by
+    pub const fn radius_scale(self) -> f64 {
+        match self {
+            Self::Portrait => 1.0,
+            Self::Cinematic => 1.5,
+            Self::Dreamy => 2.0,
+        }
+    }
+
+    /// Parameter set used to build the kernel
+    pub const fn param_set(self) -> &'static KernelParamSet<'static> {
+        match self {
+            Self::Portrait => &KERNEL5_PARAM_SET,
+            Self::Cinematic => &KERNEL9_PARAM_SET,
+            Self::Dreamy => &KERNEL3_PARAM_SET,
+        }
+    }
+
+    /// Gamma used to modify the exposure
+    pub const fn gamma(self) -> f64 {
+        match self {
+            Self::Portrait => 3.0,
+            Self::Cinematic => 4.0,
+            Self::Dreamy => 1.5,
+        }
+    }
+}
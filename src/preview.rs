@@ -0,0 +1,159 @@
+//! A fast, approximate blur for previewing the Bokeh blur interactively.
+use alloc::vec;
+
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use num::Float;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+/// Number of box blurs applied along each axis by [`bokeh_blur_preview`].
+/// Repeated box blurs quickly converge towards a Gaussian, and three are
+/// already smooth enough to hide the square shape of each box.
+const PREVIEW_PASSES: usize = 3;
+
+/// Blurs an image with a cheap approximation of the blur of [`crate::bokeh_blur`],
+/// for showing a preview instantly, e.g. while a slider is dragged, before
+/// computing the accurate blur.
+///
+/// Each axis is blurred by three box blurs in turn, each a running sum along
+/// the rows or columns, so the cost per pixel is the same whatever the
+/// `radius`. The boxes are sized to spread the image as far as a disc of
+/// radius `radius`. The result is soft, like a Gaussian, rather than showing
+/// the disc-shaped highlights of the Bokeh blur, and radii under √3, about
+/// `1.73`, are too small for a box and leave the image unchanged. As with
+/// [`crate::bokeh_blur`] the pixels beyond the edges of the image are black.
+/// No gamma is applied.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image.
+pub fn bokeh_blur_preview(img: &mut [[f64; 4]], width: usize, height: usize, radius: f64) {
+    debug_assert_eq!(img.len(), width * height);
+    let half_width = box_half_width(radius);
+    if half_width == 0 || img.is_empty() {
+        return;
+    }
+
+    for _ in 0..PREVIEW_PASSES {
+        box_blur_rows(img, width, half_width);
+    }
+    let mut transposed = vec![[0.0; 4]; width * height];
+    transpose(img, width, height, &mut transposed);
+    for _ in 0..PREVIEW_PASSES {
+        box_blur_rows(&mut transposed, height, half_width);
+    }
+    transpose(&transposed, height, width, img);
+
+    for pixel in img.iter_mut() {
+        // Clamp any values from floating point ops
+        *pixel = pixel.map(|i| i.clamp(0.0, 255.0));
+    }
+}
+
+/// Half the width of the boxes spreading the image as far as a disc of radius
+/// `radius`.
+///
+/// A disc of radius `r` spreads the image with a variance of `r^2 / 4` along
+/// each axis, while a box covering `2k + 1` pixels has a variance of
+/// `k (k + 1) / 3`, so [`PREVIEW_PASSES`] boxes match the disc when
+/// `k (k + 1) = r^2 / 4`.
+fn box_half_width(radius: f64) -> usize {
+    let variance = radius * radius / 4.0 * 3.0 / PREVIEW_PASSES as f64;
+    (((1.0 + 4.0 * variance).sqrt() - 1.0) / 2.0).round() as usize
+}
+
+/// Replaces each pixel of each row of `width` pixels with the average of the
+/// `2 * half_width + 1` pixels around it, those beyond the ends being black
+fn box_blur_rows(img: &mut [[f64; 4]], width: usize, half_width: usize) {
+    let scale = 1.0 / (2 * half_width + 1) as f64;
+    let blur_row = |row: &mut [[f64; 4]]| {
+        let line = row.to_vec();
+        let mut sum = [0.0; 4];
+        for pixel in &line[..(half_width + 1).min(width)] {
+            add(&mut sum, pixel, 1.0);
+        }
+        for (i, out) in row.iter_mut().enumerate() {
+            *out = sum.map(|s| s * scale);
+            if let Some(entering) = line.get(i + half_width + 1) {
+                add(&mut sum, entering, 1.0);
+            }
+            if let Some(leaving) = i.checked_sub(half_width).map(|j| &line[j]) {
+                add(&mut sum, leaving, -1.0);
+            }
+        }
+    };
+
+    #[cfg(feature = "rayon")]
+    img.par_chunks_exact_mut(width).for_each(blur_row);
+    #[cfg(not(feature = "rayon"))]
+    img.chunks_exact_mut(width).for_each(blur_row);
+}
+
+/// Adds `pixel` scaled by `sign` to `sum`
+fn add(sum: &mut [f64; 4], pixel: &[f64; 4], sign: f64) {
+    for (s, c) in sum.iter_mut().zip(pixel) {
+        *s += sign * c;
+    }
+}
+
+/// Writes the `width` by `height` image `img` to `out` with its rows and
+/// columns swapped
+fn transpose(img: &[[f64; 4]], width: usize, height: usize, out: &mut [[f64; 4]]) {
+    for (n, pixel) in img.iter().enumerate() {
+        out[(n % width) * height + n / width] = *pixel;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::impulse_image;
+
+    #[test]
+    fn spreads_like_the_disc() {
+        let (w, h) = (41, 41);
+        let mut img = impulse_image(w, h);
+        bokeh_blur_preview(&mut img, w, h, 9.0);
+
+        // Brightness is kept away from the edges, and the spread along each
+        // axis matches the variance of the disc
+        let total = img.iter().map(|p| p[0]).sum::<f64>();
+        assert!((total - 255.0).abs() < 1e-9);
+        let variance = img
+            .iter()
+            .enumerate()
+            .map(|(n, p)| (n % w).abs_diff(w / 2).pow(2) as f64 * p[0] / total)
+            .sum::<f64>();
+        assert!((variance - 20.25).abs() < 1.0, "{variance}");
+        for n in 0..w * h {
+            let mirrored = (n / w) * w + w - 1 - n % w;
+            assert!((img[n][0] - img[mirrored][0]).abs() < 1e-9);
+        }
+
+        // The smallest box covers three pixels, just above a radius of √3
+        let mut img = impulse_image(w, h);
+        bokeh_blur_preview(&mut img, w, h, 1.7);
+        assert_eq!(img, impulse_image(w, h));
+        bokeh_blur_preview(&mut img, w, h, 1.9);
+        assert_ne!(img, impulse_image(w, h));
+        assert_eq!(box_half_width(1.9), 1);
+    }
+
+    #[test]
+    fn flat_images_stay_flat_away_from_the_edges() {
+        let (w, h) = (30, 20);
+        let mut img = vec![[100.0, 50.0, 200.0, 255.0]; w * h];
+        bokeh_blur_preview(&mut img, w, h, 4.0);
+
+        let half = 3 * box_half_width(4.0);
+        for y in half..h - half {
+            for x in half..w - half {
+                for (a, b) in img[y * w + x].iter().zip([100.0, 50.0, 200.0, 255.0]) {
+                    assert!((a - b).abs() < 1e-9);
+                }
+            }
+        }
+        assert!(img[0][0] < 100.0);
+    }
+}
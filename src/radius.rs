@@ -0,0 +1,63 @@
+//! The size of a blur, given in whichever units are most natural.
+use crate::complex::recommended_kernel_radius;
+
+/// The size of a disc-shaped blur, constructed from a physically meaningful
+/// quantity so that it's clear what the number means.
+///
+/// The blurs taking an `f64` radius, e.g. [`crate::bokeh_blur`], expect the
+/// radius of the disc in pixels: a single bright pixel spreads into a disc
+/// that many pixels from its centre to its edge. Other blurs are often sized
+/// by the standard deviation, sigma, of a Gaussian instead, which is a much
+/// smaller number for the same apparent amount of blur. [`BlurRadius::sigma`]
+/// converts from that, so a Bokeh blur can be swapped in for a Gaussian one
+/// without retuning.
+/// ```
+/// use bokeh::{params::KERNEL9_PARAM_SET, BlurRadius, BokehBlur};
+///
+/// let radius = BlurRadius::sigma(2.5);
+/// assert_eq!(radius, BlurRadius::pixels(5.0));
+/// assert_eq!(radius.kernel_radius(), 5);
+///
+/// let blur = BokehBlur::builder().blur_radius(radius).build();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct BlurRadius {
+    /// Radius of the disc in pixels
+    pixels: f64,
+}
+
+impl BlurRadius {
+    /// A disc `radius` pixels from its centre to its edge
+    pub const fn pixels(radius: f64) -> Self {
+        Self { pixels: radius }
+    }
+
+    /// A disc spreading the image as far as a Gaussian with standard deviation
+    /// `sigma` pixels.
+    ///
+    /// The light passing through a disc of radius `r` is spread with a
+    /// standard deviation of `r / 2` along each axis, so the disc has twice
+    /// the radius of the Gaussian's sigma.
+    pub const fn sigma(sigma: f64) -> Self {
+        Self {
+            pixels: 2.0 * sigma,
+        }
+    }
+
+    /// Radius of the disc in pixels, as taken by [`crate::bokeh_blur`]
+    pub const fn to_pixels(self) -> f64 {
+        self.pixels
+    }
+
+    /// Standard deviation in pixels of the Gaussian spreading the image as
+    /// far as the disc
+    pub const fn to_sigma(self) -> f64 {
+        self.pixels / 2.0
+    }
+
+    /// Number of pixels either side of the centre the kernel is sampled to,
+    /// the [`crate::recommended_kernel_radius`]
+    pub fn kernel_radius(self) -> usize {
+        recommended_kernel_radius(self.pixels)
+    }
+}
@@ -0,0 +1,99 @@
+//! The shape of the aperture a blur simulates.
+use crate::params::KernelParamSet;
+use crate::polygon::bokeh_blur_polygon;
+
+/// The shape out of focus highlights are blurred into, set by the aperture of
+/// the lens.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum KernelShape {
+    /// A disc, from a perfectly round aperture, approximated by separable
+    /// complex Gaussians as [`crate::bokeh_blur`]
+    #[default]
+    Disc,
+    /// A regular polygon, from an aperture with straight `blades`, e.g. 6 for
+    /// a hexagon, turned clockwise by `rotation` radians, see
+    /// [`crate::polygon_kernel`]. Convolved directly in 2-D, so much slower
+    /// than a disc for large radii.
+    Polygon {
+        /// Number of aperture blades, i.e. sides of the polygon
+        blades: usize,
+        /// Clockwise rotation of the polygon in radians
+        rotation: f64,
+    },
+}
+
+/// Blurs an image using a kernel of the given `shape`, producing the Bokeh of
+/// a lens with that aperture.
+///
+/// A [`KernelShape::Disc`] is the same as [`crate::bokeh_blur`], built from
+/// components corresponding to `param_set`, and a [`KernelShape::Polygon`]
+/// the same as [`bokeh_blur_polygon`], which doesn't use `param_set`. In both
+/// cases the shape fits inside the disc of radius `radius`.
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The exposure can be modified using `gamma`, set to `1.0` for no
+/// change.
+pub fn bokeh_blur_shaped(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    shape: KernelShape,
+    param_set: &KernelParamSet,
+    gamma: f64,
+) {
+    match shape {
+        KernelShape::Disc => crate::bokeh_blur(img, width, height, radius, param_set, gamma),
+        KernelShape::Polygon { blades, rotation } => {
+            bokeh_blur_polygon(img, width, height, radius, blades, rotation, gamma)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::KERNEL9_PARAM_SET;
+    use crate::testing::gradient_image;
+    use alloc::vec;
+
+    #[test]
+    fn dispatches_on_the_shape() {
+        let (w, h) = (11, 9);
+        let original = gradient_image(w, h);
+        let blur = |shape| {
+            let mut img = original.clone();
+            bokeh_blur_shaped(&mut img, w, h, 3.0, shape, &KERNEL9_PARAM_SET, 2.2);
+            img
+        };
+
+        let mut disc = original.clone();
+        crate::bokeh_blur(&mut disc, w, h, 3.0, &KERNEL9_PARAM_SET, 2.2);
+        assert_eq!(blur(KernelShape::default()), disc);
+
+        let mut hexagon = original.clone();
+        bokeh_blur_polygon(&mut hexagon, w, h, 3.0, 6, 0.5, 2.2);
+        let shape = KernelShape::Polygon {
+            blades: 6,
+            rotation: 0.5,
+        };
+        assert_eq!(blur(shape), hexagon);
+    }
+
+    #[test]
+    fn zero_radius_leaves_pixels_untouched() {
+        let original = vec![[100.3; 4]; 12];
+        for shape in [
+            KernelShape::Disc,
+            KernelShape::Polygon {
+                blades: 6,
+                rotation: 0.0,
+            },
+        ] {
+            let mut img = original.clone();
+            bokeh_blur_shaped(&mut img, 4, 3, 0.0, shape, &KERNEL9_PARAM_SET, 2.2);
+            assert_eq!(img, original, "{shape:?}");
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Helpers generating simple images for testing blurs.
+//!
+//! Requires the `testing` feature. Each image is stored row by row, with every
+//! channel of a pixel, alpha included, set to the same value.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Black image with a single white pixel at its centre, at
+/// (`width / 2`, `height / 2`)
+pub fn impulse_image(width: usize, height: usize) -> Vec<[f64; 4]> {
+    let mut img = vec![[0.0; 4]; width * height];
+    if let Some(centre) = img.get_mut((height / 2) * width + width / 2) {
+        *centre = [255.0; 4];
+    }
+    img
+}
+
+/// Image going from black in its left column to white in its right column
+pub fn gradient_image(width: usize, height: usize) -> Vec<[f64; 4]> {
+    let step = 255.0 / width.saturating_sub(1).max(1) as f64;
+    (0..width * height)
+        .map(|n| [(n % width) as f64 * step; 4])
+        .collect()
+}
+
+/// Image of alternating white and black `cell` by `cell` squares, starting with
+/// a white square in the top-left corner
+pub fn checkerboard(width: usize, height: usize, cell: usize) -> Vec<[f64; 4]> {
+    let cell = cell.max(1);
+    (0..width * height)
+        .map(|n| {
+            let (x, y) = (n % width / cell, n / width / cell);
+            if (x + y) % 2 == 0 {
+                [255.0; 4]
+            } else {
+                [0.0; 4]
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_images() {
+        assert_eq!(
+            impulse_image(3, 2),
+            [[0.0; 4], [0.0; 4], [0.0; 4], [0.0; 4], [255.0; 4], [0.0; 4]]
+        );
+        assert_eq!(
+            gradient_image(3, 2),
+            [[0.0; 4], [127.5; 4], [255.0; 4], [0.0; 4], [127.5; 4], [255.0; 4]]
+        );
+        #[rustfmt::skip]
+        assert_eq!(
+            checkerboard(4, 2, 2),
+            [
+                [255.0; 4], [255.0; 4], [0.0; 4], [0.0; 4],
+                [255.0; 4], [255.0; 4], [0.0; 4], [0.0; 4],
+            ]
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Blurring large images a tile or a band of rows at a time, bounding the
+//! memory used.
+use crate::complex::{blur_region_with_kernels, is_identity, recommended_kernel_radius};
+use crate::mask::Rect;
+use crate::params::KernelParamSet;
+use crate::plan::KernelPlan;
+use alloc::vec::Vec;
+
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use num::Float;
+
+/// Blurs an image using an approximation of a disc-shaped kernel, convolving
+/// it one `tile_size` by `tile_size` tile at a time.
+///
+/// [`crate::bokeh_blur`] holds several copies of the whole image as complex
+/// numbers while convolving, which for very large images can be more memory
+/// than is available. Here each tile is convolved along with the pixels within
+/// the kernel radius around it, so that the tile has the full support of its
+/// neighbours and the seams between tiles are invisible, and only the tile
+/// itself is kept. The memory used is then bounded by the tile size, plus a
+/// strip of the original image as wide as the image and as tall as a tile and
+/// its overlap, which is kept as the rows above are overwritten. Smaller tiles
+/// use less memory but convolve more overlap. A `tile_size` of `0` is treated
+/// as `1`. The result is the same as [`crate::bokeh_blur`].
+///
+/// Takes an exclusive reference to a slice of size 4 arrays, where each array
+/// element corresponds to a pixel. Also requires the `width` and `height` of
+/// the image. The image is blurred by a disc-shaped kernel with radius
+/// `radius`, built from components corresponding to `param_set`. The exposure
+/// can be modified using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_tiled(
+    img: &mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    tile_size: usize,
+) {
+    debug_assert_eq!(img.len(), width * height);
+    if is_identity(radius) {
+        return;
+    }
+
+    let plan = KernelPlan::for_radius(param_set, radius);
+    let overlap = recommended_kernel_radius(radius);
+    let tile_size = tile_size.max(1);
+
+    // The original rows from `top` down, copied before the tiles covering them
+    // are written
+    let mut source = Vec::new();
+    let mut top = 0;
+
+    for y in (0..height).step_by(tile_size) {
+        let band = Rect::new(0, y, width, tile_size.min(height - y)).expand(overlap, width, height);
+
+        // Drop the rows above the band, then copy the rows below those already
+        // held, none of which have been written yet
+        let stale = (band.y - top).min(source.len() / width.max(1));
+        source.drain(..stale * width);
+        top = band.y;
+        let read = top + source.len() / width.max(1);
+        source.extend_from_slice(&img[read * width..(band.y + band.height) * width]);
+
+        for x in (0..width).step_by(tile_size) {
+            let tile = Rect::new(
+                x,
+                y - band.y,
+                tile_size.min(width - x),
+                tile_size.min(height - y),
+            );
+            for (n, rgba) in
+                blur_region_with_kernels(&source, width, band.height, tile, plan.kernels(), gamma)
+                    .into_iter()
+                    .enumerate()
+            {
+                // Clamp any values from floating point ops
+                img[tile.pixel_index(n, width) + band.y * width] =
+                    rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0));
+            }
+        }
+    }
+}
+
+/// Blurs an image using an approximation of a disc-shaped kernel, passing the
+/// result to `sink` one row at a time rather than writing it back.
+///
+/// The image is convolved in bands of rows, each along with the rows within
+/// the kernel radius above and below it so the result is the same as
+/// [`crate::bokeh_blur`], and each row is passed to `sink` along with its
+/// index as soon as its band is finished, then dropped. Rows arrive in order,
+/// from the top of the image down. Only one band is held at a time, so the
+/// result can be written straight to an encoder without ever holding all of
+/// it. The bands are four times as tall as the kernel radius, and at least 16
+/// rows, trading the memory held against the extra rows convolved around each
+/// band.
+///
+/// Takes a reference to a slice of size 4 arrays, where each array element
+/// corresponds to a pixel. Also requires the `width` and `height` of the image.
+/// The image is blurred by a disc-shaped kernel with radius `radius`, built
+/// from components corresponding to `param_set`. The exposure can be modified
+/// using `gamma`, set to `1.0` for no change.
+pub fn bokeh_blur_streaming(
+    img: &[[f64; 4]],
+    width: usize,
+    height: usize,
+    radius: f64,
+    param_set: &KernelParamSet,
+    gamma: f64,
+    mut sink: impl FnMut(usize, &[[f64; 4]]),
+) {
+    debug_assert_eq!(img.len(), width * height);
+    if is_identity(radius) {
+        // Each row is passed on as it is
+        for (y, row) in img.chunks_exact(width.max(1)).take(height).enumerate() {
+            sink(y, row);
+        }
+        return;
+    }
+
+    let plan = KernelPlan::for_radius(param_set, radius);
+    let band_height = (4 * recommended_kernel_radius(radius)).max(16);
+
+    for y in (0..height).step_by(band_height) {
+        let band = Rect::new(0, y, width, band_height.min(height - y));
+        let blurred = blur_region_with_kernels(img, width, height, band, plan.kernels(), gamma);
+        for (j, row) in blurred.chunks_exact(width.max(1)).enumerate() {
+            // Clamp any values from floating point ops
+            let row = row
+                .iter()
+                .map(|rgba| rgba.map(|i| i.max(0.0).powf(1.0 / gamma).min(255.0)))
+                .collect::<Vec<_>>();
+            sink(y + j, &row);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::KERNEL9_PARAM_SET;
+    use crate::testing::gradient_image;
+
+    #[test]
+    fn tiles_match_whole_image() {
+        let (w, h) = (23, 17);
+        let original = gradient_image(w, h)
+            .into_iter()
+            .enumerate()
+            .map(|(n, p)| if n % 5 == 0 { [255.0; 4] } else { p })
+            .collect::<Vec<_>>();
+        let mut expected = original.clone();
+        crate::bokeh_blur(&mut expected, w, h, 3.0, &KERNEL9_PARAM_SET, 2.2);
+
+        for tile_size in [0, 1, 2, 5, 8, 17, 64] {
+            let mut img = original.clone();
+            bokeh_blur_tiled(&mut img, w, h, 3.0, &KERNEL9_PARAM_SET, 2.2, tile_size);
+            for (a, b) in img.iter().flatten().zip(expected.iter().flatten()) {
+                assert!((a - b).abs() < 1e-9, "tile size {tile_size}: {a} != {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn streamed_rows_match_whole_image() {
+        let (w, h) = (11, 41);
+        let original = gradient_image(w, h);
+        let mut expected = original.clone();
+        crate::bokeh_blur(&mut expected, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+
+        let mut next_row = 0;
+        bokeh_blur_streaming(&original, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2, |y, row| {
+            assert_eq!(y, next_row);
+            next_row += 1;
+            for (a, b) in row
+                .iter()
+                .flatten()
+                .zip(expected[y * w..(y + 1) * w].iter().flatten())
+            {
+                assert!((a - b).abs() < 1e-9);
+            }
+        });
+        assert_eq!(next_row, h);
+    }
+}
@@ -0,0 +1,49 @@
+//! Tone curves applied when writing back a blurred image.
+
+/// Curve mapping the blurred, linear values of the colour channels back into
+/// range before the gamma is inverted.
+///
+/// Values are normalised so that `1.0` is the brightest value of the input
+/// range before the curve is applied. The alpha channel is always clamped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ToneMap {
+    /// Clamps values into range, so anything brighter than the input range is
+    /// clipped
+    #[default]
+    Clamp,
+    /// Krzysztof Narkowicz's approximation of the ACES filmic curve,
+    /// `x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14)`, which rolls off
+    /// highlights smoothly rather than clipping them
+    Filmic,
+}
+
+impl ToneMap {
+    /// Applies the curve to a normalised linear value, returning a value in
+    /// `0.0..=1.0`
+    pub fn apply(self, x: f64) -> f64 {
+        match self {
+            Self::Clamp => x.clamp(0.0, 1.0),
+            Self::Filmic => {
+                let x = x.max(0.0);
+                (x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filmic_is_monotonic_from_zero() {
+        assert_eq!(ToneMap::Filmic.apply(0.0), 0.0);
+
+        let mut previous = 0.0;
+        for i in 1..=1000 {
+            let y = ToneMap::Filmic.apply(i as f64 / 100.0);
+            assert!(y > previous || y == 1.0);
+            previous = y;
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! A checked view of pixels borrowed from a caller's buffer.
+use crate::builder::BlurOptions;
+use crate::error::BlurError;
+use crate::params::KernelParamSet;
+use crate::Blur;
+use alloc::vec::Vec;
+
+/// An exclusive view of an image's pixels, checked to hold every pixel of its
+/// `width` by `height` rows when it's made.
+///
+/// The borrowed counterpart to [`crate::Image`], bundling the pixels with their
+/// dimensions so they can't get out of step, and blurred through [`Blur`].
+/// Row `j` starts at pixel `j * stride`, where the stride is the width unless
+/// made with [`PixelsMut::with_stride`], and only the first `width` pixels of
+/// each row are part of the image.
+/// ```
+/// use bokeh::{params::KERNEL9_PARAM_SET, Blur, PixelsMut};
+///
+/// let mut pixels = vec![[255.0; 4]; 16];
+/// let mut view = PixelsMut::new(&mut pixels, 4, 4).unwrap();
+/// view.bokeh_blur(1.0, &KERNEL9_PARAM_SET, 3.0);
+///
+/// assert!(PixelsMut::new(&mut pixels, 5, 4).is_err());
+/// ```
+#[derive(Debug, PartialEq)]
+pub struct PixelsMut<'a> {
+    pixels: &'a mut [[f64; 4]],
+    width: usize,
+    height: usize,
+    stride: usize,
+}
+
+impl<'a> PixelsMut<'a> {
+    /// Views `pixels` as an image of `width` by `height` pixels, stored row by
+    /// row.
+    ///
+    /// Returns [`BlurError::DimensionMismatch`] if there aren't exactly
+    /// `width * height` pixels.
+    pub fn new(pixels: &'a mut [[f64; 4]], width: usize, height: usize) -> Result<Self, BlurError> {
+        if pixels.len() != width * height {
+            return Err(BlurError::DimensionMismatch {
+                expected: width * height,
+                got: pixels.len(),
+            });
+        }
+
+        Ok(Self {
+            pixels,
+            width,
+            height,
+            stride: width,
+        })
+    }
+
+    /// Views `pixels` as an image of `width` by `height` pixels whose rows are
+    /// `stride` pixels apart, e.g. a view into a larger frame. The pixels
+    /// between the rows are never read or written.
+    ///
+    /// Returns [`BlurError::InvalidStride`] if `stride` is less than `width`,
+    /// and [`BlurError::DimensionMismatch`] if `pixels` is too short to hold
+    /// `height` rows.
+    pub fn with_stride(
+        pixels: &'a mut [[f64; 4]],
+        width: usize,
+        height: usize,
+        stride: usize,
+    ) -> Result<Self, BlurError> {
+        if stride < width {
+            return Err(BlurError::InvalidStride { stride, width });
+        }
+        let len = match height {
+            0 => 0,
+            _ => (height - 1) * stride + width,
+        };
+        if pixels.len() < len {
+            return Err(BlurError::DimensionMismatch {
+                expected: len,
+                got: pixels.len(),
+            });
+        }
+
+        Ok(Self {
+            pixels,
+            width,
+            height,
+            stride,
+        })
+    }
+
+    /// Width of the image in pixels
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height of the image in pixels
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Width and height of the image in pixels
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Number of pixels from the start of one row to the start of the next
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Row `y` of the image, or `None` if it's beyond the bottom
+    pub fn row(&self, y: usize) -> Option<&[[f64; 4]]> {
+        (y < self.height).then(|| &self.pixels[y * self.stride..y * self.stride + self.width])
+    }
+
+    /// Passes the pixels to `blur` as a single slice of `width * height`
+    /// pixels, copying them out of and back into their rows if they're apart
+    fn blur(&mut self, blur: impl FnOnce(&mut [[f64; 4]], usize, usize)) {
+        let (width, height, stride) = (self.width, self.height, self.stride);
+        if stride == width {
+            return blur(&mut self.pixels[..width * height], width, height);
+        }
+
+        let mut pixels = (0..height)
+            .flat_map(|y| self.pixels[y * stride..y * stride + width].iter().copied())
+            .collect::<Vec<_>>();
+        blur(&mut pixels, width, height);
+        for (y, row) in pixels.chunks_exact(width.max(1)).enumerate() {
+            self.pixels[y * stride..y * stride + width].copy_from_slice(row);
+        }
+    }
+}
+
+impl Blur for PixelsMut<'_> {
+    fn bokeh_blur(&mut self, radius: f64, param_set: &KernelParamSet, gamma: f64) {
+        self.blur(|pixels, w, h| crate::bokeh_blur(pixels, w, h, radius, param_set, gamma))
+    }
+
+    fn bokeh_blur_with_mask<'a>(
+        &mut self,
+        mask: impl IntoIterator<Item = &'a bool>,
+        radius: f64,
+        param_set: &KernelParamSet,
+        gamma: f64,
+    ) {
+        self.blur(|pixels, w, h| {
+            crate::bokeh_blur_with_mask(pixels, mask, w, h, radius, param_set, gamma)
+        })
+    }
+
+    fn gaussian_blur(&mut self, r: f64, kernel_radius: usize) {
+        self.blur(|pixels, w, h| crate::gaussian_blur(pixels, w, h, r, kernel_radius))
+    }
+
+    fn bokeh_blur_with(&mut self, options: &BlurOptions) {
+        self.blur(|pixels, w, h| options.apply_slice(pixels, w, h))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::KERNEL9_PARAM_SET;
+    use crate::testing::gradient_image;
+    use alloc::vec;
+
+    #[test]
+    fn checks_the_dimensions() {
+        let mut pixels = vec![[0.0; 4]; 12];
+        assert_eq!(
+            PixelsMut::new(&mut pixels, 4, 4),
+            Err(BlurError::DimensionMismatch {
+                expected: 16,
+                got: 12
+            })
+        );
+        assert_eq!(
+            PixelsMut::with_stride(&mut pixels, 4, 3, 3),
+            Err(BlurError::InvalidStride {
+                stride: 3,
+                width: 4
+            })
+        );
+        assert_eq!(
+            PixelsMut::with_stride(&mut pixels, 3, 3, 5),
+            Err(BlurError::DimensionMismatch {
+                expected: 13,
+                got: 12
+            })
+        );
+
+        let view = PixelsMut::with_stride(&mut pixels, 2, 3, 5).unwrap();
+        assert_eq!((view.dimensions(), view.stride()), ((2, 3), 5));
+        assert_eq!(view.row(2).map(<[_]>::len), Some(2));
+        assert_eq!(view.row(3), None);
+    }
+
+    #[test]
+    fn blurs_only_the_image_between_the_padding() {
+        let (w, h, stride) = (9, 7, 12);
+        let original = gradient_image(stride, h);
+
+        let mut padded = original.clone();
+        let mut view = PixelsMut::with_stride(&mut padded, w, h, stride).unwrap();
+        view.bokeh_blur(2.0, &KERNEL9_PARAM_SET, 2.2);
+        let mut expected = original.clone();
+        crate::bokeh_blur_with_stride(&mut expected, w, h, stride, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (a, b) in padded.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+
+        let mask = (0..w * h).map(|i| i % 3 == 0).collect::<Vec<_>>();
+        let mut padded = original.clone();
+        let mut view = PixelsMut::with_stride(&mut padded, w, h, stride).unwrap();
+        view.bokeh_blur_with_mask(&mask, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        let mut expected = (0..h)
+            .flat_map(|y| original[y * stride..y * stride + w].iter().copied())
+            .collect::<Vec<_>>();
+        crate::bokeh_blur_with_mask(&mut expected, &mask, w, h, 2.0, &KERNEL9_PARAM_SET, 2.2);
+        for (n, pixel) in padded.iter().enumerate() {
+            let (x, y) = (n % stride, n / stride);
+            let expected = if x < w {
+                expected[y * w + x]
+            } else {
+                original[n]
+            };
+            assert_eq!(*pixel, expected);
+        }
+    }
+}